@@ -8,7 +8,7 @@ use hopper::channel_with_explicit_capacity;
 use std::sync::mpsc::channel;
 use std::{mem, thread};
 
-fn mpsc_tst(input: MpscInput) -> () {
+fn mpsc_tst(input: MpscInput) {
     let (tx, rx) = channel();
 
     let chunk_size = input.total_elems / input.total_senders;
@@ -28,26 +28,21 @@ fn mpsc_tst(input: MpscInput) -> () {
 
     let total_senders = snd_jh.len();
     let builder = thread::Builder::new();
-    match builder.spawn(move || {
+    if let Ok(rcv_jh) = builder.spawn(move || {
         let mut collected = 0;
         while collected < (chunk_size * total_senders) {
             let _ = rx.recv().unwrap();
             collected += 1;
         }
     }) {
-        Ok(rcv_jh) => {
-            for jh in snd_jh {
-                jh.join().expect("snd join failed");
-            }
-            rcv_jh.join().expect("rcv join failed");
-        }
-        _ => {
-            return;
+        for jh in snd_jh {
+            jh.join().expect("snd join failed");
         }
+        rcv_jh.join().expect("rcv join failed");
     }
 }
 
-fn hopper_tst(input: HopperInput) -> () {
+fn hopper_tst(input: HopperInput) {
     let sz = mem::size_of::<u64>();
     let in_memory_bytes = sz * input.in_memory_max;
     let max_disk_bytes = sz * input.on_disk_max;
@@ -57,7 +52,7 @@ fn hopper_tst(input: HopperInput) -> () {
             dir.path(),
             in_memory_bytes,
             max_disk_bytes,
-            usize::max_value(),
+            usize::MAX,
         ) {
             let chunk_size = input.total_elems / input.total_senders;
 
@@ -76,7 +71,7 @@ fn hopper_tst(input: HopperInput) -> () {
 
             let total_senders = snd_jh.len();
             let builder = thread::Builder::new();
-            match builder.spawn(move || {
+            if let Ok(rcv_jh) = builder.spawn(move || {
                 let mut collected = 0;
                 let mut rcv_iter = rcv.iter();
                 while collected < (chunk_size * total_senders) {
@@ -85,15 +80,10 @@ fn hopper_tst(input: HopperInput) -> () {
                     }
                 }
             }) {
-                Ok(rcv_jh) => {
-                    for jh in snd_jh {
-                        jh.join().expect("snd join failed");
-                    }
-                    rcv_jh.join().expect("rcv join failed");
-                }
-                _ => {
-                    return;
+                for jh in snd_jh {
+                    jh.join().expect("snd join failed");
                 }
+                rcv_jh.join().expect("rcv join failed");
             }
         }
     }
@@ -155,7 +145,7 @@ fn mpsc_benchmark(c: &mut Criterion) {
     );
 }
 
-criterion_group!{
+criterion_group! {
     name = benches;
     config = Criterion::default().without_plots();
     targets = hopper_benchmark, mpsc_benchmark
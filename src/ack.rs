@@ -0,0 +1,357 @@
+//! Acknowledged delivery
+//!
+//! A Receiver is done with an item the moment it hands it out. An
+//! `AckReceiver` hands out each item as a `Delivery` instead, to be acked once
+//! the item has been dealt with. An item that is nacked, or is not acked
+//! within the ack timeout, is delivered again.
+//!
+//! Items paged to disk are held on to until acked. The queue file they were
+//! read from is only removed -- and `max_disk_files` replenished -- once every
+//! item read from it and the files before it has been acked. A durable
+//! channel's cursor points no further along than the oldest item not yet
+//! acked, so that a Receiver recovered after a restart delivers it again.
+//! Items that never left memory are, as ever, gone with the process.
+//!
+//! Delivery is at-least-once: an item may be delivered more than once, say
+//! should it be acked after it timed out, or be read again after a restart
+//! alongside an older item that was not acked.
+use codec::{Bincode, Codec};
+use parking_lot::Mutex;
+use private;
+use receiver::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+use stats::Counters;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A position in the queue files: a sequence number, an offset into that file
+// and the items of the block there to pass over.
+pub type Position = (usize, u64, usize);
+
+#[derive(Debug)]
+struct Outstanding<T> {
+    value: T,
+    deadline: Instant,
+}
+
+// What an AckReceiver and its Deliveries share: the items handed out and not
+// yet acked, and the queue files that can't be removed until they are.
+#[derive(Debug)]
+pub struct Ledger<T> {
+    root: PathBuf,
+    mem_buffer: private::Queue<T>,
+    max_disk_files: Arc<AtomicUsize>,
+    counters: Arc<Counters>,
+    durable: bool,
+    position: Position, // the Receiver's
+    // Where each item read from disk and not yet acked was read from
+    on_disk: BTreeMap<u64, Position>,
+    retired: VecDeque<(usize, usize)>, // queue files read through, and their lengths
+    outstanding: HashMap<u64, Outstanding<T>>,
+    timers: VecDeque<(Instant, u64)>, // deadlines, soonest first
+    nacked: VecDeque<u64>,
+    next_id: u64,
+}
+
+impl<T> Ledger<T> {
+    pub fn new(
+        root: PathBuf,
+        mem_buffer: private::Queue<T>,
+        max_disk_files: Arc<AtomicUsize>,
+        counters: Arc<Counters>,
+        durable: bool,
+        position: Position,
+    ) -> Ledger<T> {
+        Ledger {
+            root,
+            mem_buffer,
+            max_disk_files,
+            counters,
+            durable,
+            position,
+            on_disk: BTreeMap::new(),
+            retired: VecDeque::new(),
+            outstanding: HashMap::new(),
+            timers: VecDeque::new(),
+            nacked: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    // The Receiver has moved on to `position`, which is to be recorded
+    // should `record` be set.
+    pub fn advance(&mut self, position: Position, record: bool) -> io::Result<()> {
+        self.position = position;
+        self.settle(record)
+    }
+
+    // The Receiver is done reading a queue file of `len` bytes, which is to
+    // be removed once every item read from it has been acked.
+    pub fn retire(&mut self, seq_num: usize, len: usize) {
+        self.retired.push_back((seq_num, len));
+    }
+
+    // The oldest position a restarted Receiver must read from
+    fn floor(&self) -> Position {
+        match self.on_disk.values().next() {
+            Some(position) => *position,
+            None => self.position,
+        }
+    }
+
+    // Remove the retired queue files before the floor and, should `record`
+    // be set and this be a durable channel, record the floor in the cursor.
+    fn settle(&mut self, record: bool) -> io::Result<()> {
+        let (floor_seq_num, offset, skip) = self.floor();
+        while let Some(&(seq_num, len)) = self.retired.front() {
+            if seq_num >= floor_seq_num {
+                break;
+            }
+            match fs::remove_file(self.root.join(format!("{}", seq_num))) {
+                Ok(()) => self.counters.remove_disk_file(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            self.retired.pop_front();
+            self.counters.remove_disk_bytes(len);
+            self.max_disk_files.fetch_add(1, Ordering::Relaxed);
+            self.mem_buffer.notify_not_full();
+        }
+        if record && self.durable {
+            private::write_cursor(&self.root, floor_seq_num, offset, skip)?;
+        }
+        Ok(())
+    }
+
+    fn deliver(&mut self, value: T, position: Option<Position>, deadline: Instant) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(position) = position {
+            self.on_disk.insert(id, position);
+        }
+        self.outstanding.insert(id, Outstanding { value, deadline });
+        self.timers.push_back((deadline, id));
+        id
+    }
+
+    fn ack(&mut self, id: u64) -> io::Result<()> {
+        self.outstanding.remove(&id);
+        if self.on_disk.remove(&id).is_some() {
+            self.settle(true)?;
+        }
+        Ok(())
+    }
+
+    fn nack(&mut self, id: u64) {
+        if self.outstanding.contains_key(&id) {
+            self.nacked.push_back(id);
+        }
+    }
+
+    // The soonest a timed out item might need delivering again
+    fn next_deadline(&self) -> Option<Instant> {
+        if self.outstanding.is_empty() {
+            return None;
+        }
+        self.timers.front().map(|&(deadline, _)| deadline)
+    }
+
+    // Take an item to deliver again, nacked items first, should there be one.
+    // A timer whose item has since been acked or delivered again is stale
+    // and passed over.
+    fn redeliver(&mut self, now: Instant, ack_timeout: Duration) -> Option<(u64, T)>
+    where
+        T: Clone,
+    {
+        let id = loop {
+            if let Some(id) = self.nacked.pop_front() {
+                if self.outstanding.contains_key(&id) {
+                    break id;
+                }
+                continue;
+            }
+            match self.timers.front() {
+                Some(&(deadline, id)) if deadline <= now => {
+                    self.timers.pop_front();
+                    match self.outstanding.get(&id) {
+                        Some(outstanding) if outstanding.deadline == deadline => break id,
+                        _ => continue,
+                    }
+                }
+                _ => return None,
+            }
+        };
+        let deadline = now + ack_timeout;
+        let outstanding = self.outstanding.get_mut(&id)?;
+        outstanding.deadline = deadline;
+        self.timers.push_back((deadline, id));
+        Some((id, outstanding.value.clone()))
+    }
+}
+
+/// A received item, to be acked once dealt with
+///
+/// The item is had through `Deref`. A Delivery dropped without being acked
+/// or nacked is delivered again once the ack timeout passes.
+#[derive(Debug)]
+pub struct Delivery<T> {
+    id: u64,
+    value: T,
+    redelivered: bool,
+    ledger: Arc<Mutex<Ledger<T>>>,
+}
+
+impl<T> Deref for Delivery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> Delivery<T> {
+    /// Acknowledge the item, which won't be delivered again
+    ///
+    /// Should the item have been read from disk its queue file may be
+    /// removed, and a durable channel's cursor moved past it. Failing to do
+    /// so is reported as `Error::IoError`, and is tried again on the next
+    /// ack.
+    pub fn ack(self) -> Result<(), super::Error> {
+        self.ledger
+            .lock()
+            .ack(self.id)
+            .map_err(super::Error::IoError)
+    }
+
+    /// Refuse the item, which is delivered again ahead of anything new
+    pub fn nack(self) {
+        self.ledger.lock().nack(self.id)
+    }
+
+    /// Whether the item has been delivered before
+    pub fn redelivered(&self) -> bool {
+        self.redelivered
+    }
+}
+
+/// The 'receive' side of hopper, handing out items to be acknowledged
+///
+/// Made by `Receiver::into_acked`.
+#[derive(Debug)]
+pub struct AckReceiver<T, C = Bincode> {
+    inner: Receiver<T, C>,
+    ledger: Arc<Mutex<Ledger<T>>>,
+    ack_timeout: Duration,
+}
+
+impl<T, C> AckReceiver<T, C>
+where
+    T: Clone,
+    C: Codec<T>,
+{
+    #[doc(hidden)]
+    pub fn new(
+        inner: Receiver<T, C>,
+        ledger: Arc<Mutex<Ledger<T>>>,
+        ack_timeout: Duration,
+    ) -> AckReceiver<T, C> {
+        AckReceiver {
+            inner,
+            ledger,
+            ack_timeout,
+        }
+    }
+
+    fn delivery(&self, id: u64, value: T, redelivered: bool) -> Delivery<T> {
+        Delivery {
+            id,
+            value,
+            redelivered,
+            ledger: Arc::clone(&self.ledger),
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<Delivery<T>, RecvTimeoutError> {
+        // Items to deliver again come ahead of new ones. Waiting for a new
+        // item is cut short when an outstanding one times out. The channel
+        // is only done with once every item has been acked.
+        loop {
+            let now = Instant::now();
+            let mut ledger = self.ledger.lock();
+            if let Some((id, value)) = ledger.redeliver(now, self.ack_timeout) {
+                drop(ledger);
+                return Ok(self.delivery(id, value, true));
+            }
+            let next_deadline = ledger.next_deadline();
+            drop(ledger);
+            let wait_until = match (deadline, next_deadline) {
+                (Some(deadline), Some(next)) => Some(::std::cmp::min(deadline, next)),
+                (deadline, None) => deadline,
+                (None, next) => next,
+            };
+            match self.inner.recv_from(wait_until) {
+                Ok((value, position)) => {
+                    let id = self.ledger.lock().deliver(
+                        value.clone(),
+                        position,
+                        Instant::now() + self.ack_timeout,
+                    );
+                    return Ok(self.delivery(id, value, false));
+                }
+                Err(RecvTimeoutError::Disconnected) if next_deadline.is_some() => {
+                    if let Some(wait_until) = wait_until {
+                        thread::sleep(wait_until.saturating_duration_since(Instant::now()));
+                    }
+                    if deadline.is_some_and(|d| Instant::now() >= d) {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if deadline.is_some_and(|d| Instant::now() >= d) {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Receive an item, blocking until one is available
+    ///
+    /// Errors are as for `Receiver::recv`, except that the channel is only
+    /// disconnected once every item handed out has been acked.
+    pub fn recv(&mut self) -> Result<Delivery<T>, RecvError> {
+        match self.recv_until(None) {
+            Ok(delivery) => Ok(delivery),
+            Err(RecvTimeoutError::Disk(e)) => Err(RecvError::Disk(e)),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                Err(RecvError::Disconnected)
+            }
+        }
+    }
+
+    /// Attempt to receive an item without blocking
+    ///
+    /// Errors are as for `Receiver::try_recv`.
+    pub fn try_recv(&mut self) -> Result<Delivery<T>, TryRecvError> {
+        match self.recv_until(Some(Instant::now())) {
+            Ok(delivery) => Ok(delivery),
+            Err(RecvTimeoutError::Timeout) => Err(TryRecvError::Empty),
+            Err(RecvTimeoutError::Disconnected) => Err(TryRecvError::Disconnected),
+            Err(RecvTimeoutError::Disk(e)) => Err(TryRecvError::Disk(e)),
+        }
+    }
+
+    /// Receive an item, blocking for no longer than `timeout`
+    ///
+    /// Errors are as for `Receiver::recv_timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Delivery<T>, RecvTimeoutError> {
+        self.recv_until(private::deadline(timeout))
+    }
+}
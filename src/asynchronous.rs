@@ -0,0 +1,557 @@
+//! Futures-aware halves of a hopper channel
+//!
+//! Blocking on `Receiver::iter` from inside an executor stalls one of its
+//! worker threads. The types in this module -- enabled by the `futures` cargo
+//! feature -- wrap the blocking Sender and Receiver so that they can be used
+//! as a `futures::Sink` and `futures::Stream` respectively.
+//!
+//! Neither half does disk IO on the executor. A Sender and its clones hand
+//! their items, one at a time, to a writer thread they share which places them
+//! -- waiting for room should there be none -- and wakes the sending task once
+//! it's done.
+//! Each Receiver parks by registering its task's Waker with the in-memory
+//! deque rather than by waiting on the deque's condvar, and reads from the
+//! queue files in batches on a reader thread of its own. The memory-then-disk
+//! ordering of the blocking Receiver is preserved: while items wait on disk
+//! the in-memory deque is not consulted.
+//!
+//! # Example
+//! ```
+//! extern crate futures;
+//! extern crate hopper;
+//! extern crate tempdir;
+//!
+//! use futures::executor::block_on;
+//! use futures::{SinkExt, StreamExt};
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let (mut snd, mut rcv) =
+//!     hopper::asynchronous::channel::<u64>("example", dir.path()).unwrap();
+//!
+//! block_on(snd.send(9)).unwrap();
+//! assert_eq!(9, block_on(rcv.next()).unwrap().unwrap());
+//! ```
+use codec::{Bincode, Codec};
+use futures::channel::oneshot;
+use futures::{Future, Sink, Stream};
+use parking_lot::{Condvar, Mutex};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::panic;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Instant;
+
+/// The most items a Receiver will read from disk in one go.
+const MAX_DISK_BATCH: usize = 1024;
+
+type DiskBatch<T, C> = (super::Receiver<T, C>, Vec<T>, Option<super::Error>);
+type DiskRead<T, C> = (super::Receiver<T, C>, oneshot::Sender<DiskBatch<T, C>>);
+
+/// Create an asynchronous (Sender, Receiver) pair
+///
+/// This function is the asynchronous counterpart to `hopper::channel`.
+pub fn channel<T>(name: &str, data_dir: &Path) -> Result<(Sender<T>, Receiver<T>), super::Error>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    channel_with_config(name, data_dir, super::Config::default())
+}
+
+/// Create an asynchronous (Sender, Receiver) pair from a `Config`
+///
+/// This function is the asynchronous counterpart to
+/// `hopper::channel_with_config`.
+pub fn channel_with_config<T>(
+    name: &str,
+    data_dir: &Path,
+    config: super::Config,
+) -> Result<(Sender<T>, Receiver<T>), super::Error>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    let (snd, rcv) = super::channel_with_config(name, data_dir, config)?;
+    Ok((Sender::from(snd), Receiver::from(rcv)))
+}
+
+/// Create an asynchronous (Sender, Receiver) pair from a `Config`,
+/// serializing items paged to disk with `codec`
+///
+/// This function is the asynchronous counterpart to
+/// `hopper::channel_with_codec`.
+#[allow(clippy::type_complexity)]
+pub fn channel_with_codec<T, C>(
+    name: &str,
+    data_dir: &Path,
+    config: super::Config,
+    codec: C,
+) -> Result<(Sender<T, C>, Receiver<T, C>), super::Error>
+where
+    T: Send + 'static,
+    C: Codec<T> + Send + 'static,
+{
+    let (snd, rcv) = super::channel_with_codec(name, data_dir, config, codec)?;
+    Ok((Sender::from(snd), Receiver::from(rcv)))
+}
+
+/// The 'send' side of an asynchronous hopper channel
+///
+/// An item handed to `start_send` is placed by the writer thread, so the
+/// Sender is not ready for another until that's done. Should there be no room
+/// for the item the writer thread holds on to it until the Receiver makes
+/// some. Flushing likewise waits for the writer thread to announce the disk
+/// writes made so far. A failure to place or flush is reported by the next
+/// call to `poll_ready` or `poll_flush`.
+///
+/// A Sender and its clones share one writer thread, which places the items
+/// handed to it by each in turn. The thread goes once they're all dropped.
+///
+/// Closing a Sender flushes it and hangs it up, as dropping it would. Items
+/// sent after are refused with `Error::Closed`.
+#[derive(Debug)]
+pub struct Sender<T, C = Bincode> {
+    id: usize,
+    writer: Arc<Writer<T, C>>,
+    closed: bool,
+}
+
+// Sender is never pinned structurally.
+impl<T, C> Unpin for Sender<T, C> {}
+
+#[derive(Debug)]
+struct Writer<T, C> {
+    state: Mutex<WriterState<T, C>>,
+    wake_writer: Condvar,
+    placed: Condvar, // the writer thread is done with a slot's handoff
+}
+
+#[derive(Debug)]
+struct WriterState<T, C> {
+    slots: BTreeMap<usize, Slot<T, C>>, // one for each open Sender, by id
+    next_id: usize,
+    template: super::Sender<T, C>, // detached, for cloning
+    running: bool,                 // the writer thread has not gone
+}
+
+impl<T, C> WriterState<T, C> {
+    fn open(&mut self, inner: super::Sender<T, C>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots.insert(
+            id,
+            Slot {
+                inner: Some(inner),
+                item: None,
+                placing: false,
+                flush: Flush::Idle,
+                error: None,
+                waker: None,
+                closed: false,
+            },
+        );
+        id
+    }
+
+    fn slot(&mut self, id: usize) -> &mut Slot<T, C> {
+        self.slots.get_mut(&id).expect("async Sender without a slot")
+    }
+}
+
+#[derive(Debug)]
+struct Slot<T, C> {
+    inner: Option<super::Sender<T, C>>, // with the writer thread while placing
+    item: Option<T>, // handed off, not yet taken up by the writer thread
+    placing: bool,   // the writer thread is placing an item, or flushing
+    flush: Flush,
+    error: Option<super::Error>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flush {
+    Idle,
+    Asked,
+    Done,
+}
+
+impl<T, C> Slot<T, C> {
+    fn park(&mut self, waker: &Waker) {
+        match self.waker {
+            Some(ref registered) if registered.will_wake(waker) => {}
+            _ => self.waker = Some(waker.clone()),
+        }
+    }
+
+    // Whether the writer thread has something to do for the slot
+    fn due(&self) -> bool {
+        !self.placing && (self.item.is_some() || self.flush == Flush::Asked || self.closed)
+    }
+
+    // Whether the writer thread is done with what the slot handed off
+    fn settled(&self) -> bool {
+        !self.placing && self.item.is_none() && self.flush != Flush::Asked
+    }
+}
+
+impl<T, C> Clone for Sender<T, C>
+where
+    T: Send + 'static,
+    C: Codec<T> + Send + 'static,
+{
+    fn clone(&self) -> Sender<T, C> {
+        let mut state = self.writer.state.lock();
+        let inner = state.template.clone();
+        let id = state.open(inner);
+        // A closed Sender may have outlived the writer thread.
+        if !state.running {
+            state.running = true;
+            let thread_writer = Arc::clone(&self.writer);
+            thread::spawn(move || write(&thread_writer));
+        }
+        Sender {
+            id,
+            writer: Arc::clone(&self.writer),
+            closed: false,
+        }
+    }
+}
+
+impl<T, C> From<super::Sender<T, C>> for Sender<T, C>
+where
+    T: Send + 'static,
+    C: Codec<T> + Send + 'static,
+{
+    fn from(inner: super::Sender<T, C>) -> Sender<T, C> {
+        let mut state = WriterState {
+            slots: BTreeMap::new(),
+            next_id: 0,
+            template: inner.detached(),
+            running: true,
+        };
+        let id = state.open(inner);
+        let writer = Arc::new(Writer {
+            state: Mutex::new(state),
+            wake_writer: Condvar::new(),
+            placed: Condvar::new(),
+        });
+        let thread_writer = Arc::clone(&writer);
+        thread::spawn(move || write(&thread_writer));
+        Sender {
+            id,
+            writer,
+            closed: false,
+        }
+    }
+}
+
+impl<T, C> Sender<T, C> {
+    /// Return the blocking Sender underneath
+    ///
+    /// This function blocks until the writer thread has placed the item
+    /// handed to it, if any.
+    ///
+    /// # Panics
+    ///
+    /// Panics should the Sender have been closed, its Sender underneath
+    /// having been hung up.
+    pub fn into_inner(self) -> super::Sender<T, C> {
+        let mut state = self.writer.state.lock();
+        while !state.slot(self.id).settled() {
+            self.writer.placed.wait(&mut state);
+        }
+        let slot = state.slots.remove(&self.id).expect("async Sender without a slot");
+        drop(state);
+        // The writer thread may have no one left to write for.
+        self.writer.wake_writer.notify_one();
+        slot.inner.expect("async Sender settled without its inner Sender")
+    }
+}
+
+impl<T, C> Drop for Sender<T, C> {
+    fn drop(&mut self) {
+        // The writer thread places what it has been handed and then hangs up
+        // this Sender's inner Sender.
+        if let Some(slot) = self.writer.state.lock().slots.get_mut(&self.id) {
+            slot.closed = true;
+        }
+        self.writer.wake_writer.notify_one();
+    }
+}
+
+// The writer thread: place each item handed off, flush when asked and wake
+// the sending task once done, taking each Sender's slot in turn. A closed
+// Sender's inner Sender is dropped, and the thread goes once they all are.
+fn write<T, C>(writer: &Writer<T, C>)
+where
+    C: Codec<T>,
+{
+    let mut last = 0;
+    loop {
+        let mut state = writer.state.lock();
+        let id = loop {
+            if state.slots.is_empty() {
+                state.running = false;
+                return;
+            }
+            let due = state
+                .slots
+                .range(last + 1..)
+                .chain(state.slots.range(..=last))
+                .find(|(_, slot)| slot.due())
+                .map(|(id, _)| *id);
+            match due {
+                Some(id) => break id,
+                None => writer.wake_writer.wait(&mut state),
+            }
+        };
+        last = id;
+        let slot = state.slot(id);
+        let item = slot.item.take();
+        let flush = slot.flush == Flush::Asked;
+        if item.is_none() && !flush {
+            // Closed, with nothing left to place. The inner Sender may flush
+            // to disk as it's dropped, so the lock is let go of first.
+            let slot = state.slots.remove(&id);
+            drop(state);
+            drop(slot);
+            continue;
+        }
+        slot.placing = true;
+        let mut inner = slot.inner.take().expect("async Sender slot without its inner Sender");
+        drop(state);
+
+        let mut failed = None;
+        if let Some(item) = item {
+            if let Err((_, e)) = inner.send_blocking(item) {
+                failed = Some(e);
+            }
+        }
+        if flush && failed.is_none() {
+            failed = inner.flush_blocking().err();
+        }
+
+        let mut state = writer.state.lock();
+        let slot = state.slot(id);
+        slot.inner = Some(inner);
+        slot.placing = false;
+        if flush {
+            slot.flush = Flush::Done;
+        }
+        if failed.is_some() {
+            slot.error = failed;
+        }
+        let waker = slot.waker.take();
+        drop(state);
+        writer.placed.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T, C> Sink<T> for Sender<T, C> {
+    type Error = super::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), super::Error>> {
+        if self.closed {
+            return Poll::Ready(Err(super::Error::Closed));
+        }
+        let mut state = self.writer.state.lock();
+        let slot = state.slot(self.id);
+        if let Some(e) = slot.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        if slot.item.is_none() && !slot.placing {
+            return Poll::Ready(Ok(()));
+        }
+        slot.park(cx.waker());
+        Poll::Pending
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), super::Error> {
+        if self.closed {
+            return Err(super::Error::Closed);
+        }
+        let mut state = self.writer.state.lock();
+        let slot = state.slot(self.id);
+        debug_assert!(slot.item.is_none(), "start_send without poll_ready");
+        slot.item = Some(item);
+        // A flush finished before this item was handed off does not cover it.
+        if slot.flush == Flush::Done {
+            slot.flush = Flush::Idle;
+        }
+        self.writer.wake_writer.notify_one();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), super::Error>> {
+        // Everything was flushed on closing.
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        let mut state = self.writer.state.lock();
+        let slot = state.slot(self.id);
+        if let Some(e) = slot.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        match slot.flush {
+            Flush::Done => {
+                slot.flush = Flush::Idle;
+                return Poll::Ready(Ok(()));
+            }
+            Flush::Idle => {
+                slot.flush = Flush::Asked;
+                self.writer.wake_writer.notify_one();
+            }
+            Flush::Asked => {}
+        }
+        slot.park(cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), super::Error>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {}
+            pending_or_failed => return pending_or_failed,
+        }
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        // Flushed, the writer thread is done with our slot. We hang up the
+        // inner Sender here and now, rather than leave it to the writer
+        // thread, so that the channel knows of it once we're closed.
+        let slot = self.writer.state.lock().slots.remove(&self.id);
+        self.closed = true;
+        // The writer thread may have no one left to write for.
+        self.writer.wake_writer.notify_one();
+        drop(slot);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The 'receive' side of an asynchronous hopper channel
+///
+/// The stream ends when every Sender has hung up and the channel is drained.
+/// Should a disk read fail the error is yielded as an item and the stream
+/// carries on, as `Receiver::recv` does.
+#[derive(Debug)]
+pub struct Receiver<T, C = Bincode> {
+    inner: Option<super::Receiver<T, C>>,
+    buffer: VecDeque<T>,
+    error: Option<super::Error>,
+    reader: Option<mpsc::Sender<DiskRead<T, C>>>,
+    disk_read: Option<oneshot::Receiver<DiskBatch<T, C>>>,
+}
+
+// Receiver is never pinned structurally.
+impl<T, C> Unpin for Receiver<T, C> {}
+
+impl<T, C> From<super::Receiver<T, C>> for Receiver<T, C> {
+    fn from(inner: super::Receiver<T, C>) -> Receiver<T, C> {
+        Receiver {
+            inner: Some(inner),
+            buffer: VecDeque::new(),
+            error: None,
+            reader: None,
+            disk_read: None,
+        }
+    }
+}
+
+impl<T, C> Receiver<T, C>
+where
+    T: Send + 'static,
+    C: Codec<T> + Send + 'static,
+{
+    // Hand `inner` to the reader thread, spawning it should this be the first
+    // disk read, for a batch to be read from disk.
+    fn read_disk(&mut self, inner: super::Receiver<T, C>) {
+        let reader = self.reader.get_or_insert_with(|| {
+            let (reader, reads) = mpsc::channel::<DiskRead<T, C>>();
+            thread::spawn(move || {
+                for (mut inner, done) in reads {
+                    let (batch, err) = inner.read_disk_values(MAX_DISK_BATCH);
+                    let _ = done.send((inner, batch, err));
+                }
+            });
+            reader
+        });
+        let (done, disk_read) = oneshot::channel();
+        // Should the reader thread have gone down, `inner` goes with it and
+        // the oneshot reports as much.
+        let _ = reader.send((inner, done));
+        self.disk_read = Some(disk_read);
+    }
+}
+
+impl<T, C> Stream for Receiver<T, C>
+where
+    T: Send + 'static,
+    C: Codec<T> + Send + 'static,
+{
+    type Item = Result<T, super::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ev) = this.buffer.pop_front() {
+                return Poll::Ready(Some(Ok(ev)));
+            }
+            if let Some(e) = this.error.take() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if let Some(mut disk_read) = this.disk_read.take() {
+                match Pin::new(&mut disk_read).poll(cx) {
+                    Poll::Pending => {
+                        this.disk_read = Some(disk_read);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok((inner, batch, err))) => {
+                        this.buffer.extend(batch);
+                        this.inner = Some(inner);
+                        this.error = err;
+                        continue;
+                    }
+                    // The reader thread went down without reporting back. Its
+                    // Receiver went with it.
+                    Poll::Ready(Err(oneshot::Canceled)) => return Poll::Ready(None),
+                }
+            }
+            let mut inner = match this.inner.take() {
+                Some(inner) => inner,
+                None => return Poll::Ready(None),
+            };
+            if inner.disk_writes_to_read() > 0 {
+                this.read_disk(inner);
+                continue;
+            }
+            // Register before looking so that a push landing between the look
+            // and our return is sure to wake us.
+            inner.register_waker(cx.waker());
+            match inner.pop_memory(Some(Instant::now())) {
+                Ok(Some(ev)) => {
+                    this.inner = Some(inner);
+                    return Poll::Ready(Some(Ok(ev)));
+                }
+                Ok(None) => {
+                    this.inner = Some(inner);
+                    continue;
+                }
+                Err(super::RecvTimeoutError::Timeout) => {
+                    this.inner = Some(inner);
+                    return Poll::Pending;
+                }
+                Err(super::RecvTimeoutError::Disconnected) => return Poll::Ready(None),
+                Err(super::RecvTimeoutError::Disk(e)) => {
+                    this.inner = Some(inner);
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,916 @@
+//! A channel delivering every item to each of several subscribers
+//!
+//! A broadcast channel has a `Publisher` -- clonable, as a `Sender` is -- and
+//! any number of `Subscriber`s, each of which receives every item published
+//! after it subscribed. Each Subscriber has an in-memory deque of its own,
+//! sized as a channel's would be. A Subscriber that keeps up is handed items
+//! through its deque. Once a Subscriber's deque fills up items are paged to
+//! disk for it, as they are for a channel's Receiver, and the Publisher and
+//! the other Subscribers carry on undisturbed.
+//!
+//! The Subscribers share one set of numbered queue files. An item is written
+//! to disk at most once, however many Subscribers have fallen behind, and each
+//! Subscriber reads the queue files from a file/offset cursor of its own. A
+//! queue file is removed -- and `max_disk_files` replenished -- only once the
+//! slowest Subscriber has passed it.
+//!
+//! Broadcast channels are not durable: the channel directory is cleared when
+//! the channel is created. Of the rest of a `Config` only the memory and disk
+//! limits and the compression are taken up. The others are ignored:
+//!
+//! * `block`: items are always written to disk one per record.
+//! * `fsync`: queue files are left to the OS to write through.
+//! * `overflow`: a Publisher with no room for an item refuses it with
+//!   `Error::Full`, as `OverflowPolicy::Error` has it.
+//! * `ttl`: items are delivered however long they've waited.
+//! * `corruption`: a record that can't be read is reported and passed over.
+//! * `memory_accounting`: items in memory are counted in slots.
+//!
+//! # Example
+//! ```
+//! extern crate hopper;
+//! extern crate tempdir;
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let mut publisher = hopper::broadcast::channel::<u64>("example", dir.path()).unwrap();
+//! let mut archive = publisher.subscribe();
+//! let mut alerts = publisher.subscribe();
+//!
+//! publisher.send(9).unwrap();
+//! assert_eq!(Some(9), archive.recv().ok());
+//! assert_eq!(Some(9), alerts.recv().ok());
+//! ```
+use byteorder::{BigEndian, ByteOrder};
+use codec::{Bincode, Codec};
+use compression::Compression;
+use deque::{self, BackGuardInner, PopError};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use private;
+use receiver::{RecvError, RecvTimeoutError, TryRecvError};
+use sender::PAYLOAD_LEN_BYTES;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::{BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// A Subscriber's in-memory deque. Its back lock carries the disk writes made
+// for the Subscriber that it has yet to be told of.
+type Inbox<T> = deque::Queue<Delivery<T>, Lag>;
+
+#[derive(Debug)]
+enum Delivery<T> {
+    Memory(T),
+    // `items` records, the first at `offset` into queue file `seq_num`
+    Disk {
+        seq_num: usize,
+        offset: u64,
+        items: usize,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Lag {
+    items: usize,
+    seq_num: usize,
+    offset: u64,
+}
+
+#[derive(Debug)]
+struct Subscription<T> {
+    id: usize,
+    inbox: Inbox<T>,
+    // Records written for the Subscriber that it has not yet read
+    unread: usize,
+    // The oldest queue file the Subscriber still has records to read from
+    oldest_needed: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Writer {
+    fp: Option<BufWriter<fs::File>>,
+    seq_num: usize,
+    bytes_written: usize,
+    flushed: usize, // of bytes_written, those known to be flushed to the file
+    unflushed: usize, // records written to the file since it was last flushed
+    dropped: usize,   // records given up on that the Subscribers still count
+    oldest_seq_num: usize, // the oldest queue file not yet removed
+    disk_files_capacity: usize,
+    disk_bytes: usize, // taken up by the queue files, buffered writes included
+}
+
+#[derive(Debug)]
+struct Log<T> {
+    writer: Writer,
+    subscriptions: Vec<Subscription<T>>,
+    publishers: usize,
+    next_id: usize,
+}
+
+impl<T> Log<T> {
+    // The records lost, per Subscriber, are no longer waiting to be read.
+    fn count_lost(&mut self, lost: &[usize]) {
+        for (subscription, lost) in self.subscriptions.iter_mut().zip(lost) {
+            subscription.unread -= lost;
+            if subscription.unread == 0 {
+                subscription.oldest_needed = None;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    root: PathBuf,
+    max_disk_bytes: usize,
+    max_total_disk_bytes: usize,
+    inbox_capacity: usize,
+    compression: Compression,
+    log: Mutex<Log<T>>,
+    // Signalled, with the log, when the file being written is flushed or
+    // moved on from, or the last Publisher hangs up
+    flushed: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn header(&self) -> private::Header {
+        private::Header {
+            compression: self.compression,
+            blocks: false,
+            timestamps: false,
+        }
+    }
+
+    // Remove the queue files no Subscriber has anything left to read from,
+    // making room for as many new ones. The file being written is kept.
+    fn collect_garbage(&self, log: &mut Log<T>) {
+        let oldest_needed = log
+            .subscriptions
+            .iter()
+            .filter_map(|s| s.oldest_needed)
+            .fold(log.writer.seq_num, usize::min);
+        while log.writer.oldest_seq_num < oldest_needed {
+            let path = self.root.join(format!("{}", log.writer.oldest_seq_num));
+            let len = fs::metadata(&path).map(|m| m.len() as usize).unwrap_or(0);
+            match fs::remove_file(path) {
+                Ok(()) => {
+                    log.writer.disk_files_capacity += 1;
+                    log.writer.disk_bytes = log.writer.disk_bytes.saturating_sub(len);
+                }
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                // Try again next time around.
+                Err(_) => return,
+            }
+            log.writer.oldest_seq_num += 1;
+        }
+    }
+}
+
+/// Create a Publisher for a broadcast channel with the default `Config`
+///
+/// Items paged to disk are serialized with `Bincode`.
+pub fn channel<T>(name: &str, data_dir: &Path) -> Result<Publisher<T>, super::Error>
+where
+    T: Clone + Serialize + DeserializeOwned,
+{
+    channel_with_config(name, data_dir, super::Config::default())
+}
+
+/// Create a Publisher for a broadcast channel from a `Config`
+///
+/// The memory limits of `config` apply to each Subscriber in turn. The disk
+/// limits apply to the channel as a whole. See the module documentation for
+/// the options a broadcast channel ignores.
+pub fn channel_with_config<T>(
+    name: &str,
+    data_dir: &Path,
+    config: super::Config,
+) -> Result<Publisher<T>, super::Error>
+where
+    T: Clone + Serialize + DeserializeOwned,
+{
+    channel_with_codec(name, data_dir, config, Bincode)
+}
+
+/// Create a Publisher for a broadcast channel from a `Config`, serializing
+/// items paged to disk with `codec`
+pub fn channel_with_codec<T, C>(
+    name: &str,
+    data_dir: &Path,
+    config: super::Config,
+    codec: C,
+) -> Result<Publisher<T, C>, super::Error>
+where
+    T: Clone,
+    C: Codec<T>,
+{
+    if !private::name_within(name) {
+        return Err(super::Error::Config(super::ConfigError::InvalidName));
+    }
+    config.check().map_err(super::Error::Config)?;
+    let root = data_dir.join(name);
+    fs::create_dir_all(&root).map_err(super::Error::IoError)?;
+    if private::queue_file_taken(&root).map_err(super::Error::IoError)? {
+        return Err(super::Error::Config(super::ConfigError::DirectoryInUse));
+    }
+    private::clear_directory(&root).map_err(super::Error::IoError)?;
+    let shared = Shared {
+        root,
+        max_disk_bytes: private::queue_file_limit(
+            config.max_disk_bytes,
+            config.max_total_disk_bytes,
+        ),
+        max_total_disk_bytes: config.max_total_disk_bytes,
+        inbox_capacity: ::std::cmp::max(1, config.max_memory_bytes / size_of::<T>()),
+        compression: config.compression,
+        log: Mutex::new(Log {
+            writer: Writer {
+                fp: None,
+                seq_num: 0,
+                bytes_written: 0,
+                flushed: 0,
+                unflushed: 0,
+                dropped: 0,
+                oldest_seq_num: 0,
+                disk_files_capacity: config.max_disk_files,
+                disk_bytes: 0,
+            },
+            subscriptions: Vec::new(),
+            publishers: 1,
+            next_id: 0,
+        }),
+        flushed: Condvar::new(),
+    };
+    let mut fp = fs::File::create(shared.root.join("0")).map_err(super::Error::IoError)?;
+    private::write_header(&mut fp, shared.header()).map_err(super::Error::IoError)?;
+    {
+        let mut log = shared.log.lock();
+        log.writer.fp = Some(BufWriter::new(fp));
+        log.writer.bytes_written = private::HEADER_LEN as usize;
+        log.writer.flushed = private::HEADER_LEN as usize;
+        log.writer.disk_bytes = private::HEADER_LEN as usize;
+    }
+    Ok(Publisher {
+        name: name.to_string(),
+        shared: Arc::new(shared),
+        codec,
+    })
+}
+
+/// The 'send' side of a broadcast channel
+#[derive(Debug)]
+pub struct Publisher<T, C = Bincode> {
+    name: String,
+    shared: Arc<Shared<T>>,
+    codec: C,
+}
+
+impl<T, C> Clone for Publisher<T, C>
+where
+    C: Codec<T>,
+{
+    fn clone(&self) -> Publisher<T, C> {
+        let mut log = self.shared.log.lock();
+        log.publishers += 1;
+        for subscription in &log.subscriptions {
+            subscription.inbox.register_sender();
+        }
+        Publisher {
+            name: self.name.clone(),
+            shared: Arc::clone(&self.shared),
+            codec: self.codec.clone(),
+        }
+    }
+}
+
+impl<T, C> Drop for Publisher<T, C> {
+    fn drop(&mut self) {
+        // The last Publisher out makes sure the disk writes are on disk
+        // before the Subscribers learn they've been hung up on, as they'll go
+        // looking for whatever disk writes they were never told of.
+        let mut log = self.shared.log.lock();
+        log.publishers -= 1;
+        if log.publishers == 0 {
+            if let Some(ref mut fp) = log.writer.fp {
+                let _ = fp.flush();
+            }
+            self.shared.flushed.notify_all();
+        }
+        for subscription in &log.subscriptions {
+            if subscription.inbox.deregister_sender() {
+                let front_guard = subscription.inbox.lock_front();
+                subscription.inbox.notify_not_empty(&front_guard);
+            }
+        }
+    }
+}
+
+impl<T, C> Publisher<T, C>
+where
+    T: Clone,
+    C: Codec<T>,
+{
+    /// Subscribe to the channel
+    ///
+    /// The Subscriber receives every item sent from now on.
+    pub fn subscribe(&self) -> Subscriber<T, C> {
+        let mut log = self.shared.log.lock();
+        let inbox: Inbox<T> = deque::Queue::with_capacity(self.shared.inbox_capacity);
+        for _ in 0..log.publishers {
+            inbox.register_sender();
+        }
+        let id = log.next_id;
+        log.next_id += 1;
+        log.subscriptions.push(Subscription {
+            id,
+            inbox: inbox.clone(),
+            unread: 0,
+            oldest_needed: None,
+        });
+        Subscriber {
+            id,
+            shared: Arc::clone(&self.shared),
+            inbox,
+            codec: self.codec.clone(),
+            fp: None,
+            seq_num: 0,
+            offset: 0,
+            disk_writes_to_read: 0,
+            segment_read: 0,
+        }
+    }
+
+    /// Send an event to every Subscriber
+    ///
+    /// Subscribers with room in memory are handed the event there. The event
+    /// is written to disk, once, for those without. This fails only should
+    /// that write fail -- with `Full` if there is no room for another queue
+    /// file -- in which case no Subscriber receives the event and ownership
+    /// of it is returned to the caller. An event sent with no Subscribers is
+    /// dropped.
+    pub fn send(&mut self, event: T) -> Result<(), (T, super::Error)> {
+        let mut log = self.shared.log.lock();
+        let log = &mut *log;
+        let mut guards: Vec<_> = log
+            .subscriptions
+            .iter()
+            .map(|s| s.inbox.lock_back())
+            .collect();
+        // A Subscriber with disk writes it's yet to be told of takes every
+        // event after them from disk too, keeping its events in order. The
+        // back locks are held, so a Subscriber found with room keeps it.
+        let lagging: Vec<bool> = log
+            .subscriptions
+            .iter()
+            .zip(&guards)
+            .map(|(s, guard)| guard.inner.items > 0 || s.inbox.size() >= s.inbox.capacity())
+            .collect();
+        let position = if lagging.iter().any(|l| *l) {
+            match self.write(&event, &mut log.writer) {
+                Ok(position) => Some(position),
+                Err(err) => {
+                    let lost = forget_dropped(&mut log.writer, &log.subscriptions, &mut guards);
+                    drop(guards);
+                    log.count_lost(&lost);
+                    return Err((event, err));
+                }
+            }
+        } else {
+            None
+        };
+        // Subscribers with room to be told of their disk writes are told
+        // straight away, once those writes are flushed. A Subscriber makes
+        // room without the back lock, so only those found with room before
+        // the flush are told: room found after it may be for writes still in
+        // the buffer.
+        let announcing: Vec<bool> = log
+            .subscriptions
+            .iter()
+            .zip(&lagging)
+            .map(|(s, lagging)| *lagging && s.inbox.size() < s.inbox.capacity())
+            .collect();
+        if announcing.iter().any(|a| *a) {
+            if let Err(err) = self.flush_writer(&mut log.writer) {
+                // The event's own record went with the file, but no
+                // Subscriber had been told to expect it.
+                log.writer.dropped -= 1;
+                let lost = forget_dropped(&mut log.writer, &log.subscriptions, &mut guards);
+                drop(guards);
+                log.count_lost(&lost);
+                return Err((event, err));
+            }
+        }
+        for (((subscription, guard), lagging), announcing) in log
+            .subscriptions
+            .iter()
+            .zip(&mut guards)
+            .zip(&lagging)
+            .zip(&announcing)
+        {
+            match position {
+                Some((seq_num, offset)) if *lagging => {
+                    if guard.inner.items == 0 {
+                        guard.inner.seq_num = seq_num;
+                        guard.inner.offset = offset;
+                    }
+                    guard.inner.items += 1;
+                    if *announcing {
+                        announce(&subscription.inbox, guard);
+                    }
+                }
+                _ => {
+                    let must_wake = subscription
+                        .inbox
+                        .push_back(Delivery::Memory(event.clone()), guard)
+                        .unwrap_or(false);
+                    if must_wake {
+                        let front_guard = subscription.inbox.lock_front();
+                        subscription.inbox.notify_not_empty(&front_guard);
+                    }
+                }
+            }
+        }
+        drop(guards);
+        if let Some((seq_num, _)) = position {
+            for (subscription, _) in log.subscriptions.iter_mut().zip(&lagging).filter(|s| *s.1) {
+                if subscription.unread == 0 {
+                    subscription.oldest_needed = Some(seq_num);
+                }
+                subscription.unread += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Attempt to tell each Subscriber of the disk writes made for it
+    ///
+    /// As with `Sender::flush`, a Subscriber whose in-memory buffer is full
+    /// can't be told, in which case `NoFlush` is returned.
+    pub fn flush(&mut self) -> Result<(), super::Error> {
+        let mut log = self.shared.log.lock();
+        let log = &mut *log;
+        let mut guards: Vec<_> = log
+            .subscriptions
+            .iter()
+            .map(|s| s.inbox.lock_back())
+            .collect();
+        if let Err(err) = self.flush_writer(&mut log.writer) {
+            let lost = forget_dropped(&mut log.writer, &log.subscriptions, &mut guards);
+            drop(guards);
+            log.count_lost(&lost);
+            return Err(err);
+        }
+        let mut result = Ok(());
+        for (subscription, guard) in log.subscriptions.iter().zip(&mut guards) {
+            if guard.inner.items > 0 && !announce(&subscription.inbox, guard) {
+                result = Err(super::Error::NoFlush);
+            }
+        }
+        result
+    }
+
+    /// Return the publisher's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Write `event` to disk as a record of its own, returning the queue file
+    // and offset it was written at.
+    fn write(&self, event: &T, writer: &mut Writer) -> Result<(usize, u64), super::Error> {
+        let header = self.shared.header();
+        let mut record = self
+            .shared
+            .compression
+            .encoder(vec![0; header.prefix_len()])
+            .map_err(super::Error::IoError)?;
+        self.codec
+            .encode(event, &mut record)
+            .map_err(super::Error::IoError)?;
+        let mut record = record.finish().map_err(super::Error::IoError)?;
+        private::fill_prefix(header, &mut record, 1, 0);
+        let new_file =
+            writer.fp.is_none() || writer.bytes_written + record.len() > self.shared.max_disk_bytes;
+        let needed = if new_file {
+            record.len() + private::HEADER_LEN as usize
+        } else {
+            record.len()
+        };
+        if writer.disk_bytes.saturating_add(needed) > self.shared.max_total_disk_bytes {
+            return Err(super::Error::Full);
+        }
+        if new_file {
+            self.next_file(writer)?;
+        }
+        let position = (writer.seq_num, writer.bytes_written as u64);
+        let written = match writer.fp {
+            Some(ref mut fp) => fp.write_all(&record),
+            None => unreachable!(),
+        };
+        if let Err(e) = written {
+            // What made it to disk may end part way through the record, so
+            // nothing more is written to the file.
+            abandon_file(writer);
+            return Err(super::Error::IoError(e));
+        }
+        writer.bytes_written += record.len();
+        writer.unflushed += 1;
+        writer.disk_bytes += record.len();
+        Ok(position)
+    }
+
+    // Move the writer on to a new queue file, should there be room for one.
+    // Subscribers are told of disk writes only once they're flushed, so a
+    // Subscriber reaching the end of a file with records still to read knows
+    // to move on to the next.
+    fn next_file(&self, writer: &mut Writer) -> Result<(), super::Error> {
+        if writer.disk_files_capacity == 0 {
+            return Err(super::Error::Full);
+        }
+        self.flush_writer(writer)?;
+        writer.fp = None;
+        let seq_num = writer.seq_num.wrapping_add(1);
+        let mut fp = fs::File::create(self.shared.root.join(format!("{}", seq_num)))
+            .map(BufWriter::new)
+            .map_err(super::Error::IoError)?;
+        private::write_header(&mut fp, self.shared.header()).map_err(super::Error::IoError)?;
+        writer.disk_files_capacity -= 1;
+        writer.seq_num = seq_num;
+        writer.bytes_written = private::HEADER_LEN as usize;
+        writer.flushed = 0;
+        writer.disk_bytes += private::HEADER_LEN as usize;
+        writer.fp = Some(fp);
+        self.shared.flushed.notify_all();
+        Ok(())
+    }
+
+    fn flush_writer(&self, writer: &mut Writer) -> Result<(), super::Error> {
+        if let Some(ref mut fp) = writer.fp {
+            if let Err(e) = fp.flush() {
+                abandon_file(writer);
+                return Err(super::Error::IoError(e));
+            }
+            writer.flushed = writer.bytes_written;
+            writer.unflushed = 0;
+            self.shared.flushed.notify_all();
+        }
+        Ok(())
+    }
+}
+
+// Give up on the queue file being written after a failed write, dropping
+// whatever is still buffered rather than flush it. The file is cut back to
+// what was flushed, so that a Subscriber reading on from its last record finds
+// the end of the file there. The next record goes to a new file.
+fn abandon_file(writer: &mut Writer) {
+    if let Some(fp) = writer.fp.take() {
+        let buffered = fp.buffer().len();
+        let (fp, _) = fp.into_parts();
+        let cut = if fp.set_len(writer.flushed as u64).is_ok() {
+            writer.bytes_written - writer.flushed
+        } else {
+            buffered
+        };
+        writer.disk_bytes = writer.disk_bytes.saturating_sub(cut);
+        writer.dropped += writer.unflushed;
+        writer.unflushed = 0;
+    }
+}
+
+// Take the records dropped with an abandoned file off the disk writes the
+// Subscribers are yet to be told of, returning how many each lost. Those are
+// the newest writes made, so a Subscriber lost the last of its own. What's
+// left of its writes is on disk and it's told of them should it have room,
+// the writes after starting a new segment in the next file.
+fn forget_dropped<T>(
+    writer: &mut Writer,
+    subscriptions: &[Subscription<T>],
+    guards: &mut [MutexGuard<BackGuardInner<Lag>>],
+) -> Vec<usize> {
+    let dropped = writer.dropped;
+    writer.dropped = 0;
+    subscriptions
+        .iter()
+        .zip(guards)
+        .map(|(subscription, guard)| {
+            let lost = ::std::cmp::min(guard.inner.items, dropped);
+            guard.inner.items -= lost;
+            if guard.inner.items > 0 && lost > 0 {
+                announce(&subscription.inbox, guard);
+            }
+            lost
+        })
+        .collect()
+}
+
+// Push a disk placement telling the Subscriber of the disk writes made for
+// it, returning whether there was room to.
+fn announce<T>(inbox: &Inbox<T>, guard: &mut MutexGuard<BackGuardInner<Lag>>) -> bool {
+    let delivery = Delivery::Disk {
+        seq_num: guard.inner.seq_num,
+        offset: guard.inner.offset,
+        items: guard.inner.items,
+    };
+    match inbox.push_back(delivery, guard) {
+        Ok(must_wake) => {
+            guard.inner.items = 0;
+            if must_wake {
+                let front_guard = inbox.lock_front();
+                inbox.notify_not_empty(&front_guard);
+            }
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// The 'receive' side of a broadcast channel
+///
+/// A Subscriber reads the queue files at its own pace. Dropping it
+/// unsubscribes, letting go of any queue files only it had left to read.
+#[derive(Debug)]
+pub struct Subscriber<T, C = Bincode> {
+    id: usize,
+    shared: Arc<Shared<T>>,
+    inbox: Inbox<T>,
+    codec: C,
+    fp: Option<BufReader<fs::File>>,
+    seq_num: usize, // the queue file of the next record to read
+    offset: u64,    // the offset into it of the next record to read
+    disk_writes_to_read: usize,
+    segment_read: usize, // records read since last reporting in
+}
+
+impl<T, C> Drop for Subscriber<T, C> {
+    fn drop(&mut self) {
+        let mut log = self.shared.log.lock();
+        log.subscriptions.retain(|s| s.id != self.id);
+        self.inbox.deregister_receiver();
+        self.shared.collect_garbage(&mut log);
+    }
+}
+
+impl<T, C> Subscriber<T, C>
+where
+    C: Codec<T>,
+{
+    // Tell the other side what we've read, so that queue files we're done
+    // with can be removed.
+    fn report(&mut self) {
+        let mut log = self.shared.log.lock();
+        let seq_num = self.seq_num;
+        if let Some(subscription) = log.subscriptions.iter_mut().find(|s| s.id == self.id) {
+            subscription.unread -= self.segment_read;
+            subscription.oldest_needed = if subscription.unread == 0 {
+                None
+            } else {
+                Some(seq_num)
+            };
+        }
+        self.segment_read = 0;
+        self.shared.collect_garbage(&mut log);
+    }
+
+    fn record_read(&mut self) {
+        self.disk_writes_to_read -= 1;
+        self.segment_read += 1;
+        if self.disk_writes_to_read == 0 {
+            self.report();
+        }
+    }
+
+    // Start reading the records of a disk placement.
+    fn read_from(&mut self, seq_num: usize, offset: u64, items: usize) {
+        if (seq_num, offset) != (self.seq_num, self.offset) {
+            self.fp = None;
+            self.seq_num = seq_num;
+            self.offset = offset;
+        }
+        self.disk_writes_to_read = items;
+    }
+
+    fn next_file(&mut self) {
+        self.fp = None;
+        self.seq_num = self.seq_num.wrapping_add(1);
+        self.offset = private::HEADER_LEN;
+        self.report();
+    }
+
+    // We've hit the end of a queue file with records still to read. Should
+    // the Publishers have moved on from the file so do we. Otherwise the
+    // records are yet to be flushed and we wait for them -- unless every
+    // Publisher has hung up, in which case they never will be. We wait no
+    // later than `deadline`, if there is one, returning false should it pass.
+    fn end_of_file(&mut self, deadline: Option<Instant>) -> Result<bool, super::Error> {
+        let mut log = self.shared.log.lock();
+        while log.writer.seq_num == self.seq_num
+            && log.publishers > 0
+            && log.writer.flushed as u64 <= self.offset
+        {
+            match deadline {
+                None => self.shared.flushed.wait(&mut log),
+                Some(deadline) => {
+                    if Instant::now() >= deadline {
+                        return Ok(false);
+                    }
+                    self.shared.flushed.wait_until(&mut log, deadline);
+                }
+            }
+        }
+        let writing = log.writer.seq_num == self.seq_num;
+        let hung_up = log.publishers == 0;
+        drop(log);
+        if !writing {
+            self.next_file();
+        } else if hung_up {
+            self.segment_read += self.disk_writes_to_read;
+            self.disk_writes_to_read = 0;
+            self.report();
+            return Err(super::Error::Corrupt);
+        }
+        Ok(true)
+    }
+
+    // A record cut short can only be the last in its file, the writer having
+    // given up on the file part way through it.
+    fn cut_short(&mut self) -> super::Error {
+        self.record_read();
+        if self.disk_writes_to_read > 0 {
+            self.next_file();
+        }
+        super::Error::Corrupt
+    }
+
+    // Read the next record from disk, waiting no later than `deadline` for it
+    // to be flushed. None is returned should the deadline pass.
+    fn read_disk_value(&mut self, deadline: Option<Instant>) -> Result<Option<T>, super::Error> {
+        loop {
+            let fp = match self.fp {
+                Some(ref mut fp) => fp,
+                None => {
+                    let path = self.shared.root.join(format!("{}", self.seq_num));
+                    let mut fp = fs::File::open(path).map_err(super::Error::IoError)?;
+                    fp.seek(SeekFrom::Start(self.offset))
+                        .map_err(super::Error::IoError)?;
+                    self.fp.get_or_insert(BufReader::new(fp))
+                }
+            };
+            let mut prefix = [0; 2 * PAYLOAD_LEN_BYTES];
+            let read = private::read_full(fp, &mut prefix);
+            match read {
+                Ok(0) => {
+                    if !self.end_of_file(deadline)? {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Ok(read) if read < prefix.len() => return Err(self.cut_short()),
+                Ok(_) => {}
+                Err(e) => {
+                    self.fp = None;
+                    return Err(super::Error::IoError(e));
+                }
+            }
+            let payload_len = BigEndian::read_u32(&prefix[..PAYLOAD_LEN_BYTES]);
+            let crc = BigEndian::read_u32(&prefix[PAYLOAD_LEN_BYTES..]);
+            let record_end = self.offset + prefix.len() as u64 + u64::from(payload_len);
+            // Check the advertised payload is there before making room for
+            // it, as a corrupt length could be anything.
+            let file_len = match fp.get_ref().metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    self.fp = None;
+                    return Err(super::Error::IoError(e));
+                }
+            };
+            if record_end > file_len {
+                return Err(self.cut_short());
+            }
+            let mut payload = vec![0; payload_len as usize];
+            match private::read_full(fp, &mut payload) {
+                Ok(read) if read < payload.len() => return Err(self.cut_short()),
+                Ok(_) => {}
+                Err(e) => {
+                    self.fp = None;
+                    return Err(super::Error::IoError(e));
+                }
+            }
+            self.offset = record_end;
+            let decoded = if private::checksum(&[], &payload) != crc {
+                Err(super::Error::Corrupt)
+            } else {
+                match self.shared.compression.decoder(&payload[..]) {
+                    Ok(mut dec) => self
+                        .codec
+                        .decode(&mut dec)
+                        .map_err(super::Error::Deserialize),
+                    Err(_) => Err(super::Error::Corrupt),
+                }
+            };
+            self.record_read();
+            return decoded.map(Some);
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        // As for the Receiver, the Subscriber takes items from its in-memory
+        // deque until it's handed a disk placement, then reads that many
+        // records from disk. Records it can't read are reported and passed
+        // over. Once hung up on there may yet be disk writes it was never
+        // told of.
+        loop {
+            if self.disk_writes_to_read > 0 {
+                return match self.read_disk_value(deadline) {
+                    Ok(Some(event)) => Ok(event),
+                    Ok(None) => Err(RecvTimeoutError::Timeout),
+                    Err(e) => Err(RecvTimeoutError::Disk(e)),
+                };
+            }
+            let popped = match deadline {
+                None => self.inbox.pop_front().ok_or(PopError::Disconnected),
+                Some(deadline) => self.inbox.pop_front_until(deadline),
+            };
+            match popped {
+                Ok(Delivery::Memory(event)) => {
+                    return Ok(event);
+                }
+                Ok(Delivery::Disk {
+                    seq_num,
+                    offset,
+                    items,
+                }) => self.read_from(seq_num, offset, items),
+                Err(PopError::Empty) => return Err(RecvTimeoutError::Timeout),
+                Err(PopError::Disconnected) => {
+                    let mut back_guard = self.inbox.lock_back();
+                    let lag = ::std::mem::take(&mut back_guard.inner);
+                    drop(back_guard);
+                    if lag.items == 0 {
+                        return Err(RecvTimeoutError::Disconnected);
+                    }
+                    self.read_from(lag.seq_num, lag.offset, lag.items);
+                }
+            }
+        }
+    }
+
+    /// Receive a value, blocking until one is available
+    ///
+    /// Errors are as for `Receiver::recv`. A value that can't be read from
+    /// disk is passed over once reported.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        match self.recv_until(None) {
+            Ok(ev) => Ok(ev),
+            Err(RecvTimeoutError::Disk(e)) => Err(RecvError::Disk(e)),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                Err(RecvError::Disconnected)
+            }
+        }
+    }
+
+    /// Attempt to receive a value without blocking
+    ///
+    /// Errors are as for `Receiver::try_recv`.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.recv_until(Some(Instant::now())) {
+            Ok(ev) => Ok(ev),
+            Err(RecvTimeoutError::Timeout) => Err(TryRecvError::Empty),
+            Err(RecvTimeoutError::Disconnected) => Err(TryRecvError::Disconnected),
+            Err(RecvTimeoutError::Disk(e)) => Err(TryRecvError::Disk(e)),
+        }
+    }
+
+    /// Receive a value, blocking for no longer than `timeout`
+    ///
+    /// Errors are as for `Receiver::recv_timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(private::deadline(timeout))
+    }
+
+    /// An iterator over messages on the subscriber, ending as that of
+    /// `Receiver::iter` does
+    pub fn iter(&mut self) -> Iter<'_, T, C> {
+        Iter { rx: self }
+    }
+}
+
+/// An iterator over the values received by a `Subscriber`
+#[derive(Debug)]
+pub struct Iter<'a, T, C = Bincode>
+where
+    T: 'a,
+    C: 'a,
+{
+    rx: &'a mut Subscriber<T, C>,
+}
+
+impl<'a, T, C> Iterator for Iter<'a, T, C>
+where
+    C: Codec<T>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
@@ -0,0 +1,192 @@
+//! Channels built up option by option
+//!
+//! `ChannelBuilder` sets the options of a channel one at a time, starting
+//! from the defaults of `Config`, and checks they make sense before any queue
+//! file is touched.
+use super::{
+    Block, Checkpoint, Config, ConfigError, CorruptionPolicy, Error, FsyncPolicy,
+    MemoryAccounting, OverflowPolicy,
+};
+use codec::{Bincode, Codec};
+use compression::Compression;
+use private;
+use receiver::Receiver;
+use sender::Sender;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A channel yet to be created
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd, mut rcv) = hopper::ChannelBuilder::new("example", dir.path())
+///     .max_memory_bytes(64 * 1024)
+///     .max_disk_files(16)
+///     .overflow(hopper::OverflowPolicy::DropOldest)
+///     .build()
+///     .unwrap();
+///
+/// snd.send(9);
+/// assert_eq!(Some(9), rcv.iter().next());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChannelBuilder<C = Bincode> {
+    name: String,
+    data_dir: PathBuf,
+    config: Config,
+    codec: C,
+}
+
+impl ChannelBuilder<Bincode> {
+    /// Start building a channel named `name` whose queue files are stored in
+    /// `data_dir`, serialized with `Bincode`
+    pub fn new(name: &str, data_dir: &Path) -> ChannelBuilder<Bincode> {
+        ChannelBuilder {
+            name: name.to_string(),
+            data_dir: data_dir.to_path_buf(),
+            config: Config::default(),
+            codec: Bincode,
+        }
+    }
+}
+
+impl<C> ChannelBuilder<C> {
+    /// Take every option from `config`, those set before included
+    ///
+    /// Options set afterwards are set on top of it.
+    pub fn config(mut self, config: Config) -> ChannelBuilder<C> {
+        self.config = config;
+        self
+    }
+
+    /// Set the most bytes held in memory, `Config::max_memory_bytes`
+    pub fn max_memory_bytes(mut self, max_memory_bytes: usize) -> ChannelBuilder<C> {
+        self.config.max_memory_bytes = max_memory_bytes;
+        self
+    }
+
+    /// Set the maximum size of a queue file, `Config::max_disk_bytes`
+    pub fn max_disk_bytes(mut self, max_disk_bytes: usize) -> ChannelBuilder<C> {
+        self.config.max_disk_bytes = max_disk_bytes;
+        self
+    }
+
+    /// Set the most queue files there may be, `Config::max_disk_files`
+    pub fn max_disk_files(mut self, max_disk_files: usize) -> ChannelBuilder<C> {
+        self.config.max_disk_files = max_disk_files;
+        self
+    }
+
+    /// Set the most bytes the queue files may take up altogether,
+    /// `Config::max_total_disk_bytes`
+    pub fn max_total_disk_bytes(mut self, max_total_disk_bytes: usize) -> ChannelBuilder<C> {
+        self.config.max_total_disk_bytes = max_total_disk_bytes;
+        self
+    }
+
+    /// Set the compression applied to items paged to disk
+    pub fn compression(mut self, compression: Compression) -> ChannelBuilder<C> {
+        self.config.compression = compression;
+        self
+    }
+
+    /// Serialize items paged to disk with `codec`
+    pub fn codec<D>(self, codec: D) -> ChannelBuilder<D> {
+        ChannelBuilder {
+            name: self.name,
+            data_dir: self.data_dir,
+            config: self.config,
+            codec,
+        }
+    }
+
+    /// Set whether items paged to disk are compressed in blocks,
+    /// `Config::block`
+    pub fn block(mut self, block: Option<Block>) -> ChannelBuilder<C> {
+        self.config.block = block;
+        self
+    }
+
+    /// Set whether the channel survives a restart, `Config::durable`
+    pub fn durable(mut self, durable: bool) -> ChannelBuilder<C> {
+        self.config.durable = durable;
+        self
+    }
+
+    /// Set how often a durable channel's Receiver records its position,
+    /// `Config::checkpoint`
+    pub fn checkpoint(mut self, checkpoint: Checkpoint) -> ChannelBuilder<C> {
+        self.config.checkpoint = checkpoint;
+        self
+    }
+
+    /// Set what the Receiver does on finding a queue file corrupt
+    pub fn corruption(mut self, corruption: CorruptionPolicy) -> ChannelBuilder<C> {
+        self.config.corruption = corruption;
+        self
+    }
+
+    /// Set what a Sender does with an item there's no room for
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> ChannelBuilder<C> {
+        self.config.overflow = overflow;
+        self
+    }
+
+    /// Set when a Sender has the OS write a queue file through to disk
+    pub fn fsync(mut self, fsync: FsyncPolicy) -> ChannelBuilder<C> {
+        self.config.fsync = fsync;
+        self
+    }
+
+    /// Set how long an item may wait to be received, `Config::ttl`
+    pub fn ttl(mut self, ttl: Option<Duration>) -> ChannelBuilder<C> {
+        self.config.ttl = ttl;
+        self
+    }
+
+    /// Set how the items held in memory are counted against
+    /// `max_memory_bytes`, `Config::memory_accounting`
+    pub fn memory_accounting(mut self, memory_accounting: MemoryAccounting) -> ChannelBuilder<C> {
+        self.config.memory_accounting = memory_accounting;
+        self
+    }
+
+    /// Check the options make sense
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !private::name_is_plain(&self.name) {
+            return Err(ConfigError::InvalidName);
+        }
+        for dir in &[self.data_dir.clone(), self.data_dir.join(&self.name)] {
+            if dir.exists() && !dir.is_dir() {
+                return Err(ConfigError::NotADirectory);
+            }
+        }
+        if self.config.max_disk_bytes == 0 {
+            return Err(ConfigError::ZeroFileSize);
+        }
+        if self.config.max_disk_files == 0 {
+            return Err(ConfigError::ZeroDiskFiles);
+        }
+        if self.config.max_total_disk_bytes == 0 {
+            return Err(ConfigError::ZeroDiskQuota);
+        }
+        self.config.check()
+    }
+
+    /// Create the (Sender, Receiver) pair
+    ///
+    /// Options that make no sense are refused with `Error::Config`, before
+    /// anything is created. Otherwise errors are as for `channel_with_codec`.
+    #[allow(clippy::type_complexity)]
+    pub fn build<T>(self) -> Result<(Sender<T, C>, Receiver<T, C>), Error>
+    where
+        C: Codec<T>,
+    {
+        self.validate().map_err(Error::Config)?;
+        super::channel_with_codec(&self.name, &self.data_dir, self.config, self.codec)
+    }
+}
@@ -0,0 +1,112 @@
+//! Serialization of items paged to disk
+//!
+//! Items which don't fit in memory are serialized by a `Codec` before being
+//! compressed and written out to a queue file. Hopper uses `Bincode` unless
+//! told otherwise, which requires items to be serde `Serialize` and
+//! `DeserializeOwned`. Channels whose items are already encoded -- or which
+//! would rather encode them some other way -- can be created with
+//! `channel_with_codec` and a codec of their choosing.
+use bincode::{deserialize_from, serialize_into, serialized_size};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/// Encodes and decodes the items of a channel for storage on disk
+///
+/// A Codec is cloned into every Sender and the Receiver of a channel. Every
+/// value written with `encode` must be readable by `decode`, though the
+/// decoder is handed a reader that ends where the encoded value does.
+pub trait Codec<T>: Clone {
+    /// Serialize `value` into `writer`
+    fn encode<W>(&self, value: &T, writer: &mut W) -> io::Result<()>
+    where
+        W: Write;
+
+    /// Deserialize a value from `reader`
+    fn decode<R>(&self, reader: &mut R) -> io::Result<T>
+    where
+        R: Read;
+
+    /// The bytes of memory `value` is reckoned to take up
+    ///
+    /// This is only consulted by channels that account for memory in bytes.
+    /// See `MemoryAccounting`. By default a value weighs as much as its
+    /// encoding.
+    fn weigh(&self, value: &T) -> usize {
+        let mut counter = ByteCounter(0);
+        match self.encode(value, &mut counter) {
+            Ok(()) => counter.0,
+            Err(_) => 0,
+        }
+    }
+}
+
+// A writer that keeps only a count of the bytes written to it.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The default codec, serializing items with
+/// [bincode](https://docs.rs/bincode)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bincode;
+
+impl<T> Codec<T> for Bincode
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode<W>(&self, value: &T, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        serialize_into(writer, value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn decode<R>(&self, reader: &mut R) -> io::Result<T>
+    where
+        R: Read,
+    {
+        deserialize_from(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn weigh(&self, value: &T) -> usize {
+        serialized_size(value).unwrap_or(0) as usize
+    }
+}
+
+/// A codec for items that are already encoded
+///
+/// The bytes of each item are written out as-is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Passthrough;
+
+impl Codec<Vec<u8>> for Passthrough {
+    fn encode<W>(&self, value: &Vec<u8>, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_all(value)
+    }
+
+    fn decode<R>(&self, reader: &mut R) -> io::Result<Vec<u8>>
+    where
+        R: Read,
+    {
+        let mut value = Vec::new();
+        reader.read_to_end(&mut value)?;
+        Ok(value)
+    }
+
+    fn weigh(&self, value: &Vec<u8>) -> usize {
+        value.len()
+    }
+}
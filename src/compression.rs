@@ -0,0 +1,201 @@
+//! Compression of items paged to disk
+//!
+//! Every item written to a queue file is compressed after being serialized by
+//! the channel's codec. Which compression is used is a property of the
+//! channel, set through `Config::compression`, and is recorded in the header
+//! of every queue file so that the Receiver -- even one from a later process,
+//! configured differently -- knows how to decompress what it reads.
+use flate2;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+#[cfg(feature = "lz4")]
+use lz4_flex;
+use std::io::{self, Read, Write};
+#[cfg(feature = "zstd")]
+use zstd;
+
+/// The compression applied to items paged to disk
+///
+/// Small items are often not worth compressing: the payload may even grow.
+/// `None` skips compression entirely.
+///
+/// Some compressions are only to be had with the cargo feature of the same
+/// name, so matching on a `Compression` takes a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Write items as the codec serialized them
+    None,
+    /// Deflate items at the given level, from 0 (none) to 9 (best)
+    Deflate(u32),
+    /// Compress items with zstd at the given level
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// Compress items with the lz4 frame format
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Limits on the blocks in which items paged to disk are compressed together
+///
+/// Small, similar items compress poorly one at a time. When `Config::block`
+/// is set the Senders gather items paged to disk into a block and compress
+/// the block as a unit. A block is written out once it holds `max_items`
+/// items or `max_bytes` bytes of serialized items, whichever comes first, or
+/// sooner should the Receiver be able to take delivery of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    /// The most items a block will hold
+    pub max_items: usize,
+    /// The serialized size past which a block is written out
+    pub max_bytes: usize,
+}
+
+impl Default for Block {
+    fn default() -> Block {
+        Block {
+            max_items: 1024,
+            max_bytes: 0x10_000,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::Deflate(1)
+    }
+}
+
+impl Compression {
+    // Whether the compression's level is one it has
+    pub(crate) fn is_valid(self) -> bool {
+        match self {
+            Compression::Deflate(level) => level <= 9,
+            _ => true,
+        }
+    }
+
+    // The tag recorded for this compression in a queue file header
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate(_) => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => 2,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 3,
+        }
+    }
+
+    // The compression recorded in a queue file header. Levels are not
+    // recorded, being of no interest to a decompressor.
+    pub(crate) fn from_tag(tag: u8) -> io::Result<Compression> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate(0)),
+            #[cfg(feature = "zstd")]
+            2 => Ok(Compression::Zstd(0)),
+            #[cfg(feature = "lz4")]
+            3 => Ok(Compression::Lz4),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown or unsupported compression tag {}", tag),
+            )),
+        }
+    }
+
+    // Begin compressing into `buf`
+    pub(crate) fn encoder(self, buf: Vec<u8>) -> io::Result<Encoder> {
+        Ok(match self {
+            Compression::None => Encoder::None(buf),
+            Compression::Deflate(level) => {
+                Encoder::Deflate(DeflateEncoder::new(buf, flate2::Compression::new(level)))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Encoder::Zstd(zstd::stream::Encoder::new(buf, level)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(buf)),
+        })
+    }
+
+    // Begin decompressing `payload`
+    pub(crate) fn decoder(self, payload: &[u8]) -> io::Result<Decoder<'_>> {
+        Ok(match self {
+            Compression::None => Decoder::None(payload),
+            Compression::Deflate(_) => Decoder::Deflate(DeflateDecoder::new(payload)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => Decoder::Zstd(zstd::stream::Decoder::with_buffer(payload)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Decoder::Lz4(lz4_flex::frame::FrameDecoder::new(payload)),
+        })
+    }
+}
+
+pub(crate) enum Encoder {
+    None(Vec<u8>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::Encoder<'static, Vec<u8>>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    // Finish compression, returning the compressed bytes
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::None(buf) => Ok(buf),
+            Encoder::Deflate(e) => e.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.finish(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(e) => e.finish().map_err(io::Error::from),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Encoder::None(ref mut w) => w.write(buf),
+            Encoder::Deflate(ref mut w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(ref mut w) => w.write(buf),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::None(ref mut w) => w.flush(),
+            Encoder::Deflate(ref mut w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(ref mut w) => w.flush(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(ref mut w) => w.flush(),
+        }
+    }
+}
+
+pub(crate) enum Decoder<'a> {
+    None(&'a [u8]),
+    Deflate(DeflateDecoder<&'a [u8]>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::Decoder<'static, &'a [u8]>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameDecoder<&'a [u8]>),
+}
+
+impl<'a> Read for Decoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Decoder::None(ref mut r) => r.read(buf),
+            Decoder::Deflate(ref mut r) => r.read(buf),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(ref mut r) => r.read(buf),
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(ref mut r) => r.read(buf),
+        }
+    }
+}
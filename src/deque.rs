@@ -6,12 +6,18 @@
 // operations. The underlying structure is a contiguous allocation operated like
 // a ring buffer. When the buffer fills up enqueue fails. The only coordination
 // that does happen is through a condvar, waking up a pop_front operation that
-// blocks when there's no data to pop.
+// blocks when there's no data to pop. The queue also keeps a count of the
+// Senders pushing onto its back, so that a blocked pop_front can give up once
+// the last of them has gone away. A Receiver which would rather not block a
+// thread can park a Waker in the queue instead, which is woken alongside the
+// condvar.
 //
 // The exact API is a little weird, which we'll get into below. Just keep in
 // mind: it's a contiguous block of memory with some fancy bits tacked on.
 use parking_lot::{Condvar, Mutex, MutexGuard};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::task::Waker;
+use std::time::Instant;
 use std::{mem, sync};
 
 unsafe impl<T, S> Send for Queue<T, S> {}
@@ -31,6 +37,11 @@ struct InnerQueue<T, S> {
     back_lock: Mutex<BackGuardInner<S>>,
     front_lock: Mutex<FrontGuardInner>,
     not_empty: Condvar,
+    not_empty_waker: Mutex<Option<Waker>>,
+    senders: AtomicUsize,
+    not_full: Condvar,
+    waiting_senders: AtomicUsize,
+    receiver_gone: AtomicBool,
 }
 
 impl<T, S> Drop for InnerQueue<T, S> {
@@ -50,6 +61,12 @@ pub enum Error<T> {
     Full(T),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PopError {
+    Empty,
+    Disconnected,
+}
+
 // FrontGuardInner and BackGuardInner are the insides of the front and back
 // locks. What's curious about BackGuardInner is that you can smuggle data
 // inside of it. This is driven _entirely_ by the needs of Sender, which has to
@@ -81,7 +98,7 @@ where
         for _ in 0..capacity {
             data.push(None);
         }
-        let raw_data = (&mut data).as_mut_ptr();
+        let raw_data = data.as_mut_ptr();
         mem::forget(data);
         InnerQueue {
             capacity,
@@ -93,6 +110,11 @@ where
             }),
             front_lock: Mutex::new(FrontGuardInner { offset: 0 }),
             not_empty: Condvar::new(),
+            not_empty_waker: Mutex::new(None),
+            senders: AtomicUsize::new(0),
+            not_full: Condvar::new(),
+            waiting_senders: AtomicUsize::new(0),
+            receiver_gone: AtomicBool::new(false),
         }
     }
 
@@ -104,11 +126,11 @@ where
         self.size.load(Ordering::Relaxed)
     }
 
-    pub fn lock_back(&self) -> MutexGuard<BackGuardInner<S>> {
+    pub fn lock_back(&self) -> MutexGuard<'_, BackGuardInner<S>> {
         self.back_lock.lock()
     }
 
-    pub fn lock_front(&self) -> MutexGuard<FrontGuardInner> {
+    pub fn lock_front(&self) -> MutexGuard<'_, FrontGuardInner> {
         self.front_lock.lock()
     }
 
@@ -121,10 +143,10 @@ where
         if self.size.load(Ordering::Acquire) == self.capacity {
             return Err(Error::Full(elem));
         } else {
-            assert!((*self.data.offset((*guard).offset)).is_none());
-            *self.data.offset((*guard).offset) = Some(elem);
-            (*guard).offset += 1;
-            (*guard).offset %= self.capacity as isize;
+            assert!((*self.data.offset(guard.offset)).is_none());
+            *self.data.offset(guard.offset) = Some(elem);
+            guard.offset += 1;
+            guard.offset %= self.capacity as isize;
             if self.size.fetch_add(1, Ordering::Release) == 0 {
                 must_wake_dequeuers = true;
             }
@@ -132,16 +154,82 @@ where
         Ok(must_wake_dequeuers)
     }
 
-    pub unsafe fn pop_front(&self) -> T {
+    pub unsafe fn pop_front(&self, deadline: Option<Instant>) -> Result<T, PopError> {
         let mut guard = self.front_lock.lock();
+        self.wait_not_empty(&mut guard, deadline)?;
+        Ok(self.take_front(&mut guard))
+    }
+
+    pub unsafe fn pop_front_batch<F>(
+        &self,
+        max: usize,
+        deadline: Option<Instant>,
+        out: &mut Vec<T>,
+        is_last: F,
+    ) -> Result<(), PopError>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut guard = self.front_lock.lock();
+        self.wait_not_empty(&mut guard, deadline)?;
+        let mut popped = 0;
+        while popped < max && self.size.load(Ordering::Acquire) > 0 {
+            let elem = self.take_front(&mut guard);
+            popped += 1;
+            let last = is_last(&elem);
+            out.push(elem);
+            if last {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub unsafe fn pop_front_if<F>(&self, pred: F) -> Option<T>
+    where
+        F: FnOnce(&T) -> bool,
+    {
+        let mut guard = self.front_lock.lock();
+        if self.size.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        match *self.data.offset(guard.offset) {
+            Some(ref elem) if pred(elem) => {}
+            _ => return None,
+        }
+        Some(self.take_front(&mut guard))
+    }
+
+    fn wait_not_empty(
+        &self,
+        guard: &mut MutexGuard<FrontGuardInner>,
+        deadline: Option<Instant>,
+    ) -> Result<(), PopError> {
         while self.size.load(Ordering::Acquire) == 0 {
-            self.not_empty.wait(&mut guard);
+            if self.senders.load(Ordering::Acquire) == 0 {
+                return Err(PopError::Disconnected);
+            }
+            match deadline {
+                None => {
+                    self.not_empty.wait(guard);
+                }
+                Some(deadline) => {
+                    if Instant::now() >= deadline {
+                        return Err(PopError::Empty);
+                    }
+                    self.not_empty.wait_until(guard, deadline);
+                }
+            }
         }
-        let elem: Option<T> = mem::replace(&mut *self.data.offset((*guard).offset), None);
+        Ok(())
+    }
+
+    unsafe fn take_front(&self, guard: &mut MutexGuard<FrontGuardInner>) -> T {
+        let elem: Option<T> = (*self.data.offset(guard.offset)).take();
         assert!(elem.is_some());
-        *self.data.offset((*guard).offset) = None;
-        (*guard).offset += 1;
-        (*guard).offset %= self.capacity as isize;
+        *self.data.offset(guard.offset) = None;
+        guard.offset += 1;
+        guard.offset %= self.capacity as isize;
         self.size.fetch_sub(1, Ordering::Release);
         elem.unwrap()
     }
@@ -182,11 +270,11 @@ where
         (*self.inner).size()
     }
 
-    pub fn lock_back(&self) -> MutexGuard<BackGuardInner<S>> {
+    pub fn lock_back(&self) -> MutexGuard<'_, BackGuardInner<S>> {
         (*self.inner).lock_back()
     }
 
-    pub fn lock_front(&self) -> MutexGuard<FrontGuardInner> {
+    pub fn lock_front(&self) -> MutexGuard<'_, FrontGuardInner> {
         (*self.inner).lock_front()
     }
 
@@ -202,24 +290,181 @@ where
     pub fn push_back(
         &self,
         elem: T,
-        mut guard: &mut MutexGuard<BackGuardInner<S>>,
+        guard: &mut MutexGuard<BackGuardInner<S>>,
     ) -> Result<bool, Error<T>> {
-        unsafe { (*self.inner).push_back(elem, &mut guard) }
+        unsafe { (*self.inner).push_back(elem, guard) }
     }
 
     pub fn notify_not_empty(&self, _guard: &MutexGuard<FrontGuardInner>) {
         // guard is not used here but is required to verifiy that 1. a deadlock
         // situation has not happened and 2. we're not doing a notify without
         // holding the lock.
-        (*self.inner).not_empty.notify_all()
+        self.inner.not_empty.notify_all();
+        if let Some(waker) = self.inner.not_empty_waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// Register a Waker to be woken when the queue becomes non-empty
+    ///
+    /// Only one Waker is held at a time, the most recently registered. The
+    /// Waker is woken -- and forgotten -- by the next notify_not_empty, so the
+    /// caller must register _before_ checking the queue for elements or it
+    /// may miss its wake up.
+    pub fn register_waker(&self, waker: &Waker) {
+        let mut slot = self.inner.not_empty_waker.lock();
+        match *slot {
+            Some(ref registered) if registered.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
     }
 
     /// Pop an element from the front of the queue
     ///
     /// This function WILL block if there are no elements to be popped from the
     /// front. This block will take no CPU time and the caller thread will only
-    /// wake once an element has been pushed onto the queue.
-    pub fn pop_front(&mut self) -> T {
-        unsafe { (*self.inner).pop_front() }
+    /// wake once an element has been pushed onto the queue. If the queue is
+    /// empty and there are no registered senders None is returned.
+    pub fn pop_front(&mut self) -> Option<T> {
+        unsafe { (*self.inner).pop_front(None).ok() }
+    }
+
+    /// Pop an element from the front of the queue, blocking no later than
+    /// `deadline`
+    ///
+    /// This function behaves as `pop_front` except that it gives up with
+    /// `PopError::Empty` once `deadline` has passed. A deadline in the past
+    /// makes for a non-blocking pop.
+    pub fn pop_front_until(&mut self, deadline: Instant) -> Result<T, PopError> {
+        unsafe { (*self.inner).pop_front(Some(deadline)) }
+    }
+
+    /// Wait, no later than `deadline`, for an element to be pushed
+    ///
+    /// Nothing is popped. Returns false should `deadline` pass with the queue
+    /// still empty. Returning true is no promise that there's an element: the
+    /// queue may have been hung up on, or another popped it first.
+    pub fn wait_not_empty(&self, deadline: Option<Instant>) -> bool {
+        let mut guard = self.inner.front_lock.lock();
+        match self.inner.wait_not_empty(&mut guard, deadline) {
+            Ok(()) | Err(PopError::Disconnected) => true,
+            Err(PopError::Empty) => false,
+        }
+    }
+
+    /// Pop the element at the front of the queue, should there be one and
+    /// `pred` hold for it
+    ///
+    /// This function never blocks. It's for a sender making room, so unlike
+    /// `pop_front` it may be called from other than the receiving side.
+    pub fn pop_front_if<F>(&self, pred: F) -> Option<T>
+    where
+        F: FnOnce(&T) -> bool,
+    {
+        unsafe { (*self.inner).pop_front_if(pred) }
+    }
+
+    /// Announce a sender that will wait for room should it find none
+    ///
+    /// This function must be called _before_ the sender looks for room, else
+    /// room made between its looking and its waiting may go unannounced. Every
+    /// call must be matched by a call to `stop_waiting_not_full`.
+    pub fn start_waiting_not_full(&self) {
+        self.inner.waiting_senders.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    pub fn stop_waiting_not_full(&self) {
+        self.inner.waiting_senders.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Wait, no later than `deadline`, for room to be made
+    ///
+    /// The back lock is given up while waiting, as for any condvar. Returns
+    /// false if `deadline` passed first or if there's no Receiver left to make
+    /// room. Waking is no promise that there's room: the caller must look
+    /// again.
+    pub fn wait_not_full(
+        &self,
+        guard: &mut MutexGuard<BackGuardInner<S>>,
+        deadline: Option<Instant>,
+    ) -> bool {
+        if self.inner.receiver_gone.load(Ordering::Acquire) {
+            return false;
+        }
+        match deadline {
+            None => {
+                self.inner.not_full.wait(guard);
+                true
+            }
+            Some(deadline) => {
+                Instant::now() < deadline
+                    && !self.inner.not_full.wait_until(guard, deadline).timed_out()
+            }
+        }
+    }
+
+    /// Wake any senders waiting for room
+    ///
+    /// The caller must not hold the back lock. This is cheap when no sender
+    /// is waiting, so call it whenever room is made.
+    pub fn notify_not_full(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.inner.waiting_senders.load(Ordering::SeqCst) > 0 {
+            let _guard = self.inner.back_lock.lock();
+            self.inner.not_full.notify_all();
+        }
+    }
+
+    /// Whether the queue has lost its Receiver
+    pub fn receiver_gone(&self) -> bool {
+        self.inner.receiver_gone.load(Ordering::Acquire)
+    }
+
+    /// Mark the queue as having lost its Receiver, so that no sender waits
+    /// for room that will never be made
+    pub fn deregister_receiver(&self) {
+        self.inner.receiver_gone.store(true, Ordering::Release);
+        let _guard = self.inner.back_lock.lock();
+        self.inner.not_full.notify_all();
+    }
+
+    /// Pop up to `max` elements from the front of the queue, blocking no later
+    /// than `deadline` for the first
+    ///
+    /// Popped elements are pushed onto `out`, all under one acquisition of
+    /// the front lock. Popping stops early after an element for which
+    /// `is_last` holds, or once the queue is empty. Errors are as for
+    /// `pop_front_until`, with no deadline meaning block indefinitely.
+    pub fn pop_front_batch<F>(
+        &mut self,
+        max: usize,
+        deadline: Option<Instant>,
+        out: &mut Vec<T>,
+        is_last: F,
+    ) -> Result<(), PopError>
+    where
+        F: Fn(&T) -> bool,
+    {
+        unsafe { (*self.inner).pop_front_batch(max, deadline, out, is_last) }
+    }
+
+    /// Register a new sender with the queue
+    pub fn register_sender(&self) {
+        self.inner.senders.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Whether any sender remains registered with the queue
+    pub fn has_senders(&self) -> bool {
+        self.inner.senders.load(Ordering::Acquire) > 0
+    }
+
+    /// Deregister a sender from the queue
+    ///
+    /// If this was the last registered sender the caller is responsible for
+    /// calling notify_not_empty, else a blocked pop_front will never learn it
+    /// has been hung up on. Returns true if this was the last sender.
+    pub fn deregister_sender(&self) -> bool {
+        self.inner.senders.fetch_sub(1, Ordering::AcqRel) == 1
     }
 }
@@ -0,0 +1,251 @@
+//! Many channels sharing one directory and one budget
+//!
+//! Each channel keeps to limits of its own, set by its `Config`. A
+//! `HopperEnv` owns a data directory and a disk and memory budget which every
+//! channel made through it draws from as well: a Sender refuses to page an
+//! item to disk that would take the env over its disk budget, and pages to
+//! disk an item that would take it over its memory budget. No one channel may
+//! take more than a share of either budget, so that a runaway channel can't
+//! starve the rest.
+//!
+//! Disk is counted as for `Config::max_total_disk_bytes`. Memory is counted
+//! as the weight of the items held in memory: their size in bytes, should the
+//! channel account for memory in bytes, and `size_of::<T>()` otherwise.
+use codec::{Bincode, Codec};
+use parking_lot::Mutex;
+use private;
+use receiver::Receiver;
+use sender::Sender;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use stats::{Counters, Stats};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+/// The budget of a `HopperEnv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvConfig {
+    /// The most bytes the queue files of every channel may take up altogether
+    pub max_disk_bytes: usize,
+    /// The most the items held in memory by every channel may weigh
+    /// altogether
+    pub max_memory_bytes: usize,
+    /// The most of either budget, in percent, any one channel may take
+    ///
+    /// This must be from 1 to 100. A channel's `Config::max_total_disk_bytes` and `max_memory_bytes` are
+    /// lowered to its share, should they be above it.
+    pub max_share_percent: usize,
+}
+
+impl Default for EnvConfig {
+    fn default() -> EnvConfig {
+        EnvConfig {
+            max_disk_bytes: usize::MAX,
+            max_memory_bytes: usize::MAX,
+            max_share_percent: 50,
+        }
+    }
+}
+
+// What the channels of a HopperEnv hold between them, counted alongside what
+// each holds.
+#[derive(Debug, Default)]
+pub struct Budget {
+    pub max_disk_bytes: usize,
+    pub max_memory_bytes: usize,
+    pub disk_bytes: AtomicUsize,
+    pub memory_bytes: AtomicUsize,
+}
+
+// A look at a channel's in-memory deque, whatever its items
+trait Gauge: fmt::Debug + Send + Sync {
+    fn size(&self) -> usize;
+    fn capacity(&self) -> usize;
+}
+
+impl<T> Gauge for private::Queue<T> {
+    fn size(&self) -> usize {
+        private::Queue::size(self)
+    }
+
+    fn capacity(&self) -> usize {
+        private::Queue::capacity(self)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    counters: Weak<Counters>, // gone with the channel
+    gauge: Box<dyn Gauge>,
+}
+
+/// A data directory and budget shared by many channels
+///
+/// Channels are made through the env by name, one of each name at a time.
+/// Their queue files are stored in the env's data directory.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let config = hopper::EnvConfig {
+///     max_disk_bytes: 64 * 1024 * 1024,
+///     ..hopper::EnvConfig::default()
+/// };
+/// let env = hopper::HopperEnv::new(dir.path(), config).unwrap();
+/// let (mut snd, mut rcv) = env.channel::<u64>("example").unwrap();
+///
+/// snd.send(9);
+/// assert_eq!(Some(9), rcv.iter().next());
+/// assert_eq!(1, env.usage()["example"].received);
+/// ```
+#[derive(Debug)]
+pub struct HopperEnv {
+    data_dir: PathBuf,
+    config: EnvConfig,
+    budget: Arc<Budget>,
+    channels: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl HopperEnv {
+    /// Create an env storing its channels in `data_dir`, which is created
+    /// should it not exist
+    ///
+    /// A share outside of 1 to 100 percent is refused with
+    /// `ConfigError::SharePercent`.
+    pub fn new(data_dir: &Path, config: EnvConfig) -> Result<HopperEnv, super::Error> {
+        if !(1..=100).contains(&config.max_share_percent) {
+            return Err(super::Error::Config(super::ConfigError::SharePercent));
+        }
+        fs::create_dir_all(data_dir).map_err(super::Error::IoError)?;
+        Ok(HopperEnv {
+            data_dir: data_dir.to_path_buf(),
+            config,
+            budget: Arc::new(Budget {
+                max_disk_bytes: config.max_disk_bytes,
+                max_memory_bytes: config.max_memory_bytes,
+                ..Budget::default()
+            }),
+            channels: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Create a (Sender, Receiver) pair in the env with the default `Config`
+    ///
+    /// Errors are as for `channel_with_codec`.
+    pub fn channel<T>(&self, name: &str) -> Result<(Sender<T>, Receiver<T>), super::Error>
+    where
+        T: Serialize + DeserializeOwned + 'static,
+    {
+        self.channel_with_config(name, super::Config::default())
+    }
+
+    /// Create a (Sender, Receiver) pair in the env from a `Config`
+    ///
+    /// Errors are as for `channel_with_codec`.
+    pub fn channel_with_config<T>(
+        &self,
+        name: &str,
+        config: super::Config,
+    ) -> Result<(Sender<T>, Receiver<T>), super::Error>
+    where
+        T: Serialize + DeserializeOwned + 'static,
+    {
+        self.channel_with_codec(name, config, Bincode)
+    }
+
+    /// Create a (Sender, Receiver) pair in the env from a `Config`,
+    /// serializing items paged to disk with `codec`
+    ///
+    /// The channel's limits are lowered to its share of the env's budget.
+    /// `Error::NameInUse` is returned should a channel of the same name be
+    /// open in the env. The name must be a single, ordinary path component,
+    /// so that the channel keeps to a directory of its own in the env's data
+    /// directory, and is otherwise refused with `Error::Config`.
+    #[allow(clippy::type_complexity)]
+    pub fn channel_with_codec<T, C>(
+        &self,
+        name: &str,
+        mut config: super::Config,
+        codec: C,
+    ) -> Result<(Sender<T, C>, Receiver<T, C>), super::Error>
+    where
+        T: 'static,
+        C: Codec<T>,
+    {
+        if !private::name_is_plain(name) {
+            return Err(super::Error::Config(super::ConfigError::InvalidName));
+        }
+        let mut channels = self.channels.lock();
+        prune(&mut channels);
+        if channels.contains_key(name) {
+            return Err(super::Error::NameInUse);
+        }
+        let percent = self.config.max_share_percent;
+        let share = |budget: usize| {
+            (budget / 100)
+                .saturating_mul(percent)
+                .saturating_add(budget % 100 * percent / 100)
+        };
+        config.max_total_disk_bytes = ::std::cmp::min(
+            config.max_total_disk_bytes,
+            share(self.config.max_disk_bytes),
+        );
+        config.max_memory_bytes =
+            ::std::cmp::min(config.max_memory_bytes, share(self.config.max_memory_bytes));
+        let counters = Counters::with_budget(Arc::clone(&self.budget));
+        let (sender, receiver) =
+            super::open_channel(name, &self.data_dir, config, codec, counters)?;
+        let (mem_buffer, counters) = receiver.shared_parts();
+        channels.insert(
+            name.to_string(),
+            Entry {
+                counters: Arc::downgrade(&counters),
+                gauge: Box::new(mem_buffer),
+            },
+        );
+        Ok((sender, receiver))
+    }
+
+    /// Return a snapshot of the counters of every open channel, by name
+    ///
+    /// A channel is open until its Senders and Receiver are all dropped.
+    pub fn usage(&self) -> BTreeMap<String, Stats> {
+        let mut channels = self.channels.lock();
+        prune(&mut channels);
+        channels
+            .iter()
+            .filter_map(|(name, entry)| {
+                let counters = entry.counters.upgrade()?;
+                let stats = counters.snapshot_of(entry.gauge.size(), entry.gauge.capacity());
+                Some((name.clone(), stats))
+            })
+            .collect()
+    }
+
+    /// The bytes the queue files of every open channel take up altogether
+    pub fn disk_bytes(&self) -> usize {
+        self.budget.disk_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The weight of the items every open channel holds in memory
+    pub fn memory_bytes(&self) -> usize {
+        self.budget.memory_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The env's data directory
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+}
+
+// Forget the channels that have been dropped.
+fn prune(channels: &mut BTreeMap<String, Entry>) {
+    channels.retain(|_, entry| entry.counters.strong_count() > 0);
+}
@@ -0,0 +1,123 @@
+//! Weighing items by the memory they take up
+//!
+//! A channel that accounts for memory in bytes weighs every item it holds in
+//! memory with its codec. By default an item weighs as much as its encoding,
+//! which costs a serialization to find out. Items that know their own size
+//! can implement `HeapSize` instead and be sent through a channel whose codec
+//! is wrapped in `HeapSized`.
+use codec::Codec;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+/// Reports the bytes a value has allocated on the heap
+///
+/// The bytes a value takes up inline, `size_of::<T>()`, are not counted.
+pub trait HeapSize {
+    /// The bytes allocated on the heap by this value and those it owns
+    fn heap_size(&self) -> usize;
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                fn heap_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+no_heap!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T> HeapSize for Vec<T>
+where
+    T: HeapSize,
+{
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T> HeapSize for VecDeque<T>
+where
+    T: HeapSize,
+{
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T> HeapSize for Box<T>
+where
+    T: HeapSize,
+{
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T> HeapSize for Option<T>
+where
+    T: HeapSize,
+{
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+/// A codec weighing items by their `HeapSize`
+///
+/// Items are encoded and decoded by the wrapped codec. An item weighs its
+/// inline size plus whatever it has allocated on the heap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapSized<C>(pub C);
+
+impl<T, C> Codec<T> for HeapSized<C>
+where
+    T: HeapSize,
+    C: Codec<T>,
+{
+    fn encode<W>(&self, value: &T, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        self.0.encode(value, writer)
+    }
+
+    fn decode<R>(&self, reader: &mut R) -> io::Result<T>
+    where
+        R: Read,
+    {
+        self.0.decode(reader)
+    }
+
+    fn weigh(&self, value: &T) -> usize {
+        size_of::<T>() + value.heap_size()
+    }
+}
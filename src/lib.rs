@@ -17,8 +17,10 @@
 //!
 //! Hopper is intended to be used in situtations where your system cannot
 //! load-shed inputs and _must_ eventually process them. Hopper does page to
-//! disk but has the same durabilty guarantees as stdlib mpsc between restarts:
-//! none.
+//! disk but, by default, has the same durabilty guarantees as stdlib mpsc
+//! between restarts: none. A channel created with `channel_durable` will pick
+//! up the queue files left behind by a previous process and deliver what was
+//! paged to disk before anything new.
 //!
 //! # Inside Baseball
 //!
@@ -46,7 +48,12 @@
 //! responsible for _creating_ "queue files". In the above,
 //! `data-dir/sink-name*/*` are queue files. These files are treated as
 //! append-only logs by the Senders. The Receivers trawl through these logs to
-//! read the data serialized there.
+//! read the data serialized there. Every queue file opens with a versioned
+//! header recording how its items were compressed -- see `Compression` --
+//! whether they were gathered into blocks and whether they carry the time
+//! they were sent, so that the Receiver knows how to read them back. Every
+//! record carries a CRC32 of its contents. See `CorruptionPolicy` for what the
+//! Receiver does when one doesn't match.
 //!
 //! ## Won't this fill up my disk?
 //!
@@ -73,21 +80,50 @@
 extern crate bincode;
 extern crate byteorder;
 extern crate flate2;
+#[cfg(feature = "futures")]
+extern crate futures;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
 extern crate parking_lot;
 extern crate serde;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
+mod ack;
+#[cfg(feature = "futures")]
+pub mod asynchronous;
+pub mod broadcast;
+mod builder;
+mod codec;
+mod compression;
 mod deque;
+mod env;
+mod heap;
+pub mod priority;
 mod private;
 mod receiver;
+mod select;
 mod sender;
+mod shared;
+mod stats;
 
-pub use self::receiver::Receiver;
+pub use self::ack::{AckReceiver, Delivery};
+pub use self::builder::ChannelBuilder;
+pub use self::codec::{Bincode, Codec, Passthrough};
+pub use self::compression::{Block, Compression};
+pub use self::env::{EnvConfig, HopperEnv};
+pub use self::heap::{HeapSize, HeapSized};
+pub use self::receiver::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+pub use self::select::Select;
 pub use self::sender::Sender;
+pub use self::shared::SharedReceiver;
+pub use self::stats::Stats;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::path::Path;
 use std::sync::atomic::AtomicUsize;
-use std::{fs, io, mem, sync};
+use std::time::{Duration, Instant};
+use std::{fs, io, sync};
 
 /// Defines the errors that hopper will bubble up
 ///
@@ -108,6 +144,276 @@ pub enum Error {
     /// Could not write element because there is no remaining memory or disk
     /// space
     Full,
+    /// A queue file was found corrupt and the items in the corrupt part of it
+    /// were lost. See `CorruptionPolicy`.
+    Corrupt,
+    /// An item read back from disk was intact but the codec could not decode
+    /// it. The item was lost. See `CorruptionPolicy`.
+    Deserialize(io::Error),
+    /// A channel of the same name is already open in the `HopperEnv`
+    NameInUse,
+    /// A `ChannelBuilder`, or a `Config`, was given options that make no
+    /// sense
+    Config(ConfigError),
+    /// An item was sent through an `asynchronous::Sender` once it had been
+    /// closed
+    Closed,
+}
+
+/// What a `ChannelBuilder` found wrong with its options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The name would take the channel directory out of the data directory
+    /// or, for a `ChannelBuilder` or `HopperEnv`, is not a single, ordinary
+    /// path component
+    InvalidName,
+    /// The data directory, or the channel directory in it, is there but is
+    /// not a directory
+    NotADirectory,
+    /// The memory budget is zero
+    ZeroMemoryBudget,
+    /// The maximum size of a queue file is zero
+    ZeroFileSize,
+    /// The maximum number of queue files is zero
+    ZeroDiskFiles,
+    /// The most bytes the queue files may take up altogether is zero
+    ZeroDiskQuota,
+    /// The compression level is out of range, as a Deflate level over 9 is
+    CompressionLevel,
+    /// A priority channel was asked for with no lanes
+    NoLanes,
+    /// The channel directory is in use by another kind of channel, as that
+    /// of a priority channel of the same name is
+    DirectoryInUse,
+    /// A `HopperEnv`'s share of its budgets for any one channel is not from 1
+    /// to 100 percent
+    SharePercent,
+}
+
+/// What a Receiver does on finding a queue file corrupt
+///
+/// Every record in a queue file carries a CRC32 of its contents. A record
+/// whose checksum does not match, or which cannot be decompressed or
+/// decoded, is corrupt. So is one cut short, as happens when a write fails
+/// part way through on a full disk. The items in a corrupt record are lost
+/// whatever the policy. The policy decides what else is lost and whether the
+/// loss is reported.
+///
+/// A record cut short, or a queue file whose header can't be made sense of,
+/// leaves no way to find the records after it and the rest of the queue file
+/// is always skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Skip the corrupt record and carry on with the next
+    #[default]
+    SkipRecord,
+    /// Skip the rest of the queue file holding the corrupt record
+    SkipFile,
+    /// Skip the corrupt record, as `SkipRecord`, but fail the read that found
+    /// it with `Error::Corrupt` or, should the record be intact but its item
+    /// not decode, `Error::Deserialize`
+    Error,
+}
+
+/// What a Sender does with an item there's no room for
+///
+/// A channel is full once its queue files take up `max_disk_files` or
+/// `max_total_disk_bytes`; a full in-memory deque merely sends items to disk.
+/// The policy applies to `Sender::send` and `Sender::send_all`.
+/// `send_blocking` and `send_timeout` always wait for room, as does an
+/// `asynchronous::Sender` -- on its writer thread, not its task. Broadcast
+/// channels ignore the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Refuse the item with `Error::Full`, handing it back
+    #[default]
+    Error,
+    /// Drop the item, the send succeeding all the same
+    ///
+    /// The item is counted in `Stats::shed`.
+    DropNewest,
+    /// Make room by removing the oldest queue file the Receiver has yet to
+    /// start reading, losing its items
+    ///
+    /// The items lost are counted in `Stats::dropped`. Should there be no
+    /// such file -- the Receiver reading the only queue file the Senders are
+    /// done with, or nothing on disk at all -- the oldest item held in the
+    /// in-memory deque is dropped instead, so long as no item is waiting on
+    /// disk ahead of the new one. Failing that the item is dropped, as
+    /// `DropNewest`.
+    DropOldest,
+    /// Wait for room, as `Sender::send_blocking`
+    Block,
+}
+
+/// When a Sender has the OS write a queue file through to disk
+///
+/// Records the OS has yet to write through are lost should the machine --
+/// rather than the process -- go down. Broadcast channels ignore the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Never, leaving it to the OS
+    #[default]
+    Never,
+    /// Whenever the queue file is flushed: on `Sender::flush`, on announcing
+    /// items paged to disk to the Receiver, on moving on to a new queue file
+    /// and when the last Sender hangs up
+    OnFlush,
+    /// After every record written
+    Always,
+}
+
+/// How a channel counts the items it holds in memory against
+/// `Config::max_memory_bytes`
+///
+/// The in-memory deque is allocated up front with room for
+/// `max_memory_bytes / size_of::<T>()` items. That bounds the memory taken up
+/// by items whose bytes are all inline but says nothing of the memory a
+/// `String` or `Vec<u8>` has allocated on the heap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemoryAccounting {
+    /// Count items by the slots they take up in the in-memory deque
+    #[default]
+    Slots,
+    /// Count items by their weight, as reckoned by the channel's codec
+    ///
+    /// The items held in memory weigh no more than `max_memory_bytes` between
+    /// them, on top of the slots of the in-memory deque. An item that would
+    /// take them over is paged to disk. See `Codec::weigh` and `HeapSized`.
+    Bytes,
+}
+
+/// When a durable channel's Receiver records its position in the queue files
+///
+/// The position -- a queue file and an offset into it -- is written to a
+/// checkpoint file in the channel directory, replaced atomically each time.
+/// A Receiver recovered after a restart picks up from the last position
+/// recorded, so items read since are delivered again. A position is always
+/// recorded when the Receiver moves on to a new queue file, whatever the
+/// cadence. The default is to record the position after every item read from
+/// disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Record the position once this many items have been read from disk
+    /// since it was last recorded, if set
+    pub every_items: Option<usize>,
+    /// Record the position on reading an item from disk this long after it
+    /// was last recorded, if set
+    pub every: Option<Duration>,
+    /// Record the position when the Receiver is dropped
+    pub on_drop: bool,
+}
+
+impl Default for Checkpoint {
+    fn default() -> Checkpoint {
+        Checkpoint {
+            every_items: Some(1),
+            every: None,
+            on_drop: true,
+        }
+    }
+}
+
+impl Checkpoint {
+    // Whether a position is due to be recorded, `unrecorded` items having
+    // been read since the last was at `recorded_at`
+    fn due(&self, unrecorded: usize, recorded_at: Instant) -> bool {
+        self.every_items.is_some_and(|n| unrecorded >= n)
+            || self.every.is_some_and(|t| recorded_at.elapsed() >= t)
+    }
+}
+
+/// Configuration for a hopper channel
+///
+/// The defaults are those used by `channel`. See
+/// `channel_with_explicit_capacity` for the meaning of the capacity fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// The maximum number of bytes that will be stored in-memory
+    pub max_memory_bytes: usize,
+    /// The maximum size of a single queue file
+    pub max_disk_bytes: usize,
+    /// The total number of concurrent queue files which are allowed to exist
+    pub max_disk_files: usize,
+    /// The most bytes the channel's queue files may take up altogether
+    ///
+    /// Bytes are counted as records are written and queue files removed, and
+    /// a Sender refuses to write a record that would take the channel over
+    /// this quota. Queue files are then kept to no more than half the quota,
+    /// whatever `max_disk_bytes` says, so that the Receiver is able to make
+    /// room.
+    pub max_total_disk_bytes: usize,
+    /// Whether the channel survives a restart of the process
+    ///
+    /// A non-durable channel clears out its directory when created. A durable
+    /// channel instead replays the queue files it finds there, starting from
+    /// the position its last Receiver recorded in the channel directory. Only
+    /// items that were paged to disk survive a restart; those held in memory
+    /// are lost with the process, same as ever.
+    pub durable: bool,
+    /// How often a durable channel's Receiver records its position
+    pub checkpoint: Checkpoint,
+    /// The compression applied to items paged to disk
+    ///
+    /// Every queue file records the compression it was written with, so a
+    /// durable channel may change this between restarts.
+    pub compression: Compression,
+    /// Whether items paged to disk are compressed in blocks rather than one
+    /// by one
+    ///
+    /// Blocks make for much smaller queue files when items are small and
+    /// alike, at the cost of the Receiver only learning of items paged to
+    /// disk once their block is written out. See `Block`.
+    pub block: Option<Block>,
+    /// What the Receiver does on finding a queue file corrupt
+    pub corruption: CorruptionPolicy,
+    /// What a Sender does with an item there's no room for
+    pub overflow: OverflowPolicy,
+    /// When a Sender has the OS write a queue file through to disk
+    pub fsync: FsyncPolicy,
+    /// How long an item may wait to be received, if limited
+    ///
+    /// Items are stamped with the time they were sent, and the Receiver
+    /// passes over -- and counts as expired -- those older than this. A queue
+    /// file whose newest item has expired is removed unread.
+    pub ttl: Option<Duration>,
+    /// How the items held in memory are counted against `max_memory_bytes`
+    pub memory_accounting: MemoryAccounting,
+}
+
+impl Config {
+    // Check the options make sense, whichever way the channel is made. A
+    // queue file limit under 1Mb is raised to 1Mb, so it's left to
+    // ChannelBuilder to refuse one of zero.
+    pub(crate) fn check(&self) -> Result<(), ConfigError> {
+        if self.max_memory_bytes == 0 {
+            return Err(ConfigError::ZeroMemoryBudget);
+        }
+        if !self.compression.is_valid() {
+            return Err(ConfigError::CompressionLevel);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_memory_bytes: 0x100_000,
+            max_disk_bytes: 0x10_000_000,
+            max_disk_files: usize::MAX,
+            max_total_disk_bytes: usize::MAX,
+            durable: false,
+            checkpoint: Checkpoint::default(),
+            compression: Compression::default(),
+            block: None,
+            corruption: CorruptionPolicy::default(),
+            overflow: OverflowPolicy::default(),
+            fsync: FsyncPolicy::default(),
+            ttl: None,
+            memory_accounting: MemoryAccounting::default(),
+        }
+    }
 }
 
 /// Create a (Sender, Reciever) pair in a like fashion to
@@ -133,7 +439,39 @@ pub fn channel<T>(name: &str, data_dir: &Path) -> Result<(Sender<T>, Receiver<T>
 where
     T: Serialize + DeserializeOwned,
 {
-    channel_with_explicit_capacity(name, data_dir, 0x100_000, 0x10_000_000, usize::max_value())
+    channel_with_config(name, data_dir, Config::default())
+}
+
+/// Create or re-open a durable (Sender, Reciever) pair
+///
+/// This function behaves like `channel` except that queue files left in
+/// `data_dir` by a previous process are not discarded. The Receiver will first
+/// deliver every item paged to disk which was not received before the restart
+/// and only then move on to new items. See `Config::durable` for details.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd, mut rcv) = hopper::channel_durable("example", dir.path()).unwrap();
+///
+/// snd.send(9);
+/// assert_eq!(Some(9), rcv.iter().next());
+/// ```
+pub fn channel_durable<T>(name: &str, data_dir: &Path) -> Result<(Sender<T>, Receiver<T>), Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    channel_with_config(
+        name,
+        data_dir,
+        Config {
+            durable: true,
+            ..Config::default()
+        },
+    )
 }
 
 /// Create a (Sender, Reciever) pair in a like fashion to
@@ -144,8 +482,10 @@ where
 /// be stored in-memory are `max(max_memory_bytes, size_of(T))` and the maximum
 /// size of a queue file will be `max(max_disk_bytes, 1Mb)`. `max_disk_files`
 /// sets the total number of concurrent queue files which are allowed to
-/// exist. The total on-disk consumption of hopper will then be
-/// `max(max_memory_bytes, size_of(T)) * max_disk_files`.
+/// exist. A queue file is only moved on from once a record takes it over its
+/// limit, so the total on-disk consumption of hopper will then be a little
+/// over `max(max_disk_bytes, 1Mb) * max_disk_files` -- by up to one record per
+/// file. Set `Config::max_total_disk_bytes` for a hard limit.
 ///
 /// The Sender is clonable.
 pub fn channel_with_explicit_capacity<T>(
@@ -158,6 +498,89 @@ pub fn channel_with_explicit_capacity<T>(
 where
     T: Serialize + DeserializeOwned,
 {
+    channel_with_config(
+        name,
+        data_dir,
+        Config {
+            max_memory_bytes: ::std::cmp::max(1, max_memory_bytes),
+            max_disk_bytes: ::std::cmp::max(0x100_000, max_disk_bytes),
+            max_disk_files,
+            ..Config::default()
+        },
+    )
+}
+
+/// Create a (Sender, Reciever) pair from a `Config`
+///
+/// `channel`, `channel_durable` and `channel_with_explicit_capacity` all defer
+/// to this function. Items paged to disk are serialized with `Bincode`.
+///
+/// A config that makes no sense, or a name that would take the channel
+/// directory out of `data_dir`, is refused with `Error::Config`. See
+/// `ChannelBuilder` for a stricter check of the options.
+pub fn channel_with_config<T>(
+    name: &str,
+    data_dir: &Path,
+    config: Config,
+) -> Result<(Sender<T>, Receiver<T>), Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    channel_with_codec(name, data_dir, config, Bincode)
+}
+
+/// Create a (Sender, Reciever) pair from a `Config`, serializing items paged
+/// to disk with `codec`
+///
+/// This is the most general way to create a channel. The items of the
+/// channel need only be encodable by `codec`.
+///
+/// # Example
+/// ```
+/// extern crate tempdir;
+/// extern crate hopper;
+///
+/// let dir = tempdir::TempDir::new("hopper").unwrap();
+/// let (mut snd, mut rcv) = hopper::channel_with_codec(
+///     "example",
+///     dir.path(),
+///     hopper::Config::default(),
+///     hopper::Passthrough,
+/// ).unwrap();
+///
+/// snd.send(b"already encoded".to_vec());
+/// assert_eq!(Some(b"already encoded".to_vec()), rcv.iter().next());
+/// ```
+#[allow(clippy::type_complexity)]
+pub fn channel_with_codec<T, C>(
+    name: &str,
+    data_dir: &Path,
+    config: Config,
+    codec: C,
+) -> Result<(Sender<T, C>, Receiver<T, C>), Error>
+where
+    C: Codec<T>,
+{
+    open_channel(name, data_dir, config, codec, stats::Counters::default())
+}
+
+// Create a (Sender, Receiver) pair counting into `counters`, which a channel
+// of a HopperEnv shares the env's budget through.
+#[allow(clippy::type_complexity)]
+fn open_channel<T, C>(
+    name: &str,
+    data_dir: &Path,
+    config: Config,
+    codec: C,
+    counters: stats::Counters,
+) -> Result<(Sender<T, C>, Receiver<T, C>), Error>
+where
+    C: Codec<T>,
+{
+    if !private::name_within(name) {
+        return Err(Error::Config(ConfigError::InvalidName));
+    }
+    config.check().map_err(Error::Config)?;
     let root = data_dir.join(name);
     if !root.is_dir() {
         match fs::create_dir_all(root.clone()) {
@@ -167,22 +590,72 @@ where
             }
         }
     }
-    let sz = mem::size_of::<T>();
-    let max_disk_bytes = ::std::cmp::max(0x100_000, max_disk_bytes);
-    let total_memory_limit: usize = ::std::cmp::max(1, max_memory_bytes / sz);
-    let q: private::Queue<T> = deque::Queue::with_capacity(total_memory_limit);
-    if let Err(e) = private::clear_directory(&root) {
-        return Err(Error::IoError(e));
+    match private::queue_file_taken(&root) {
+        Ok(false) => {}
+        Ok(true) => return Err(Error::Config(ConfigError::DirectoryInUse)),
+        Err(e) => return Err(Error::IoError(e)),
     }
+    let sz = size_of::<T>();
+    let max_disk_bytes =
+        private::queue_file_limit(config.max_disk_bytes, config.max_total_disk_bytes);
+    let total_memory_limit: usize = ::std::cmp::max(1, config.max_memory_bytes / sz);
+    let q: private::Queue<T> = deque::Queue::with_capacity(total_memory_limit);
+    let mut max_disk_files = config.max_disk_files;
+    let recovered = if config.durable {
+        match private::recover_directory(&root) {
+            Ok((position, leftover_files)) => {
+                max_disk_files = max_disk_files.saturating_sub(leftover_files.len());
+                q.lock_back().inner.file_items.extend(leftover_files);
+                Some(position)
+            }
+            Err(e) => return Err(Error::IoError(e)),
+        }
+    } else {
+        if let Err(e) = private::clear_directory(&root) {
+            return Err(Error::IoError(e));
+        }
+        None
+    };
     let max_disk_files = sync::Arc::new(AtomicUsize::new(max_disk_files));
+    let counters = sync::Arc::new(counters);
+    match private::disk_usage(&root) {
+        Ok(bytes) => counters.add_disk_bytes(bytes),
+        Err(e) => return Err(Error::IoError(e)),
+    }
+    match private::queue_files(&root) {
+        Ok(seq_nums) => counters.add_disk_files(seq_nums.len()),
+        Err(e) => return Err(Error::IoError(e)),
+    }
     let sender = Sender::new(
         name,
         &root,
         max_disk_bytes,
+        config.max_total_disk_bytes,
         q.clone(),
         sync::Arc::clone(&max_disk_files),
+        codec.clone(),
+        config.compression,
+        config.block,
+        config.overflow,
+        config.fsync,
+        config.ttl,
+        match config.memory_accounting {
+            MemoryAccounting::Slots => None,
+            MemoryAccounting::Bytes => Some(config.max_memory_bytes),
+        },
+        sync::Arc::clone(&counters),
+    )?;
+    let receiver = Receiver::new(
+        &root,
+        q,
+        sync::Arc::clone(&max_disk_files),
+        recovered,
+        codec,
+        config.corruption,
+        config.checkpoint,
+        config.ttl,
+        counters,
     )?;
-    let receiver = Receiver::new(&root, q, sync::Arc::clone(&max_disk_files))?;
     Ok((sender, receiver))
 }
 
@@ -192,8 +665,16 @@ mod test {
     extern crate tempdir;
 
     use self::quickcheck::{QuickCheck, TestResult};
-    use super::channel_with_explicit_capacity;
-    use std::{mem, thread};
+    use super::{
+        broadcast, channel, channel_with_codec, channel_with_config,
+        channel_with_explicit_capacity, priority, Bincode, Block, ChannelBuilder, Checkpoint,
+        Codec, Compression, Config, ConfigError, CorruptionPolicy, EnvConfig, Error, FsyncPolicy,
+        HeapSized, HopperEnv, MemoryAccounting, OverflowPolicy, Passthrough, Receiver, RecvError,
+        RecvTimeoutError, Select, Sender, SharedReceiver, Stats, TryRecvError,
+    };
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::time::{Duration, Instant};
+    use std::{fs, thread};
 
     #[test]
     fn ingress_shedding() {
@@ -208,7 +689,7 @@ mod test {
                 let total_elems = 5 * 131082;
                 // Magic constant, depends on compression level and what
                 // not. May need to do a looser assertion.
-                let expected_shed_sends = 383981;
+                let expected_shed_sends = 441311;
                 let mut shed_sends = 0;
                 let mut sent_values = Vec::new();
                 for i in 0..total_elems {
@@ -221,7 +702,7 @@ mod test {
                             Err((r, err)) => {
                                 assert_eq!(r, i);
                                 match err {
-                                    super::Error::Full => {
+                                    Error::Full => {
                                         shed_sends += 1;
                                         break;
                                     }
@@ -279,6 +760,2050 @@ mod test {
         }
     }
 
+    fn durable_restart_exp(total_elems: usize, received_before_restart: usize) -> bool {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            max_disk_bytes: 32,
+            durable: true,
+            ..Config::default()
+        };
+        {
+            let (mut snd, mut rcv) = channel_with_config("durable", dir.path(), config).unwrap();
+            for i in 0..total_elems {
+                assert!(snd.send(i).is_ok());
+            }
+            // The first element sits in memory, the rest on disk. Pull the
+            // in-memory element to make room for the flush.
+            assert_eq!(Some(0), rcv.iter().next());
+            assert!(snd.flush().is_ok());
+            for i in 1..received_before_restart {
+                assert_eq!(Some(i), rcv.iter().next());
+            }
+        }
+        let (mut snd, mut rcv) = channel_with_config("durable", dir.path(), config).unwrap();
+        assert!(snd.send(total_elems).is_ok());
+        for i in received_before_restart..(total_elems + 1) {
+            assert_eq!(Some(i), rcv.iter().next());
+        }
+        true
+    }
+
+    #[test]
+    fn durable_restart() {
+        fn inner(total_elems: usize, received_before_restart: usize) -> TestResult {
+            if !(2..=2_000).contains(&total_elems) {
+                return TestResult::discard();
+            }
+            let received_before_restart = 1 + (received_before_restart % total_elems);
+            TestResult::from_bool(durable_restart_exp(total_elems, received_before_restart))
+        }
+        QuickCheck::new().quickcheck(inner as fn(usize, usize) -> TestResult);
+    }
+
+    // Send 0..100 through a durable channel, reading `received` of them
+    // before the Receiver goes away -- dropped or, should `crash` be set,
+    // leaked as though the process died. The first item read after a
+    // restart is returned.
+    fn checkpoint_exp(checkpoint: Checkpoint, received: u64, crash: bool) -> Option<u64> {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            durable: true,
+            checkpoint,
+            ..Config::default()
+        };
+        {
+            let (mut snd, mut rcv) =
+                channel_with_config::<u64>("checkpoint", dir.path(), config).unwrap();
+            for i in 0..100 {
+                assert!(snd.send(i).is_ok());
+            }
+            assert_eq!(Some(0), rcv.iter().next());
+            assert!(snd.flush().is_ok());
+            for i in 1..received {
+                assert_eq!(Some(i), rcv.iter().next());
+            }
+            if crash {
+                ::std::mem::forget(rcv);
+            }
+        }
+        let (_snd, mut rcv) = channel_with_config::<u64>("checkpoint", dir.path(), config).unwrap();
+        rcv.iter().next()
+    }
+
+    #[test]
+    fn checkpoint_cadence() {
+        let every_ten = Checkpoint {
+            every_items: Some(10),
+            every: None,
+            on_drop: false,
+        };
+        // Items 1 through 24 were read from disk, the position last recorded
+        // after the 20th of them.
+        assert_eq!(Some(21), checkpoint_exp(every_ten, 25, true));
+        assert_eq!(Some(21), checkpoint_exp(every_ten, 25, false));
+        let on_drop = Checkpoint {
+            on_drop: true,
+            ..every_ten
+        };
+        assert_eq!(Some(21), checkpoint_exp(on_drop, 25, true));
+        assert_eq!(Some(25), checkpoint_exp(on_drop, 25, false));
+        assert_eq!(Some(25), checkpoint_exp(Checkpoint::default(), 25, true));
+    }
+
+    #[test]
+    fn short_cursor_read_as_none() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            durable: true,
+            ..Config::default()
+        };
+        {
+            let (mut snd, mut rcv) =
+                channel_with_config::<u64>("cursor", dir.path(), config).unwrap();
+            for i in 0..100 {
+                assert!(snd.send(i).is_ok());
+            }
+            assert_eq!(Some(0), rcv.iter().next());
+            assert!(snd.flush().is_ok());
+            for i in 1..25 {
+                assert_eq!(Some(i), rcv.iter().next());
+            }
+        }
+        // A crash part way through writing the cursor leaves it cut short.
+        let cursor = dir.path().join("cursor").join(super::private::CURSOR_FILE);
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&cursor)
+            .unwrap()
+            .set_len(10)
+            .unwrap();
+        // The position is lost, so the items on disk are read over again.
+        let (_snd, mut rcv) = channel_with_config::<u64>("cursor", dir.path(), config).unwrap();
+        assert_eq!(Some(1), rcv.iter().next());
+    }
+
+    #[test]
+    fn recovery_removes_leftover_replacements() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            durable: true,
+            ..Config::default()
+        };
+        {
+            let (mut snd, mut rcv) =
+                channel_with_config::<u64>("leftover", dir.path(), config).unwrap();
+            for i in 0..100 {
+                assert!(snd.send(i).is_ok());
+            }
+            assert_eq!(Some(0), rcv.iter().next());
+        }
+        // A crash before the replacements were renamed into place.
+        let root = dir.path().join("leftover");
+        let evicted = root.join(super::private::EVICTED_REPLACEMENT_FILE);
+        let cursor = root.join(super::private::CURSOR_REPLACEMENT_FILE);
+        for path in &[&evicted, &cursor] {
+            let fp = fs::File::create(path).unwrap();
+            let mut permissions = fp.metadata().unwrap().permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(path, permissions).unwrap();
+        }
+        let (_snd, mut rcv) =
+            channel_with_config::<u64>("leftover", dir.path(), config).unwrap();
+        assert!(!evicted.exists());
+        assert!(!cursor.exists());
+        assert_eq!(Some(1), rcv.iter().next());
+    }
+
+    #[test]
+    fn non_durable_clears_directory() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            ..Config::default()
+        };
+        {
+            let (mut snd, _rcv) = channel_with_config("clear", dir.path(), config).unwrap();
+            for i in 0..100 {
+                assert!(snd.send(i).is_ok());
+            }
+        }
+        let (mut snd, mut rcv) = channel_with_config("clear", dir.path(), config).unwrap();
+        assert!(snd.send(100).is_ok());
+        assert_eq!(Some(100), rcv.iter().next());
+    }
+
+    #[test]
+    fn hang_up_after_drain() {
+        fn inner(in_memory_bytes: usize, total_senders: usize, total_elems: usize) -> TestResult {
+            if (in_memory_bytes / size_of::<u64>()) == 0 || total_senders == 0 {
+                return TestResult::discard();
+            }
+            let total_senders = 1 + (total_senders % 10);
+            let total_elems = total_elems % 1_000;
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: in_memory_bytes,
+                ..Config::default()
+            };
+            let (snd, rcv) = channel_with_config("hang_up", dir.path(), config).unwrap();
+            let mut snd_jh = Vec::new();
+            for _ in 0..total_senders {
+                let mut thr_snd = snd.clone();
+                snd_jh.push(thread::spawn(move || {
+                    // No flush here: whatever is left stranded on disk must
+                    // still come out the other end.
+                    for i in 0..total_elems {
+                        assert!(thr_snd.send(i).is_ok());
+                    }
+                }));
+            }
+            drop(snd);
+            let rcv_jh = thread::spawn(move || rcv.into_iter().collect::<Vec<usize>>());
+            for jh in snd_jh {
+                jh.join().expect("snd join failed");
+            }
+            let mut received = rcv_jh.join().expect("rcv join failed");
+            let mut expected = Vec::new();
+            for _ in 0..total_senders {
+                expected.extend(0..total_elems);
+            }
+            received.sort();
+            expected.sort();
+            TestResult::from_bool(received == expected)
+        }
+        QuickCheck::new().quickcheck(inner as fn(usize, usize, usize) -> TestResult);
+    }
+
+    #[test]
+    fn try_recv_empty_then_disconnected() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel::<u64>("try_recv", dir.path()).unwrap();
+        assert!(matches!(rcv.try_recv(), Err(TryRecvError::Empty)));
+        assert!(snd.send(1).is_ok());
+        assert_eq!(1, rcv.try_recv().unwrap());
+        assert!(matches!(rcv.try_recv(), Err(TryRecvError::Empty)));
+        assert!(snd.send(2).is_ok());
+        drop(snd);
+        assert_eq!(2, rcv.try_recv().unwrap());
+        assert!(matches!(rcv.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn recv_timeout_wakes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = channel::<u64>("recv_timeout", dir.path()).unwrap();
+
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        assert!(matches!(
+            rcv.recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        assert!(start.elapsed() >= timeout);
+
+        let snd_jh = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            assert!(snd.send(1).is_ok());
+        });
+        assert_eq!(1, rcv.recv_timeout(Duration::from_secs(60)).unwrap());
+        snd_jh.join().expect("snd join failed");
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert!(matches!(
+            rcv.recv_deadline(deadline),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn timeouts_beyond_instant_wait_forever() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = channel::<u64>("max_timeout", dir.path()).unwrap();
+        let shared = SharedReceiver::from(rcv);
+        assert!(snd.send_timeout(1, Duration::MAX).is_ok());
+        assert!(snd.send(2).is_ok());
+        assert_eq!(1, shared.recv_timeout(Duration::MAX).unwrap());
+        assert_eq!(vec![2], shared.recv_batch(10, Duration::MAX).unwrap());
+        drop(snd);
+        assert!(matches!(
+            shared.recv_timeout(Duration::MAX),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn asynchronous_round_trip() {
+        use futures::executor::block_on;
+        use futures::{stream, SinkExt, StreamExt};
+
+        fn inner(in_memory_bytes: usize, total_elems: usize) -> TestResult {
+            if (in_memory_bytes / size_of::<u64>()) == 0 {
+                return TestResult::discard();
+            }
+            let total_elems = (total_elems % 10_000) as u64;
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: in_memory_bytes,
+                ..Config::default()
+            };
+            let (mut snd, rcv) =
+                super::asynchronous::channel_with_config("async", dir.path(), config).unwrap();
+            let snd_jh = thread::spawn(move || {
+                let mut items = stream::iter((0..total_elems).map(Ok));
+                block_on(snd.send_all(&mut items)).unwrap();
+            });
+            let received: Vec<u64> = block_on(rcv.map(|ev| ev.unwrap()).collect());
+            snd_jh.join().expect("snd join failed");
+            TestResult::from_bool(received == (0..total_elems).collect::<Vec<u64>>())
+        }
+        QuickCheck::new().quickcheck(inner as fn(usize, usize) -> TestResult);
+    }
+
+    #[test]
+    fn passthrough_codec_round_trip() {
+        fn inner(payloads: Vec<Vec<u8>>) -> bool {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: 8,
+                ..Config::default()
+            };
+            let (mut snd, rcv) =
+                channel_with_codec("passthrough", dir.path(), config, Passthrough).unwrap();
+            for payload in &payloads {
+                assert!(snd.send(payload.clone()).is_ok());
+            }
+            drop(snd);
+            rcv.into_iter().collect::<Vec<Vec<u8>>>() == payloads
+        }
+        QuickCheck::new().quickcheck(inner as fn(Vec<Vec<u8>>) -> bool);
+    }
+
+    // Not serde serializable, on purpose.
+    #[derive(Debug, PartialEq)]
+    struct Celsius(i16);
+
+    #[derive(Debug, Clone, Copy)]
+    struct CelsiusCodec;
+
+    impl Codec<Celsius> for CelsiusCodec {
+        fn encode<W: Write>(&self, value: &Celsius, writer: &mut W) -> io::Result<()> {
+            writer.write_all(&value.0.to_be_bytes())
+        }
+
+        fn decode<R: Read>(&self, reader: &mut R) -> io::Result<Celsius> {
+            let mut buf = [0; 2];
+            reader.read_exact(&mut buf)?;
+            Ok(Celsius(i16::from_be_bytes(buf)))
+        }
+    }
+
+    #[test]
+    fn custom_codec_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 2,
+            ..Config::default()
+        };
+        let (mut snd, rcv) =
+            channel_with_codec("celsius", dir.path(), config, CelsiusCodec).unwrap();
+        for i in -100..100 {
+            assert!(snd.send(Celsius(i)).is_ok());
+        }
+        drop(snd);
+        let expected: Vec<Celsius> = (-100..100).map(Celsius).collect();
+        assert_eq!(expected, rcv.into_iter().collect::<Vec<Celsius>>());
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        #[allow(unused_mut)]
+        let mut compressions = vec![
+            Compression::None,
+            Compression::Deflate(0),
+            Compression::Deflate(9),
+        ];
+        #[cfg(feature = "zstd")]
+        compressions.push(Compression::Zstd(3));
+        #[cfg(feature = "lz4")]
+        compressions.push(Compression::Lz4);
+        for compression in compressions {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: 8,
+                compression,
+                ..Config::default()
+            };
+            let (mut snd, rcv) = channel_with_config("compression", dir.path(), config).unwrap();
+            for i in 0..1_000u64 {
+                assert!(snd.send(i).is_ok());
+            }
+            drop(snd);
+            assert_eq!(
+                (0..1_000).collect::<Vec<u64>>(),
+                rcv.into_iter().collect::<Vec<u64>>(),
+                "{:?}",
+                compression
+            );
+        }
+    }
+
+    #[test]
+    fn durable_compression_change() {
+        // Queue files left behind are read with the compression they were
+        // written with, not that of the re-opened channel.
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            durable: true,
+            compression: Compression::Deflate(6),
+            ..Config::default()
+        };
+        let (mut snd, rcv) = channel_with_config::<u64>("change", dir.path(), config).unwrap();
+        for i in 0..100 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        drop(rcv);
+
+        let config = Config {
+            compression: Compression::None,
+            ..config
+        };
+        let (mut snd, rcv) = channel_with_config::<u64>("change", dir.path(), config).unwrap();
+        for i in 100..200 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        let received: Vec<u64> = rcv.into_iter().collect();
+        // Only what was paged to disk survives the restart, ahead of
+        // everything sent since.
+        let (before, after) = received.split_at(received.len() - 100);
+        assert!(!before.is_empty());
+        assert!(before.windows(2).all(|w| w[0] < w[1] && w[1] < 100));
+        assert_eq!((100..200).collect::<Vec<u64>>(), after);
+    }
+
+    #[test]
+    fn block_round_trip() {
+        fn inner(total_elems: u16, max_items: u8, recv_every: u8) -> TestResult {
+            if max_items == 0 || recv_every == 0 {
+                return TestResult::discard();
+            }
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: 32,
+                block: Some(Block {
+                    max_items: usize::from(max_items),
+                    max_bytes: 256,
+                }),
+                ..Config::default()
+            };
+            let (mut snd, mut rcv) = channel_with_config("block", dir.path(), config).unwrap();
+            let mut received = Vec::new();
+            for i in 0..u64::from(total_elems) {
+                assert!(snd.send(i).is_ok());
+                if i % u64::from(recv_every) == 0 {
+                    if let Ok(ev) = rcv.try_recv() {
+                        received.push(ev);
+                    }
+                }
+            }
+            drop(snd);
+            received.extend(rcv.iter());
+            TestResult::from_bool(received == (0..u64::from(total_elems)).collect::<Vec<u64>>())
+        }
+        QuickCheck::new().quickcheck(inner as fn(u16, u8, u8) -> TestResult);
+    }
+
+    #[test]
+    fn durable_block_restart() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            durable: true,
+            block: Some(Block {
+                max_items: 16,
+                max_bytes: 4096,
+            }),
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) = channel_with_config::<u64>("block", dir.path(), config).unwrap();
+        for i in 0..100 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        // Stop part way through a block.
+        assert_eq!(
+            (0..10).collect::<Vec<u64>>(),
+            rcv.iter().take(10).collect::<Vec<u64>>()
+        );
+        drop(rcv);
+
+        let (snd, rcv) = channel_with_config::<u64>("block", dir.path(), config).unwrap();
+        drop(snd);
+        assert_eq!(
+            (10..100).collect::<Vec<u64>>(),
+            rcv.into_iter().collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn blocks_shrink_queue_files() {
+        fn queue_bytes(block: Option<Block>) -> u64 {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: 1,
+                block,
+                ..Config::default()
+            };
+            let (mut snd, _rcv) = channel_with_config("logs", dir.path(), config).unwrap();
+            for i in 0..1_000 {
+                let record = format!(
+                    "{{\"level\":\"info\",\"msg\":\"request served\",\"path\":\"/items/{}\"}}",
+                    i
+                );
+                assert!(snd.send(record).is_ok());
+            }
+            drop(snd);
+            fs::read_dir(dir.path().join("logs"))
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum()
+        }
+        let per_item = queue_bytes(None);
+        let blocks = queue_bytes(Some(Block::default()));
+        assert!(blocks * 4 < per_item, "{} vs {}", blocks, per_item);
+    }
+
+    // Page items 1 through 99 to disk behind item 0, held in memory, then have
+    // `mangle` do its worst to the queue file they're in.
+    fn mangled_channel<F>(
+        dir: &tempdir::TempDir,
+        corruption: CorruptionPolicy,
+        mangle: F,
+    ) -> Receiver<u64>
+    where
+        F: FnOnce(&mut fs::File),
+    {
+        let config = Config {
+            max_memory_bytes: 8,
+            corruption,
+            ..Config::default()
+        };
+        let (mut snd, rcv) = channel_with_config("mangled", dir.path(), config).unwrap();
+        for i in 0..100 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        let mut fp = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.path().join("mangled").join("0"))
+            .unwrap();
+        mangle(&mut fp);
+        rcv
+    }
+
+    // Flip a bit in the payload of the first record of the queue file.
+    fn flip_first_payload(fp: &mut fs::File) {
+        // A 7 byte file header, then the record's length and checksum.
+        let mut byte = [0; 1];
+        fp.seek(SeekFrom::Start(7 + 8)).unwrap();
+        fp.read_exact(&mut byte).unwrap();
+        fp.seek(SeekFrom::Start(7 + 8)).unwrap();
+        fp.write_all(&[byte[0] ^ 0x01]).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_skipped() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let rcv = mangled_channel(&dir, CorruptionPolicy::SkipRecord, flip_first_payload);
+        let expected: Vec<u64> = (0..100).filter(|i| *i != 1).collect();
+        assert_eq!(expected, rcv.into_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn corrupt_record_skips_file() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let rcv = mangled_channel(&dir, CorruptionPolicy::SkipFile, flip_first_payload);
+        assert_eq!(vec![0], rcv.into_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn corrupt_record_reported() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let mut rcv = mangled_channel(&dir, CorruptionPolicy::Error, flip_first_payload);
+        // The corrupt record fails a receive, after which the Receiver
+        // carries on past it.
+        assert_eq!(0, rcv.recv().unwrap());
+        match rcv.recv() {
+            Err(RecvError::Disk(Error::Corrupt)) => {}
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        let expected: Vec<u64> = (2..100).collect();
+        assert_eq!(expected, rcv.iter().collect::<Vec<u64>>());
+    }
+
+    // A codec which won't decode temperatures below absolute zero.
+    #[derive(Debug, Clone, Copy)]
+    struct KelvinCodec;
+
+    impl Codec<Celsius> for KelvinCodec {
+        fn encode<W: Write>(&self, value: &Celsius, writer: &mut W) -> io::Result<()> {
+            CelsiusCodec.encode(value, writer)
+        }
+
+        fn decode<R: Read>(&self, reader: &mut R) -> io::Result<Celsius> {
+            let value = CelsiusCodec.decode(reader)?;
+            if value.0 < -273 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "too cold"));
+            }
+            Ok(value)
+        }
+    }
+
+    #[test]
+    fn undecodable_item_reported() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 2,
+            corruption: CorruptionPolicy::Error,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_codec("kelvin", dir.path(), config, KelvinCodec).unwrap();
+        for i in &[0, -300, 1, 2] {
+            assert!(snd.send(Celsius(*i)).is_ok());
+        }
+        drop(snd);
+        assert_eq!(Celsius(0), rcv.recv().unwrap());
+        match rcv.recv() {
+            Err(RecvError::Disk(Error::Deserialize(_))) => {}
+            other => panic!("expected a decode failure, got {:?}", other),
+        }
+        assert_eq!(Celsius(1), rcv.recv().unwrap());
+        assert_eq!(Celsius(2), rcv.recv().unwrap());
+        match rcv.recv() {
+            Err(RecvError::Disconnected) => {}
+            other => panic!("expected disconnection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stats_track_channel() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_disk_bytes: 0,
+            max_disk_files: 0,
+            compression: Compression::None,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_codec("stats", dir.path(), config, Passthrough).unwrap();
+        let empty = Stats {
+            memory_capacity: 1,
+            disk_files: 1,
+            disk_bytes: 7,
+            ..Stats::default()
+        };
+        assert_eq!(empty, snd.stats());
+
+        // One item in memory, one on disk and no room on disk for a third.
+        let item = vec![0; 600_000];
+        assert!(snd.send(item.clone()).is_ok());
+        assert!(snd.send(item.clone()).is_ok());
+        assert!(matches!(snd.send(item), Err((_, Error::Full))));
+        let expected = Stats {
+            memory_items: 1,
+            disk_items: 1,
+            disk_bytes: 7 + 8 + 600_000,
+            sent: 2,
+            shed: 1,
+            ..empty
+        };
+        assert_eq!(expected, rcv.stats());
+
+        drop(snd);
+        assert_eq!(2, rcv.iter().count());
+        let expected = Stats {
+            memory_items: 0,
+            disk_items: 0,
+            received: 2,
+            ..expected
+        };
+        assert_eq!(expected, rcv.stats());
+    }
+
+    #[test]
+    fn disk_quota_in_bytes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_total_disk_bytes: 100_000,
+            compression: Compression::None,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_codec("quota", dir.path(), config, Passthrough).unwrap();
+        // Each record is a length prefix and the item.
+        let item = vec![7; 1_000];
+        let mut sent = 0;
+        loop {
+            match snd.send(item.clone()) {
+                Ok(()) => sent += 1,
+                Err((_, Error::Full)) => break,
+                Err((_, e)) => panic!("unexpected error: {:?}", e),
+            }
+        }
+        let stats = snd.stats();
+        assert!(stats.disk_bytes <= 100_000, "{:?}", stats);
+        assert!(stats.disk_bytes + 8 + 1_000 + 7 > 100_000, "{:?}", stats);
+        assert!(stats.disk_files > 1, "{:?}", stats);
+        let on_disk = super::private::queue_files(&dir.path().join("quota")).unwrap();
+        assert_eq!(stats.disk_files, on_disk.len());
+        let on_disk = super::private::disk_usage(&dir.path().join("quota")).unwrap();
+        assert_eq!(stats.disk_bytes, on_disk);
+
+        // Reading through the queue files makes room for as many again, the
+        // Sender never taking the channel over its quota.
+        let reader = thread::spawn(move || {
+            let mut received = 0;
+            while let Ok(event) = rcv.recv() {
+                assert_eq!(vec![7; 1_000], event);
+                received += 1;
+            }
+            received
+        });
+        for _ in 0..(sent * 3) {
+            assert!(snd.send_blocking(item.clone()).is_ok());
+            assert!(snd.stats().disk_bytes <= 100_000);
+        }
+        drop(snd);
+        assert_eq!(sent * 4, reader.join().unwrap());
+    }
+
+    // A queue file whose header can't be written -- here, one that turns out
+    // to be /dev/full -- leaves the Sender on the file it was writing, and
+    // the Receiver reading on past it once it can be written.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_header_leaves_sender_in_place() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_total_disk_bytes: 4_000,
+            compression: Compression::None,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_codec("header", dir.path(), config, Passthrough).unwrap();
+        let next = dir.path().join("header").join("1");
+        ::std::os::unix::fs::symlink("/dev/full", &next).unwrap();
+        let mut sent: usize = 0;
+        loop {
+            match snd.send(vec![sent as u8; 500]) {
+                Ok(()) => sent += 1,
+                Err((_, Error::IoError(_))) => break,
+                Err((_, e)) => panic!("unexpected error: {:?}", e),
+            }
+        }
+        assert_eq!(0, snd.stats().sender_seq_num);
+        fs::remove_file(&next).unwrap();
+        for _ in 0..3 {
+            assert!(snd.send(vec![sent as u8; 500]).is_ok());
+            sent += 1;
+        }
+        assert_eq!(1, snd.stats().sender_seq_num);
+        drop(snd);
+        for i in 0..sent {
+            match rcv.recv_timeout(Duration::from_secs(5)) {
+                Ok(event) => assert_eq!(vec![i as u8; 500], event),
+                Err(e) => panic!("item {} of {} not received: {:?}", i, sent, e),
+            }
+        }
+        assert!(matches!(
+            rcv.recv_timeout(Duration::from_secs(5)),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    fn overflow_exp(
+        dir: &tempdir::TempDir,
+        name: &str,
+        overflow: OverflowPolicy,
+    ) -> (Sender<Vec<u8>, Passthrough>, Receiver<Vec<u8>, Passthrough>) {
+        // Ten items to a queue file, and room on disk for two.
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_total_disk_bytes: 20_000,
+            max_disk_files: 4,
+            compression: Compression::None,
+            overflow,
+            ..Config::default()
+        };
+        channel_with_codec(name, dir.path(), config, Passthrough).unwrap()
+    }
+
+    fn numbered(i: u64) -> Vec<u8> {
+        let mut item = vec![0; 1_000];
+        item[..8].copy_from_slice(&i.to_be_bytes());
+        item
+    }
+
+    fn number(item: &[u8]) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&item[..8]);
+        u64::from_be_bytes(bytes)
+    }
+
+    #[test]
+    fn overflow_drop_newest() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = overflow_exp(&dir, "newest", OverflowPolicy::DropNewest);
+        for i in 0..100 {
+            assert!(snd.send(numbered(i)).is_ok());
+        }
+        drop(snd);
+        let stats = rcv.stats();
+        let received: Vec<u64> = rcv.into_iter().map(|item| number(&item)).collect();
+        assert!(received.len() < 100);
+        assert_eq!((0..received.len() as u64).collect::<Vec<u64>>(), received);
+        assert_eq!(100, stats.sent + stats.shed);
+        assert_eq!(0, stats.dropped);
+    }
+
+    #[test]
+    fn overflow_drop_oldest() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = overflow_exp(&dir, "oldest", OverflowPolicy::DropOldest);
+        for i in 0..100 {
+            assert!(snd.send(numbered(i)).is_ok());
+        }
+        let stats = rcv.stats();
+        assert!(stats.dropped > 0);
+        let files = fs::read_dir(dir.path().join("oldest"))
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .filter(|metadata| metadata.len() > 0)
+            .count();
+        assert!(files <= 5, "{} queue files", files);
+        assert_eq!(0, number(&rcv.recv().unwrap()));
+        for i in 100..200 {
+            assert!(snd.send(numbered(i)).is_ok());
+        }
+        drop(snd);
+        // The queue file the Receiver was on is kept, as are the newest.
+        assert_eq!(1, number(&rcv.recv().unwrap()));
+        let mut received = vec![0, 1];
+        received.extend(rcv.iter().map(|item| number(&item)));
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(Some(&199), received.last());
+        let stats = rcv.stats();
+        assert_eq!(200, stats.sent);
+        assert_eq!(0, stats.shed);
+        assert_eq!(200, received.len() + stats.dropped);
+        assert_eq!(0, stats.disk_items);
+
+        // With no room on disk at all it's the oldest items in memory that
+        // make way.
+        let config = Config {
+            max_memory_bytes: 4 * size_of::<Vec<u8>>(),
+            max_total_disk_bytes: 1_000,
+            compression: Compression::None,
+            overflow: OverflowPolicy::DropOldest,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_codec("memory", dir.path(), config, Passthrough).unwrap();
+        for i in 0..10 {
+            assert!(snd.send(numbered(i)).is_ok());
+        }
+        drop(snd);
+        let received: Vec<u64> = rcv.iter().map(|item| number(&item)).collect();
+        assert_eq!(vec![6, 7, 8, 9], received);
+        let stats = rcv.stats();
+        assert_eq!(6, stats.dropped);
+        assert_eq!(0, stats.shed);
+    }
+
+    #[test]
+    fn overflow_block() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = overflow_exp(&dir, "block", OverflowPolicy::Block);
+        let sender = thread::spawn(move || {
+            for i in 0..100 {
+                assert!(snd.send(numbered(i)).is_ok());
+            }
+        });
+        let received: Vec<u64> = rcv.into_iter().map(|item| number(&item)).collect();
+        sender.join().unwrap();
+        assert_eq!((0..100).collect::<Vec<u64>>(), received);
+    }
+
+    fn ttl_exp(block: Option<Block>) {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 4 * size_of::<u64>(),
+            max_total_disk_bytes: 100_000,
+            compression: Compression::None,
+            block,
+            corruption: CorruptionPolicy::Error,
+            ttl: Some(Duration::from_secs(1)),
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) = channel_with_config::<u64>("ttl", dir.path(), config).unwrap();
+        let mut sent = 0;
+        while !dir.path().join("ttl").join("1").exists() {
+            assert!(snd.send(sent).is_ok());
+            sent += 1;
+        }
+        // A whole queue file that has expired is passed over unread, so
+        // corruption in it goes unnoticed.
+        let path = dir.path().join("ttl").join("0");
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        assert!(permissions.readonly());
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions.clone()).unwrap();
+        let mut fp = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        fp.seek(SeekFrom::Start(1_000)).unwrap();
+        fp.write_all(&[0xff; 64]).unwrap();
+        drop(fp);
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+
+        thread::sleep(Duration::from_millis(1_200));
+        // Touching the file doesn't bring its items back to life.
+        let fp = fs::File::open(&path).unwrap();
+        fp.set_modified(::std::time::SystemTime::now()).unwrap();
+        drop(fp);
+        for i in sent..sent + 10 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        let received: Vec<u64> = rcv.iter().collect();
+        assert_eq!((sent..sent + 10).collect::<Vec<u64>>(), received);
+        assert!(!path.exists());
+        let stats = rcv.stats();
+        assert_eq!(sent as usize, stats.expired);
+        assert_eq!(10, stats.received);
+    }
+
+    #[test]
+    fn ttl_expires_items() {
+        ttl_exp(None);
+        ttl_exp(Some(Block::default()));
+    }
+
+    #[test]
+    fn ttl_goes_by_record_stamps_not_mtime() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 4 * size_of::<u64>(),
+            max_total_disk_bytes: 100_000,
+            compression: Compression::None,
+            ttl: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) = channel_with_config::<u64>("mtime", dir.path(), config).unwrap();
+        let mut sent = 0;
+        while !dir.path().join("mtime").join("1").exists() {
+            assert!(snd.send(sent).is_ok());
+            sent += 1;
+        }
+        // A file that looks old -- say, restored from a backup -- holds items
+        // sent just now.
+        let path = dir.path().join("mtime").join("0");
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+        let fp = fs::File::open(&path).unwrap();
+        fp.set_modified(::std::time::UNIX_EPOCH).unwrap();
+        drop(fp);
+        drop(snd);
+        assert_eq!(sent as usize, rcv.iter().count());
+        assert_eq!(0, rcv.stats().expired);
+    }
+
+    #[test]
+    fn ttl_passes_over_recovered_file() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: size_of::<u64>(),
+            durable: true,
+            compression: Compression::None,
+            corruption: CorruptionPolicy::Error,
+            ttl: Some(Duration::from_secs(1)),
+            ..Config::default()
+        };
+        {
+            let (mut snd, mut rcv) =
+                channel_with_config::<u64>("recovered", dir.path(), config).unwrap();
+            for i in 0..100 {
+                assert!(snd.send(i).is_ok());
+            }
+            assert_eq!(Some(0), rcv.iter().next());
+        }
+        // The queue file the restarted Receiver opens first has expired
+        // whole, so the corrupt payload of its 41st record goes unnoticed.
+        let path = dir.path().join("recovered").join("0");
+        let mut fp = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        fp.seek(SeekFrom::Start(7 + 40 * 24 + 16)).unwrap();
+        fp.write_all(&[0xff; 8]).unwrap();
+        drop(fp);
+        thread::sleep(Duration::from_millis(1_200));
+
+        let (mut snd, mut rcv) =
+            channel_with_config::<u64>("recovered", dir.path(), config).unwrap();
+        for i in 100..110 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        let received: Vec<u64> = rcv.iter().collect();
+        assert_eq!((100..110).collect::<Vec<u64>>(), received);
+        assert!(!path.exists());
+        assert_eq!(99, rcv.stats().expired);
+    }
+
+    #[test]
+    fn builder_refuses_nonsense() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let refused = |builder: ChannelBuilder| match builder.build::<u64>() {
+            Err(Error::Config(e)) => Some(e),
+            _ => None,
+        };
+        for name in &["", ".", "..", "a/b", "/abs"] {
+            let builder = ChannelBuilder::new(name, dir.path());
+            assert_eq!(Some(ConfigError::InvalidName), refused(builder));
+        }
+        let file = dir.path().join("file");
+        fs::write(&file, b"not a directory").unwrap();
+        let builder = ChannelBuilder::new("builder", &file);
+        assert_eq!(Some(ConfigError::NotADirectory), refused(builder));
+        let builder = ChannelBuilder::new("file", dir.path());
+        assert_eq!(Some(ConfigError::NotADirectory), refused(builder));
+        let builder = ChannelBuilder::new("builder", dir.path()).max_memory_bytes(0);
+        assert_eq!(Some(ConfigError::ZeroMemoryBudget), refused(builder));
+        let builder = ChannelBuilder::new("builder", dir.path()).max_disk_bytes(0);
+        assert_eq!(Some(ConfigError::ZeroFileSize), refused(builder));
+        let builder = ChannelBuilder::new("builder", dir.path()).max_disk_files(0);
+        assert_eq!(Some(ConfigError::ZeroDiskFiles), refused(builder));
+        let builder = ChannelBuilder::new("builder", dir.path()).max_total_disk_bytes(0);
+        assert_eq!(Some(ConfigError::ZeroDiskQuota), refused(builder));
+        let builder =
+            ChannelBuilder::new("builder", dir.path()).compression(Compression::Deflate(10));
+        assert_eq!(Some(ConfigError::CompressionLevel), refused(builder));
+        let config = Config {
+            compression: Compression::Deflate(10),
+            ..Config::default()
+        };
+        assert!(matches!(
+            channel_with_config::<u64>("builder", dir.path(), config),
+            Err(Error::Config(ConfigError::CompressionLevel))
+        ));
+        assert!(!dir.path().join("builder").exists());
+    }
+
+    #[test]
+    fn explicit_capacity_clamps_rather_than_refuses() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) =
+            channel_with_explicit_capacity::<u64>("a/b", dir.path(), 0, 0, 1).unwrap();
+        snd.send(9).unwrap();
+        assert_eq!(Some(9), rcv.iter().next());
+        assert_eq!(1, rcv.stats().memory_capacity);
+        assert!(dir.path().join("a").join("b").is_dir());
+        let (mut snd, mut rcv) = channel::<u64>("c/d", dir.path()).unwrap();
+        snd.send(9).unwrap();
+        assert_eq!(Some(9), rcv.iter().next());
+    }
+
+    #[test]
+    fn every_constructor_checks_config() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("precious"), b"not hopper's").unwrap();
+        let inside = dir.path().join("inside");
+        let zero_memory = Config {
+            max_memory_bytes: 0,
+            ..Config::default()
+        };
+        let bad_level = Config {
+            compression: Compression::Deflate(10),
+            ..Config::default()
+        };
+        let refused = |res: Result<(), Error>| match res {
+            Err(Error::Config(e)) => Some(e),
+            _ => None,
+        };
+
+        for name in &["", "../outside", "/abs"] {
+            let res = channel_with_config::<u64>(name, &inside, Config::default());
+            assert_eq!(Some(ConfigError::InvalidName), refused(res.map(|_| ())));
+            let res = priority::channel::<u64>(name, &inside, 2);
+            assert_eq!(Some(ConfigError::InvalidName), refused(res.map(|_| ())));
+            let res = broadcast::channel::<u64>(name, &inside);
+            assert_eq!(Some(ConfigError::InvalidName), refused(res.map(|_| ())));
+        }
+        let res = channel_with_config::<u64>("zero", dir.path(), zero_memory);
+        assert_eq!(Some(ConfigError::ZeroMemoryBudget), refused(res.map(|_| ())));
+        let res = channel_with_codec::<Vec<u8>, _>("level", dir.path(), bad_level, Passthrough);
+        assert_eq!(Some(ConfigError::CompressionLevel), refused(res.map(|_| ())));
+        let res = priority::channel_with_config::<u64>("zero", dir.path(), 2, zero_memory);
+        assert_eq!(Some(ConfigError::ZeroMemoryBudget), refused(res.map(|_| ())));
+        let res = priority::channel::<u64>("lanes", dir.path(), 0);
+        assert_eq!(Some(ConfigError::NoLanes), refused(res.map(|_| ())));
+        let res = broadcast::channel_with_config::<u64>("level", dir.path(), bad_level);
+        assert_eq!(Some(ConfigError::CompressionLevel), refused(res.map(|_| ())));
+        assert!(outside.join("precious").exists());
+    }
+
+    #[test]
+    fn builder_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        for fsync in &[
+            FsyncPolicy::Never,
+            FsyncPolicy::OnFlush,
+            FsyncPolicy::Always,
+        ] {
+            let (mut snd, rcv) = ChannelBuilder::new("builder", dir.path())
+                .max_memory_bytes(4 * size_of::<Vec<u8>>())
+                .max_disk_bytes(1)
+                .max_total_disk_bytes(1_000_000)
+                .compression(Compression::None)
+                .codec(Passthrough)
+                .durable(true)
+                .overflow(OverflowPolicy::DropNewest)
+                .fsync(*fsync)
+                .block(Some(Block::default()))
+                .checkpoint(Checkpoint::default())
+                .corruption(CorruptionPolicy::Error)
+                .ttl(Some(Duration::from_secs(60)))
+                .memory_accounting(MemoryAccounting::Bytes)
+                .build()
+                .unwrap();
+            for i in 0..100u64 {
+                assert!(snd.send(i.to_be_bytes().to_vec()).is_ok());
+            }
+            drop(snd);
+            let received: Vec<u64> = rcv.into_iter().map(|item| number(&item)).collect();
+            assert_eq!((0..100).collect::<Vec<u64>>(), received);
+        }
+    }
+
+    #[test]
+    fn env_budget_shared_between_channels() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let env = HopperEnv::new(
+            &dir.path().join("env"),
+            EnvConfig {
+                max_disk_bytes: 400_000,
+                max_memory_bytes: 16 * size_of::<Vec<u8>>(),
+                max_share_percent: 50,
+            },
+        )
+        .unwrap();
+        let config = Config {
+            compression: Compression::None,
+            ..Config::default()
+        };
+        let (mut runaway, runaway_rcv) = env
+            .channel_with_codec("runaway", config, Passthrough)
+            .unwrap();
+        let (mut quiet, mut quiet_rcv) = env
+            .channel_with_codec("quiet", config, Passthrough)
+            .unwrap();
+        assert!(matches!(env.channel::<u64>("quiet"), Err(Error::NameInUse)));
+
+        // The runaway channel is held to its share of memory, then of disk.
+        let item = vec![7; 1_000];
+        let mut sent = 0;
+        while runaway.send(item.clone()).is_ok() {
+            sent += 1;
+        }
+        let usage = env.usage();
+        assert_eq!(8, usage["runaway"].memory_items);
+        assert!(usage["runaway"].disk_bytes <= 200_000);
+        assert!(usage["runaway"].disk_bytes + 1_015 > 200_000);
+        assert_eq!(sent, usage["runaway"].sent);
+        assert_eq!(env.memory_bytes(), 8 * size_of::<Vec<u8>>());
+        assert_eq!(
+            env.disk_bytes(),
+            usage["runaway"].disk_bytes + usage["quiet"].disk_bytes
+        );
+
+        // The rest have what's left.
+        for _ in 0..100 {
+            assert!(quiet.send(item.clone()).is_ok());
+        }
+        drop(quiet);
+        assert_eq!(100, quiet_rcv.iter().count());
+        drop(quiet_rcv);
+        assert_eq!(vec!["runaway"], env.usage().keys().collect::<Vec<_>>());
+
+        // A channel gone hands back what it held.
+        drop(runaway);
+        drop(runaway_rcv);
+        assert!(env.usage().is_empty());
+        assert_eq!(0, env.disk_bytes());
+        assert_eq!(0, env.memory_bytes());
+        assert!(env.channel::<u64>("runaway").is_ok());
+    }
+
+    #[test]
+    fn env_refuses_names_outside_data_dir() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let env = HopperEnv::new(&dir.path().join("env"), EnvConfig::default()).unwrap();
+        let x = dir.path().join("x");
+        fs::create_dir(&x).unwrap();
+        fs::write(x.join("precious"), b"not the env's").unwrap();
+        for name in &["../x", "/tmp/x", "a/b", "..", ""] {
+            assert!(matches!(
+                env.channel::<u64>(name),
+                Err(Error::Config(ConfigError::InvalidName))
+            ));
+        }
+        assert!(x.join("precious").exists());
+        assert!(env.usage().is_empty());
+    }
+
+    #[test]
+    fn subdirectories_are_left_be() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let notes = dir.path().join("subdir").join("notes");
+        fs::create_dir_all(&notes).unwrap();
+        fs::write(notes.join("precious"), b"not hopper's").unwrap();
+        let (mut snd, mut rcv) = channel::<u64>("subdir", dir.path()).unwrap();
+        assert!(snd.send(9).is_ok());
+        assert_eq!(Some(9), rcv.iter().next());
+        assert!(notes.join("precious").exists());
+        drop((snd, rcv));
+        // A priority channel's lanes are where the queue files would go.
+        drop(priority::channel::<u64>("lanes", dir.path(), 2).unwrap());
+        assert!(matches!(
+            channel::<u64>("lanes", dir.path()),
+            Err(Error::Config(ConfigError::DirectoryInUse))
+        ));
+        assert!(matches!(
+            broadcast::channel::<u64>("lanes", dir.path()),
+            Err(Error::Config(ConfigError::DirectoryInUse))
+        ));
+    }
+
+    #[test]
+    fn env_refuses_share_out_of_range() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        for percent in &[0, 101] {
+            let config = EnvConfig {
+                max_share_percent: *percent,
+                ..EnvConfig::default()
+            };
+            assert!(matches!(
+                HopperEnv::new(&dir.path().join("share"), config),
+                Err(Error::Config(ConfigError::SharePercent))
+            ));
+        }
+        assert!(!dir.path().join("share").exists());
+    }
+
+    #[test]
+    fn memory_budget_in_bytes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 4096,
+            memory_accounting: MemoryAccounting::Bytes,
+            ..Config::default()
+        };
+        let (mut snd, rcv) = channel_with_config("budget", dir.path(), config).unwrap();
+        // Each item weighs its bincode encoding: a length and 1000 bytes.
+        let items: Vec<String> = (0..100).map(|i| format!("{:1000}", i)).collect();
+        for item in &items {
+            assert!(snd.send(item.clone()).is_ok());
+        }
+        let stats = snd.stats();
+        assert_eq!(4 * 1008, stats.memory_bytes);
+        assert_eq!(96, stats.disk_items);
+        drop(snd);
+        assert_eq!(items, rcv.into_iter().collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn memory_budget_heap_sized() {
+        let item = vec![0u64; 100];
+        assert_eq!(size_of::<Vec<u64>>() + 800, HeapSized(Bincode).weigh(&item));
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 2 * HeapSized(Bincode).weigh(&item),
+            memory_accounting: MemoryAccounting::Bytes,
+            ..Config::default()
+        };
+        let (mut snd, rcv) =
+            channel_with_codec("heap_sized", dir.path(), config, HeapSized(Bincode)).unwrap();
+        for _ in 0..10 {
+            assert!(snd.send(item.clone()).is_ok());
+        }
+        let stats = snd.stats();
+        assert_eq!(config.max_memory_bytes, stats.memory_bytes);
+        assert_eq!(8, stats.disk_items);
+        drop(snd);
+        assert_eq!(vec![item; 10], rcv.into_iter().collect::<Vec<Vec<u64>>>());
+    }
+
+    // A channel with room for one item in memory and four on disk.
+    fn cramped_channel(
+        dir: &tempdir::TempDir,
+    ) -> (Sender<Vec<u8>, Passthrough>, Receiver<Vec<u8>, Passthrough>) {
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_disk_bytes: 0,
+            max_disk_files: 1,
+            compression: Compression::None,
+            ..Config::default()
+        };
+        channel_with_codec("cramped", dir.path(), config, Passthrough).unwrap()
+    }
+
+    #[test]
+    fn send_blocking_waits_for_room() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = cramped_channel(&dir);
+        let item = vec![7; 400_000];
+        let snd_item = item.clone();
+        let snd_jh = thread::spawn(move || {
+            for _ in 0..20 {
+                assert!(snd.send_blocking(snd_item.clone()).is_ok());
+            }
+            snd.stats().shed
+        });
+        // Give the Sender time to run out of room.
+        thread::sleep(Duration::from_millis(100));
+        let received: Vec<Vec<u8>> = rcv.into_iter().collect();
+        assert_eq!(vec![item; 20], received);
+        assert_eq!(0, snd_jh.join().expect("snd join failed"));
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn asynchronous_send_waits_for_room() {
+        use futures::executor::block_on;
+        use futures::{stream, SinkExt, StreamExt};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, rcv) = cramped_channel(&dir);
+        let mut snd = super::asynchronous::Sender::from(snd);
+        let rcv = super::asynchronous::Receiver::from(rcv);
+        let item = vec![7; 400_000];
+        let snd_item = item.clone();
+        let snd_jh = thread::spawn(move || {
+            let mut items = stream::iter((0..20).map(|_| Ok(snd_item.clone())));
+            block_on(snd.send_all(&mut items)).unwrap();
+        });
+        // Give the Sender time to run out of room.
+        thread::sleep(Duration::from_millis(100));
+        let received: Vec<Vec<u8>> = block_on(rcv.map(|ev| ev.unwrap()).collect());
+        assert_eq!(vec![item; 20], received);
+        snd_jh.join().expect("snd join failed");
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn asynchronous_clones_share_a_writer() {
+        use futures::executor::block_on;
+        use futures::{stream, SinkExt, StreamExt};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 4 * size_of::<u64>(),
+            ..Config::default()
+        };
+        let (snd, rcv) =
+            super::asynchronous::channel_with_config::<u64>("clones", dir.path(), config)
+                .unwrap();
+        let mut jhs = Vec::new();
+        for n in 0..8 {
+            let mut snd = snd.clone();
+            jhs.push(thread::spawn(move || {
+                let mut items = stream::iter((n * 1_000..(n + 1) * 1_000).map(Ok));
+                block_on(snd.send_all(&mut items)).unwrap();
+            }));
+        }
+        // A clone taken back to a blocking Sender still sends.
+        let mut blocking = snd.clone().into_inner();
+        assert!(blocking.send(8_000).is_ok());
+        drop(blocking);
+        drop(snd);
+        let mut received: Vec<u64> = block_on(rcv.map(|ev| ev.unwrap()).collect());
+        for jh in jhs {
+            jh.join().expect("snd join failed");
+        }
+        received.sort();
+        assert_eq!((0..8_001).collect::<Vec<u64>>(), received);
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn asynchronous_close_hangs_up() {
+        use futures::executor::block_on;
+        use futures::{SinkExt, StreamExt};
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = super::asynchronous::channel::<u64>("close", dir.path()).unwrap();
+        block_on(snd.send(1)).unwrap();
+        block_on(snd.close()).unwrap();
+        assert!(block_on(snd.close()).is_ok());
+        assert!(matches!(block_on(snd.send(2)), Err(Error::Closed)));
+        // The writer thread went with the last open Sender. A clone of the
+        // closed one still sends.
+        let mut clone = snd.clone();
+        block_on(clone.send(3)).unwrap();
+        block_on(clone.close()).unwrap();
+        // Both Senders are closed, not dropped, yet the stream still ends.
+        let received: Vec<u64> = block_on(rcv.map(|ev| ev.unwrap()).collect());
+        assert_eq!(vec![1, 3], received);
+        drop(snd);
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn asynchronous_stream_carries_on_past_errors() {
+        use futures::executor::block_on;
+        use futures::StreamExt;
+
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let rcv = mangled_channel(&dir, CorruptionPolicy::Error, flip_first_payload);
+        let rcv = super::asynchronous::Receiver::from(rcv);
+        let received: Vec<Result<u64, Error>> = block_on(rcv.collect());
+        assert_eq!(Some(&0), received[0].as_ref().ok());
+        assert!(matches!(received[1], Err(Error::Corrupt)));
+        let rest: Vec<u64> = received[2..].iter().map(|ev| *ev.as_ref().unwrap()).collect();
+        assert_eq!((2..100).collect::<Vec<u64>>(), rest);
+    }
+
+    #[test]
+    fn send_timeout_gives_up() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = cramped_channel(&dir);
+        let item = vec![7; 400_000];
+        for _ in 0..5 {
+            assert!(snd.send(item.clone()).is_ok());
+        }
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        assert!(matches!(
+            snd.send_timeout(item.clone(), timeout),
+            Err((_, Error::Full))
+        ));
+        assert!(start.elapsed() >= timeout);
+        // No one is left to make room.
+        drop(rcv);
+        assert!(matches!(snd.send_blocking(item), Err((_, Error::Full))));
+    }
+
+    #[test]
+    fn send_all_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8 * 16,
+            ..Config::default()
+        };
+        let (mut snd, rcv) = channel_with_config::<u64>("send_all", dir.path(), config).unwrap();
+        assert!(snd.send_all(0..1_000).is_ok());
+        assert!(snd.send_all(1_000..2_000).is_ok());
+        assert_eq!(2_000, snd.stats().sent);
+        drop(snd);
+        let expected: Vec<u64> = (0..2_000).collect();
+        assert_eq!(expected, rcv.into_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn send_all_stops_at_failure() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = cramped_channel(&dir);
+        let mut items = (0..10).map(|i| vec![i; 400_000]);
+        match snd.send_all(items.by_ref()) {
+            Err((item, Error::Full)) => assert!(item == vec![5; 400_000]),
+            Err((_, e)) => panic!("expected Full, got {:?}", e),
+            Ok(()) => panic!("expected Full"),
+        }
+        assert_eq!(4, items.count());
+        let stats = snd.stats();
+        assert_eq!((5, 1), (stats.sent, stats.shed));
+        drop(snd);
+        let expected: Vec<Vec<u8>> = (0..5).map(|i| vec![i; 400_000]).collect();
+        assert!(expected == rcv.into_iter().collect::<Vec<Vec<u8>>>());
+    }
+
+    #[test]
+    fn recv_batch_round_trip() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8 * 16,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_config::<u64>("recv_batch", dir.path(), config).unwrap();
+        let timeout = Duration::from_millis(10);
+        assert!(matches!(
+            rcv.recv_batch(10, timeout),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        assert!(snd.send_all(0..1_000).is_ok());
+        drop(snd);
+        let mut received = Vec::new();
+        loop {
+            match rcv.recv_batch(10, timeout) {
+                Ok(batch) => {
+                    assert!(!batch.is_empty() && batch.len() <= 10);
+                    received.extend(batch);
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(e) => panic!("unexpected {:?}", e),
+            }
+        }
+        let expected: Vec<u64> = (0..1_000).collect();
+        assert_eq!(expected, received);
+    }
+
+    // Takes its time encoding Celsius(2), long enough for the Receiver to
+    // make room meanwhile.
+    #[derive(Debug, Clone, Copy)]
+    struct DawdlingCodec;
+
+    impl Codec<Celsius> for DawdlingCodec {
+        fn encode<W: Write>(&self, value: &Celsius, writer: &mut W) -> io::Result<()> {
+            if value.0 == 2 {
+                thread::sleep(Duration::from_millis(500));
+            }
+            CelsiusCodec.encode(value, writer)
+        }
+
+        fn decode<R: Read>(&self, reader: &mut R) -> io::Result<Celsius> {
+            CelsiusCodec.decode(reader)
+        }
+    }
+
+    #[test]
+    fn block_spill_then_quiet() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 2,
+            block: Some(Block::default()),
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            channel_with_codec("spill", dir.path(), config, DawdlingCodec).unwrap();
+        assert!(snd.send(Celsius(0)).is_ok());
+        // The deque is full, so 1 goes into the pending block.
+        assert!(snd.send(Celsius(1)).is_ok());
+        // As does 2, the Receiver making room while it is encoded.
+        let rcv_jh = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(Celsius(0), rcv.recv().unwrap());
+            rcv
+        });
+        assert!(snd.send(Celsius(2)).is_ok());
+        let mut rcv = rcv_jh.join().expect("rcv join failed");
+        // Announce what's left, then go quiet without hanging up.
+        assert!(snd.flush().is_ok());
+        let timeout = Duration::from_secs(10);
+        assert_eq!(Celsius(1), rcv.recv_timeout(timeout).unwrap());
+        assert_eq!(Celsius(2), rcv.recv_timeout(timeout).unwrap());
+        drop(snd);
+        assert!(matches!(rcv.recv(), Err(RecvError::Disconnected)));
+    }
+
+    #[test]
+    fn flusher_announces_disk_writes() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) = channel_with_config::<u64>("flusher", dir.path(), config).unwrap();
+        let timeout = Duration::from_millis(100);
+        // With no room in memory to announce it, 1 is left in limbo.
+        assert!(snd.send(0).is_ok());
+        assert!(snd.send(1).is_ok());
+        assert_eq!(0, rcv.recv().unwrap());
+        assert!(matches!(
+            rcv.recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        // Until the flusher comes along.
+        snd.spawn_flusher(Duration::from_millis(10)).unwrap();
+        assert_eq!(1, rcv.recv_timeout(Duration::from_secs(60)).unwrap());
+        drop(snd);
+        assert!(matches!(rcv.recv(), Err(RecvError::Disconnected)));
+    }
+
+    #[test]
+    fn shared_receiver_delivers_once() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8 * 16,
+            ..Config::default()
+        };
+        let (mut snd, rcv) = channel_with_config::<u64>("shared", dir.path(), config).unwrap();
+        let rcv = rcv.into_shared();
+        let mut rcv_jhs = Vec::new();
+        for _ in 0..4 {
+            let rcv = rcv.clone();
+            rcv_jhs.push(thread::spawn(move || rcv.iter().collect::<Vec<u64>>()));
+        }
+        drop(rcv);
+        let snd_jh = thread::spawn(move || {
+            for i in 0..10_000 {
+                assert!(snd.send(i).is_ok());
+            }
+        });
+        snd_jh.join().expect("snd join failed");
+        let mut received = Vec::new();
+        for jh in rcv_jhs {
+            received.extend(jh.join().expect("rcv join failed"));
+        }
+        received.sort();
+        let expected: Vec<u64> = (0..10_000).collect();
+        assert_eq!(expected, received);
+    }
+
+    #[test]
+    fn acked_redelivers_nacked_and_timed_out() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = channel::<u64>("acked", dir.path()).unwrap();
+        let mut rcv = rcv.into_acked(Duration::from_millis(50));
+        for i in 0..3 {
+            assert!(snd.send(i).is_ok());
+        }
+        let first = rcv.recv().unwrap();
+        assert_eq!(0, *first);
+        first.nack();
+        let first = rcv.recv().unwrap();
+        assert_eq!(0, *first);
+        assert!(first.redelivered());
+        assert!(first.ack().is_ok());
+        // Dropped unacked, this one is delivered again once it times out.
+        assert_eq!(1, *rcv.recv().unwrap());
+        let third = rcv.recv().unwrap();
+        assert_eq!(2, *third);
+        assert!(third.ack().is_ok());
+        drop(snd);
+        let second = rcv.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(1, *second);
+        assert!(second.redelivered());
+        assert!(second.ack().is_ok());
+        assert!(matches!(rcv.recv(), Err(RecvError::Disconnected)));
+    }
+
+    #[test]
+    fn acked_queue_files_held_until_acked() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, rcv) = cramped_channel(&dir);
+        let mut rcv = rcv.into_acked(Duration::from_secs(3600));
+        let root = dir.path().join("cramped");
+        let on_disk = || super::private::queue_files(&root).unwrap().len();
+        let item = vec![7; 400_000];
+        let mut sent = 0;
+        while snd.send(item.clone()).is_ok() {
+            sent += 1;
+        }
+        let mut deliveries = vec![rcv.recv().unwrap()];
+        assert!(snd.flush().is_ok());
+        for _ in 1..sent {
+            deliveries.push(rcv.recv().unwrap());
+        }
+        assert!(deliveries.iter().all(|delivery| **delivery == item));
+        // Every queue file has been read, but none can go until acked. With
+        // the in-memory buffer full there's nowhere for an item to go.
+        assert!(snd.send(item.clone()).is_ok());
+        assert!(matches!(snd.send(item.clone()), Err((_, Error::Full))));
+        assert!(on_disk() > 1);
+        assert_eq!(on_disk(), snd.stats().disk_files);
+        for delivery in deliveries {
+            assert!(delivery.ack().is_ok());
+        }
+        assert_eq!(on_disk(), snd.stats().disk_files);
+        assert!(snd.send(item.clone()).is_ok());
+    }
+
+    #[test]
+    fn acked_restart_redelivers_unacked() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            max_disk_bytes: 32,
+            durable: true,
+            ..Config::default()
+        };
+        {
+            let (mut snd, mut rcv) = channel_with_config("acked", dir.path(), config).unwrap();
+            for i in 0..100u64 {
+                assert!(snd.send(i).is_ok());
+            }
+            assert_eq!(Some(0), rcv.iter().next());
+            assert!(snd.flush().is_ok());
+            let mut rcv = rcv.into_acked(Duration::from_secs(3600));
+            for i in 1..100 {
+                let delivery = rcv.recv().unwrap();
+                assert_eq!(i, *delivery);
+                if i != 50 {
+                    assert!(delivery.ack().is_ok());
+                }
+            }
+        }
+        // Everything from the oldest item not acked on is delivered again.
+        let (mut snd, mut rcv) = channel_with_config("acked", dir.path(), config).unwrap();
+        assert!(snd.send(100).is_ok());
+        for i in 50..101u64 {
+            assert_eq!(Some(i), rcv.iter().next());
+        }
+    }
+
+    #[test]
+    fn broadcast_every_subscriber_sees_everything() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8 * 4,
+            ..Config::default()
+        };
+        let mut publisher =
+            broadcast::channel_with_config::<u64>("broadcast", dir.path(), config).unwrap();
+        let mut rcv_jhs = Vec::new();
+        for _ in 0..3 {
+            let mut subscriber = publisher.subscribe();
+            rcv_jhs.push(thread::spawn(move || {
+                subscriber.iter().collect::<Vec<u64>>()
+            }));
+        }
+        // This one falls behind, spilling everything past its first four
+        // items to disk.
+        let mut laggard = publisher.subscribe();
+        for i in 0..10_000 {
+            assert!(publisher.send(i).is_ok());
+        }
+        drop(publisher);
+        let expected: Vec<u64> = (0..10_000).collect();
+        for jh in rcv_jhs {
+            assert_eq!(expected, jh.join().expect("rcv join failed"));
+        }
+        assert_eq!(expected, laggard.iter().collect::<Vec<u64>>());
+        assert!(matches!(
+            laggard.try_recv(),
+            Err(TryRecvError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn broadcast_subscriber_racing_publisher() {
+        // A Subscriber making room while the Publisher sends must only be
+        // told of disk writes once they're flushed.
+        for _ in 0..20 {
+            let dir = tempdir::TempDir::new("hopper").unwrap();
+            let config = Config {
+                max_memory_bytes: 8,
+                ..Config::default()
+            };
+            let mut publisher =
+                broadcast::channel_with_config::<u64>("broadcast", dir.path(), config).unwrap();
+            let mut subscriber = publisher.subscribe();
+            let rcv_jh = thread::spawn(move || subscriber.iter().collect::<Vec<u64>>());
+            for i in 0..2_000 {
+                assert!(publisher.send(i).is_ok());
+            }
+            drop(publisher);
+            let received = rcv_jh.join().expect("rcv join failed");
+            assert_eq!((0..2_000).collect::<Vec<u64>>(), received);
+        }
+    }
+
+    #[test]
+    fn broadcast_files_held_for_slowest() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_disk_bytes: 0,
+            max_disk_files: 1,
+            compression: Compression::None,
+            ..Config::default()
+        };
+        let mut publisher =
+            broadcast::channel_with_codec("broadcast", dir.path(), config, Passthrough).unwrap();
+        let mut fast = publisher.subscribe();
+        let mut slow = publisher.subscribe();
+        let item = vec![7; 64 * 1024];
+        // The fast subscriber keeps up, but the slow one pins every queue
+        // file until there's no room for another.
+        let mut sent = 0;
+        loop {
+            match publisher.send(item.clone()) {
+                Ok(()) => sent += 1,
+                Err((_, Error::Full)) => break,
+                Err((_, e)) => panic!("unexpected error: {:?}", e),
+            }
+            assert_eq!(Some(item.clone()), fast.recv().ok());
+        }
+        assert!(sent > 16);
+        assert_eq!(Some(item.clone()), slow.recv().ok());
+        assert!(publisher.flush().is_ok());
+        for _ in 1..sent {
+            assert_eq!(Some(item.clone()), slow.recv().ok());
+        }
+        // Once the slow subscriber has caught up its files are removed,
+        // making room for as many again.
+        for _ in 0..sent {
+            assert!(publisher.send(item.clone()).is_ok());
+            assert_eq!(Some(item.clone()), fast.recv().ok());
+            assert_eq!(Some(item.clone()), slow.recv().ok());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn broadcast_failed_flush_forgets_dropped_records() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        // Nine items to a queue file, and a Subscriber's deque holds one.
+        let config = Config {
+            max_memory_bytes: size_of::<Vec<u8>>(),
+            max_total_disk_bytes: 2_000,
+            compression: Compression::None,
+            ..Config::default()
+        };
+        let mut publisher =
+            broadcast::channel_with_codec("broadcast", dir.path(), config, Passthrough).unwrap();
+        let next = dir.path().join("broadcast").join("1");
+        ::std::os::unix::fs::symlink("/dev/full", &next).unwrap();
+        let item = |i: u8| vec![i; 100];
+        let mut early = publisher.subscribe();
+        for i in 0..13 {
+            assert!(publisher.send(item(i)).is_ok());
+        }
+        // Every disk write made for this one is still buffered when the
+        // flush fails.
+        let mut late = publisher.subscribe();
+        for i in 13..16 {
+            assert!(publisher.send(item(i)).is_ok());
+        }
+        assert_eq!(Some(item(0)), early.recv().ok());
+        assert_eq!(Some(item(13)), late.recv().ok());
+        match publisher.send(item(16)) {
+            Err((_, Error::IoError(_))) => {}
+            other => panic!("unexpected result: {:?}", other.map_err(|e| e.1)),
+        }
+        for i in 17..19 {
+            assert!(publisher.send(item(i)).is_ok());
+        }
+        drop(publisher);
+        let expected: Vec<Vec<u8>> = (1..10).chain(17..19).map(item).collect();
+        assert_eq!(expected, early.iter().collect::<Vec<Vec<u8>>>());
+        assert_eq!(
+            vec![item(17), item(18)],
+            late.iter().collect::<Vec<Vec<u8>>>()
+        );
+    }
+
+    #[test]
+    fn priority_high_lane_overtakes_bulk() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 64,
+            ..Config::default()
+        };
+        let (mut snd, mut rcv) =
+            priority::channel_with_config::<u64>("priority", dir.path(), 3, config).unwrap();
+        // The bulk lane spills to disk well before the control lane is used.
+        for i in 0..1_000 {
+            assert!(snd.send(2, i).is_ok());
+        }
+        for i in 0..5 {
+            assert!(snd.send(0, i).is_ok());
+        }
+        assert!(snd.send(1, 500).is_ok());
+        for i in 0..5 {
+            assert_eq!(Some(i), rcv.recv().ok());
+        }
+        assert_eq!(Some(500), rcv.recv().ok());
+        assert_eq!(Some(0), rcv.recv().ok());
+        assert!(snd.send(0, 42).is_ok());
+        assert_eq!(Some(42), rcv.recv().ok());
+        drop(snd);
+        assert_eq!(
+            (1..1_000).collect::<Vec<u64>>(),
+            rcv.iter().collect::<Vec<u64>>()
+        );
+        assert!(dir.path().join("priority").join("2").is_dir());
+    }
+
+    #[test]
+    fn priority_weights_prevent_starvation() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut snd, mut rcv) = priority::channel::<u64>("priority", dir.path(), 2).unwrap();
+        rcv.set_weights(&[3, 1]);
+        for i in 0..30 {
+            assert!(snd.send(0, i).is_ok());
+        }
+        for i in 100..110 {
+            assert!(snd.send(1, i).is_ok());
+        }
+        // Three from the high lane for every one from the low.
+        let mut received = Vec::new();
+        for _ in 0..8 {
+            received.push(rcv.try_recv().ok());
+        }
+        assert_eq!(
+            vec![0, 1, 2, 100, 3, 4, 5, 101]
+                .into_iter()
+                .map(Some)
+                .collect::<Vec<_>>(),
+            received
+        );
+        assert!(matches!(rcv.recv_timeout(Duration::from_millis(1)), Ok(6)));
+    }
+
+    #[test]
+    fn priority_recv_wakes_on_any_lane() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (snd, mut rcv) = priority::channel::<u64>("priority", dir.path(), 4).unwrap();
+        let mut snds = (0..4).map(|_| snd.clone()).collect::<Vec<_>>();
+        drop(snd);
+        assert!(matches!(rcv.try_recv(), Err(TryRecvError::Empty)));
+        assert!(matches!(
+            rcv.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        let joins = snds
+            .drain(..)
+            .enumerate()
+            .map(|(lane, mut snd)| {
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(10 * lane as u64));
+                    for i in 0..100 {
+                        assert!(snd.send(lane, i).is_ok());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut received = 0;
+        while rcv.recv().is_ok() {
+            received += 1;
+        }
+        for join in joins {
+            join.join().unwrap();
+        }
+        assert_eq!(400, received);
+    }
+
+    #[test]
+    fn select_takes_from_any_receiver() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 64,
+            ..Config::default()
+        };
+        let mut select = Select::new();
+        let mut snds = Vec::new();
+        for i in 0..20 {
+            let (snd, rcv) =
+                channel_with_config::<u64>(&format!("{}", i), dir.path(), config).unwrap();
+            assert_eq!(i, select.add(rcv));
+            snds.push(snd);
+        }
+        assert!(matches!(select.try_recv(), Err(TryRecvError::Empty)));
+        assert!(matches!(
+            select.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        let joins = snds
+            .into_iter()
+            .enumerate()
+            .map(|(index, mut snd)| {
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(index as u64));
+                    for i in 0..500 {
+                        assert!(snd.send((index as u64) * 1_000 + i).is_ok());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut received: Vec<Vec<u64>> = vec![Vec::new(); 20];
+        while let Ok((index, event)) = select.recv() {
+            received[index].push(event);
+        }
+        for join in joins {
+            join.join().unwrap();
+        }
+        for (index, events) in received.into_iter().enumerate() {
+            let base = (index as u64) * 1_000;
+            assert_eq!((base..base + 500).collect::<Vec<u64>>(), events);
+        }
+        assert!(select.remove(3).is_some());
+        assert!(select.remove(3).is_none());
+        assert_eq!(19, select.len());
+    }
+
+    #[test]
+    fn select_is_fair() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let (mut busy, busy_rcv) = channel::<u64>("busy", dir.path()).unwrap();
+        let (mut quiet, quiet_rcv) = channel::<u64>("quiet", dir.path()).unwrap();
+        let mut select = Select::new();
+        select.add(busy_rcv);
+        select.add(quiet_rcv);
+        for i in 0..10 {
+            assert!(busy.send(i).is_ok());
+        }
+        assert!(quiet.send(100).is_ok());
+        assert_eq!(Some((0, 0)), select.recv().ok());
+        assert_eq!(Some((1, 100)), select.recv().ok());
+        assert_eq!(Some((0, 1)), select.recv().ok());
+    }
+
+    #[test]
+    fn truncated_record_skipped() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let rcv = mangled_channel(&dir, CorruptionPolicy::SkipRecord, |fp| {
+            let len = fp.metadata().unwrap().len();
+            fp.set_len(len - 3).unwrap();
+        });
+        let expected: Vec<u64> = (0..99).collect();
+        assert_eq!(expected, rcv.into_iter().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn foreign_file_skipped() {
+        let dir = tempdir::TempDir::new("hopper").unwrap();
+        let config = Config {
+            max_memory_bytes: 8,
+            durable: true,
+            ..Config::default()
+        };
+        let (mut snd, rcv) = channel_with_config::<u64>("foreign", dir.path(), config).unwrap();
+        for i in 0..100 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        drop(rcv);
+        let path = dir.path().join("foreign").join("0");
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        let mut fp = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        fp.write_all(b"nope").unwrap();
+        drop(fp);
+
+        let (mut snd, rcv) = channel_with_config::<u64>("foreign", dir.path(), config).unwrap();
+        for i in 100..200 {
+            assert!(snd.send(i).is_ok());
+        }
+        drop(snd);
+        let expected: Vec<u64> = (100..200).collect();
+        assert_eq!(expected, rcv.into_iter().collect::<Vec<u64>>());
+    }
+
     fn round_trip_exp(
         in_memory_limit: usize,
         max_bytes: usize,
@@ -344,11 +2869,11 @@ mod test {
     #[test]
     fn round_trip() {
         fn inner(in_memory_limit: usize, max_bytes: usize, total_elems: usize) -> TestResult {
-            let sz = mem::size_of::<u64>();
+            let sz = size_of::<u64>();
             if (in_memory_limit / sz) == 0 || (max_bytes / sz) == 0 || total_elems == 0 {
                 return TestResult::discard();
             }
-            let max_disk_files = usize::max_value();
+            let max_disk_files = usize::MAX;
             TestResult::from_bool(round_trip_exp(
                 in_memory_limit,
                 max_bytes,
@@ -384,15 +2909,8 @@ mod test {
                     snd_jh.push(thread::spawn(move || {
                         let mut queued = Vec::new();
                         for mut ev in chunk {
-                            loop {
-                                match thr_snd.send(ev) {
-                                    Err(res) => {
-                                        ev = res.0;
-                                    }
-                                    Ok(()) => {
-                                        break;
-                                    }
-                                }
+                            while let Err(res) = thr_snd.send(ev) {
+                                ev = res.0;
                             }
                             queued.push(ev);
                         }
@@ -480,10 +2998,10 @@ mod test {
             max_disk_files: usize,
             vals: Vec<u64>,
         ) -> TestResult {
-            let sz = mem::size_of::<u64>();
+            let sz = size_of::<u64>();
             if total_senders == 0
                 || total_senders > 10
-                || vals.len() == 0
+                || vals.is_empty()
                 || (vals.len() < total_senders)
                 || (in_memory_bytes / sz) == 0
                 || (disk_bytes / sz) == 0
@@ -589,7 +3107,7 @@ mod test {
             max_disk_files: usize,
             total_vals: usize,
         ) -> TestResult {
-            let sz = mem::size_of::<u64>();
+            let sz = size_of::<u64>();
             if total_vals == 0 || (in_memory_bytes / sz) == 0 || (disk_bytes / sz) == 0 {
                 return TestResult::discard();
             }
@@ -602,5 +3120,4 @@ mod test {
         }
         QuickCheck::new().quickcheck(inner as fn(usize, usize, usize, usize) -> TestResult);
     }
-
 }
@@ -0,0 +1,323 @@
+//! A channel of several lanes, higher lanes overtaking lower
+//!
+//! A priority channel is made up of lanes, lane 0 being the highest. Each
+//! lane is a hopper channel of its own, with its own in-memory deque and its
+//! own queue files in a sub-directory of the channel directory named for the
+//! lane. A `PrioritySender` sends an item down a given lane. The
+//! `PriorityReceiver` always takes from the highest lane with an item to
+//! hand, so that a burst of bulk data in a low lane does not hold up control
+//! messages in a higher one. Lower lanes may be starved that way: weights
+//! bound how many items a lane hands out in a row while lower lanes wait. See
+//! `PriorityReceiver::set_weights`.
+//!
+//! # Example
+//! ```
+//! extern crate hopper;
+//! extern crate tempdir;
+//!
+//! let dir = tempdir::TempDir::new("hopper").unwrap();
+//! let (mut snd, mut rcv) = hopper::priority::channel::<u64>("example", dir.path(), 2).unwrap();
+//!
+//! snd.send(1, 9).unwrap();
+//! snd.send(0, 1).unwrap();
+//! assert_eq!(Some(1), rcv.recv().ok());
+//! assert_eq!(Some(9), rcv.recv().ok());
+//! ```
+use codec::{Bincode, Codec};
+use private::{self, Unparker};
+use receiver::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+use sender::Sender;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Create a (PrioritySender, PriorityReceiver) pair of `lanes` lanes with the
+/// default `Config`
+///
+/// Items paged to disk are serialized with `Bincode`.
+#[allow(clippy::type_complexity)]
+pub fn channel<T>(
+    name: &str,
+    data_dir: &Path,
+    lanes: usize,
+) -> Result<(PrioritySender<T>, PriorityReceiver<T>), super::Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    channel_with_config(name, data_dir, lanes, super::Config::default())
+}
+
+/// Create a (PrioritySender, PriorityReceiver) pair of `lanes` lanes from a
+/// `Config`
+///
+/// Each lane is a channel made from `config`, so that the limits of `config`
+/// apply to each lane in turn.
+#[allow(clippy::type_complexity)]
+pub fn channel_with_config<T>(
+    name: &str,
+    data_dir: &Path,
+    lanes: usize,
+    config: super::Config,
+) -> Result<(PrioritySender<T>, PriorityReceiver<T>), super::Error>
+where
+    T: Serialize + DeserializeOwned,
+{
+    channel_with_codec(name, data_dir, lanes, config, Bincode)
+}
+
+/// Create a (PrioritySender, PriorityReceiver) pair of `lanes` lanes from a
+/// `Config`, serializing items paged to disk with `codec`
+///
+/// A channel of no lanes is refused with `ConfigError::NoLanes`.
+#[allow(clippy::type_complexity)]
+pub fn channel_with_codec<T, C>(
+    name: &str,
+    data_dir: &Path,
+    lanes: usize,
+    config: super::Config,
+    codec: C,
+) -> Result<(PrioritySender<T, C>, PriorityReceiver<T, C>), super::Error>
+where
+    C: Codec<T>,
+{
+    if lanes == 0 {
+        return Err(super::Error::Config(super::ConfigError::NoLanes));
+    }
+    if !private::name_within(name) {
+        return Err(super::Error::Config(super::ConfigError::InvalidName));
+    }
+    let root = data_dir.join(name);
+    let mut senders = Vec::with_capacity(lanes);
+    let mut receivers = Vec::with_capacity(lanes);
+    for lane in 0..lanes {
+        let (snd, rcv) =
+            super::channel_with_codec(&format!("{}", lane), &root, config, codec.clone())?;
+        senders.push(snd);
+        receivers.push(rcv);
+    }
+    Ok((
+        PrioritySender { lanes: senders },
+        PriorityReceiver {
+            lanes: receivers,
+            weights: None,
+            credits: Vec::new(),
+        },
+    ))
+}
+
+/// The 'send' side of a priority channel
+///
+/// The PrioritySender is clonable.
+#[derive(Debug)]
+pub struct PrioritySender<T, C = Bincode> {
+    lanes: Vec<Sender<T, C>>,
+}
+
+impl<T, C> Clone for PrioritySender<T, C>
+where
+    C: Codec<T>,
+{
+    fn clone(&self) -> PrioritySender<T, C> {
+        PrioritySender {
+            lanes: self.lanes.clone(),
+        }
+    }
+}
+
+impl<T, C> PrioritySender<T, C>
+where
+    C: Codec<T>,
+{
+    /// Send an event down `lane`, lane 0 being the highest
+    ///
+    /// Errors are as for `Sender::send`.
+    ///
+    /// # Panics
+    ///
+    /// Panics should there be no such lane.
+    pub fn send(&mut self, lane: usize, event: T) -> Result<(), (T, super::Error)> {
+        self.lanes[lane].send(event)
+    }
+
+    /// Attempt to flush the outstanding disk writes of every lane
+    ///
+    /// Every lane is flushed, even should one fail. The first failure is
+    /// returned. See `Sender::flush`.
+    pub fn flush(&mut self) -> Result<(), super::Error> {
+        let mut flushed = Ok(());
+        for lane in &mut self.lanes {
+            if let Err(e) = lane.flush() {
+                if flushed.is_ok() {
+                    flushed = Err(e);
+                }
+            }
+        }
+        flushed
+    }
+
+    /// The number of lanes of the channel
+    pub fn lanes(&self) -> usize {
+        self.lanes.len()
+    }
+}
+
+/// The 'receive' side of a priority channel
+#[derive(Debug)]
+pub struct PriorityReceiver<T, C = Bincode> {
+    lanes: Vec<Receiver<T, C>>,
+    weights: Option<Vec<usize>>,
+    credits: Vec<usize>, // items each lane may yet hand out this round
+}
+
+impl<T, C> PriorityReceiver<T, C>
+where
+    C: Codec<T>,
+{
+    /// Bound how many items a lane hands out in a row while lower lanes wait
+    ///
+    /// Lane `i` hands out up to `weights[i]` items each round. Once no lane
+    /// with an item to hand has any of its allowance left a new round begins.
+    /// Lanes without a weight, or with a weight of zero, have a weight of 1.
+    /// By default lanes have no weights and a higher lane always goes first.
+    pub fn set_weights(&mut self, weights: &[usize]) {
+        let weights: Vec<usize> = (0..self.lanes.len())
+            .map(|lane| weights.get(lane).map_or(1, |w| ::std::cmp::max(1, *w)))
+            .collect();
+        self.credits = weights.clone();
+        self.weights = Some(weights);
+    }
+
+    // Take an item from the highest lane that has one and, with weights,
+    // some of its allowance left.
+    fn take(&mut self) -> Result<T, TryRecvError> {
+        let mut disconnected = 0;
+        for lane in 0..self.lanes.len() {
+            if self.weights.is_some() && self.credits[lane] == 0 {
+                continue;
+            }
+            match self.lanes[lane].try_recv() {
+                Ok(event) => {
+                    if self.weights.is_some() {
+                        self.credits[lane] -= 1;
+                    }
+                    return Ok(event);
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => disconnected += 1,
+                Err(TryRecvError::Disk(e)) => return Err(TryRecvError::Disk(e)),
+            }
+        }
+        let exhausted = self.credits.contains(&0);
+        if let (true, Some(weights)) = (exhausted, self.weights.as_ref()) {
+            // Start a new round.
+            self.credits.clone_from(weights);
+            return self.take();
+        }
+        if disconnected == self.lanes.len() {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        // Each lane's deque wakes us through a Waker should an item land in
+        // it. The Waker is registered before looking a last time, so that an
+        // item landing between the look and the park is sure to wake us.
+        let waker = Waker::from(Arc::new(Unparker(thread::current())));
+        loop {
+            match self.take() {
+                Ok(event) => return Ok(event),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Disk(e)) => return Err(RecvTimeoutError::Disk(e)),
+            }
+            for lane in &self.lanes {
+                lane.register_waker(&waker);
+            }
+            match self.take() {
+                Ok(event) => return Ok(event),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Disk(e)) => return Err(RecvTimeoutError::Disk(e)),
+            }
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+
+    /// Receive a value from the highest lane with one, blocking until one is
+    /// available
+    ///
+    /// `RecvError::Disconnected` is returned once every lane is hung up on
+    /// and drained. Errors are otherwise as for `Receiver::recv`.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        match self.recv_until(None) {
+            Ok(ev) => Ok(ev),
+            Err(RecvTimeoutError::Disk(e)) => Err(RecvError::Disk(e)),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                Err(RecvError::Disconnected)
+            }
+        }
+    }
+
+    /// Attempt to receive a value from the highest lane with one, without
+    /// blocking
+    ///
+    /// Errors are as for `recv` and `Receiver::try_recv`.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.take()
+    }
+
+    /// Receive a value from the highest lane with one, blocking for no longer
+    /// than `timeout`
+    ///
+    /// Errors are as for `recv` and `Receiver::recv_timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(private::deadline(timeout))
+    }
+
+    /// The number of lanes of the channel
+    pub fn lanes(&self) -> usize {
+        self.lanes.len()
+    }
+
+    /// An iterator over messages on the receiver, ending as that of
+    /// `Receiver::iter` does
+    pub fn iter(&mut self) -> Iter<'_, T, C> {
+        Iter { rx: self }
+    }
+}
+
+/// An iterator over the values received by a `PriorityReceiver`
+#[derive(Debug)]
+pub struct Iter<'a, T, C = Bincode>
+where
+    T: 'a,
+    C: 'a,
+{
+    rx: &'a mut PriorityReceiver<T, C>,
+}
+
+impl<'a, T, C> Iterator for Iter<'a, T, C>
+where
+    C: Codec<T>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
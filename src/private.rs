@@ -1,18 +1,163 @@
-use sender;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use compression::Compression;
 use deque;
+use flate2::Crc;
+use sender;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::task::Wake;
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{cmp, fs, io};
-use std::path::Path;
 
+/// The name of the file, kept alongside the queue files, that a durable
+/// Receiver records its read position into.
+pub const CURSOR_FILE: &str = "cursor";
+
+/// The name of the file a new cursor is written to before it replaces the
+/// cursor file.
+pub const CURSOR_REPLACEMENT_FILE: &str = "cursor.new";
+
+/// The name of the file an evicted queue file is replaced with.
+pub const EVICTED_REPLACEMENT_FILE: &str = "evicted.new";
+
+/// The bytes every queue file opens with
+pub const MAGIC: &[u8; 4] = b"hopr";
+
+/// The version of the queue file format, following the magic bytes
+pub const VERSION: u8 = 1;
+
+/// The length of the header that opens every queue file: the magic bytes,
+/// the format version, the compression and the record framing.
+pub const HEADER_LEN: u64 = 7;
+
+/// The header that opens every queue file, recording how the records that
+/// follow it were written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub compression: Compression,
+    /// Whether records are blocks of items or single items
+    pub blocks: bool,
+    /// Whether records carry the time their items were sent
+    pub timestamps: bool,
+}
+
+impl Header {
+    /// The length of the prefix of every record in the file. Each record
+    /// opens with the length of its payload and a CRC32 of the rest of the
+    /// record. A block follows that with the number of items in it and,
+    /// should the file carry timestamps, the record ends its prefix with the
+    /// time its newest item was sent.
+    pub fn prefix_len(&self) -> usize {
+        let mut len = 2 * sender::PAYLOAD_LEN_BYTES;
+        if self.blocks {
+            len += sender::PAYLOAD_LEN_BYTES;
+        }
+        if self.timestamps {
+            len += STAMP_LEN;
+        }
+        len
+    }
+
+    /// The number of items in the record opening with `prefix`
+    pub fn items(&self, prefix: &[u8]) -> usize {
+        let len = sender::PAYLOAD_LEN_BYTES;
+        if self.blocks {
+            BigEndian::read_u32(&prefix[2 * len..3 * len]) as usize
+        } else {
+            1
+        }
+    }
+
+    /// The time the newest item of the record opening with `prefix` was
+    /// sent, should the file carry timestamps
+    pub fn stamp(&self, prefix: &[u8]) -> Option<u64> {
+        if self.timestamps {
+            Some(BigEndian::read_u64(
+                &prefix[self.prefix_len() - STAMP_LEN..],
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// The length of a timestamp: milliseconds since the UNIX epoch
+pub const STAMP_LEN: usize = 8;
+
+/// The time now, as recorded in a queue file
+pub fn now_stamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() * 1000 + u64::from(since.subsec_millis()),
+        Err(_) => 0,
+    }
+}
+
+/// Whether an item sent at `stamp` has outlived `ttl`
+pub fn expired(stamp: u64, ttl: Duration) -> bool {
+    let age = now_stamp().saturating_sub(stamp);
+    u128::from(age) > ttl.as_millis()
+}
+
+/// The CRC32 recorded in a record prefix, covering the rest of the prefix
+/// and the payload
+pub fn checksum(prefix_rest: &[u8], payload: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(prefix_rest);
+    crc.update(payload);
+    crc.sum()
+}
+
+/// Fill in the prefix of `record`, left blank ahead of its payload, for a
+/// record of `items` items, the newest sent at `stamp`, in a file opening
+/// with `header`
+pub fn fill_prefix(header: Header, record: &mut [u8], items: usize, stamp: u64) {
+    let len = sender::PAYLOAD_LEN_BYTES;
+    let prefix_len = header.prefix_len();
+    let payload_len = record.len() - prefix_len;
+    BigEndian::write_u32(&mut record[..len], payload_len as u32);
+    if header.blocks {
+        BigEndian::write_u32(&mut record[2 * len..3 * len], items as u32);
+    }
+    if header.timestamps {
+        BigEndian::write_u64(&mut record[prefix_len - STAMP_LEN..prefix_len], stamp);
+    }
+    let crc = checksum(
+        &record[2 * len..header.prefix_len()],
+        &record[header.prefix_len()..],
+    );
+    BigEndian::write_u32(&mut record[len..2 * len], crc);
+}
+
+/// Read into `buf` until it's full or `fp` runs out, returning the number of
+/// bytes read
+pub fn read_full<R: Read>(fp: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match fp.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+// An item held in memory goes alongside its weight, which is zero unless
+// the channel accounts for memory in bytes, and the time it was sent, should
+// the channel have a TTL.
 #[derive(Debug)]
 pub enum Placement<T> {
-    Memory(T),
+    Memory(T, usize, Option<Instant>),
     Disk(usize),
 }
 
 impl<T> Placement<T> {
     pub fn extract(self) -> Option<T> {
         match self {
-            Placement::Memory(elem) => Some(elem),
+            Placement::Memory(elem, _, _) => Some(elem),
             Placement::Disk(_) => None,
         }
     }
@@ -20,43 +165,393 @@ impl<T> Placement<T> {
 
 pub type Queue<T> = deque::Queue<Placement<T>, sender::SenderSync>;
 
-pub fn read_seq_num(data_dir: &Path) -> io::Result<usize> {
-    let mut max = 0;
+/// Where a Receiver should pick up reading from and how many items are
+/// waiting for it on disk before it should consult the in-memory deque.
+/// Should `offset` be the start of a block, the first `skip` items of it have
+/// already been read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Position {
+    pub seq_num: usize,
+    pub offset: u64,
+    pub skip: usize,
+    pub disk_writes_to_read: usize,
+}
+
+// Queue files are named by their sequence number. Anything else in the
+// directory -- the cursor file, say -- is not a queue file and is skipped.
+pub fn queue_files(data_dir: &Path) -> io::Result<Vec<usize>> {
+    let mut seq_nums = Vec::new();
     for directory_entry in fs::read_dir(data_dir)? {
-        let num = directory_entry?
-            .file_name()
-            .to_str()
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        max = cmp::max(num, max);
+        let name = directory_entry?.file_name();
+        if let Some(num) = name.to_str().and_then(|n| n.parse::<usize>().ok()) {
+            seq_nums.push(num);
+        }
     }
-    Ok(max)
+    seq_nums.sort();
+    Ok(seq_nums)
+}
+
+/// The instant `timeout` from now, or None should that be further off than
+/// an `Instant` can hold, in which case there's no deadline to speak of
+pub fn deadline(timeout: Duration) -> Option<Instant> {
+    Instant::now().checked_add(timeout)
+}
+
+// The size a queue file is kept to. A file is only removed once the Senders
+// have moved on from it, so under a disk quota files are kept to half the
+// quota: there's then always room for the file being written to fill up
+// while the Receiver reads through the one before it.
+pub fn queue_file_limit(max_disk_bytes: usize, max_total_disk_bytes: usize) -> usize {
+    cmp::min(
+        cmp::max(0x100_000, max_disk_bytes),
+        max_total_disk_bytes / 2,
+    )
 }
 
-pub fn read_seq_num_min(data_dir: &Path) -> io::Result<usize> {
-    let mut min = usize::max_value();
-    let mut worked = false;
+// Whether `name` names a directory inside the data directory, rather than
+// the data directory itself or one outside it.
+pub fn name_within(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+// Whether `name` is a single, ordinary path component.
+pub fn name_is_plain(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+pub fn read_seq_num(data_dir: &Path) -> io::Result<usize> {
+    Ok(queue_files(data_dir)?.into_iter().fold(0, cmp::max))
+}
+
+// The bytes taken up by the queue files in `data_dir`.
+pub fn disk_usage(data_dir: &Path) -> io::Result<usize> {
+    let mut bytes = 0;
+    for seq_num in queue_files(data_dir)? {
+        bytes += fs::metadata(data_dir.join(format!("{}", seq_num)))?.len() as usize;
+    }
+    Ok(bytes)
+}
+
+// Read back the position recorded in the cursor file. A cursor file cut
+// short or otherwise unreadable is as good as none: the Receiver starts from
+// the oldest queue file left, redelivering rather than losing items.
+pub fn read_cursor(data_dir: &Path) -> io::Result<Option<(usize, u64, usize)>> {
+    let mut fp = match fs::File::open(data_dir.join(CURSOR_FILE)) {
+        Ok(fp) => fp,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let read = fp.read_u64::<BigEndian>().and_then(|seq_num| {
+        let offset = fp.read_u64::<BigEndian>()?;
+        let skip = fp.read_u64::<BigEndian>()?;
+        Ok((seq_num as usize, offset, skip as usize))
+    });
+    Ok(read.ok())
+}
+
+// Record a Receiver's position in the cursor file. The position is written
+// to a file of its own which then replaces the cursor file, so that a crash
+// part way through leaves the last position recorded intact. The replacement
+// is synced before the rename and the directory after it, else a crash could
+// leave the cursor file renamed into place but empty.
+pub fn write_cursor(data_dir: &Path, seq_num: usize, offset: u64, skip: usize) -> io::Result<()> {
+    let mut buf = [0; 24];
+    (&mut buf[..8]).write_u64::<BigEndian>(seq_num as u64)?;
+    (&mut buf[8..16]).write_u64::<BigEndian>(offset)?;
+    (&mut buf[16..]).write_u64::<BigEndian>(skip as u64)?;
+    let replacement = data_dir.join(CURSOR_REPLACEMENT_FILE);
+    let mut fp = fs::File::create(&replacement)?;
+    fp.write_all(&buf)?;
+    fp.sync_all()?;
+    fs::rename(replacement, data_dir.join(CURSOR_FILE))?;
+    fs::File::open(data_dir)?.sync_all()
+}
+
+// Open the queue file at `path` for appending, creating it should there be
+// none. The Senders and the Receiver may both go to create the same queue
+// file, so whether this call did is returned alongside.
+pub fn open_queue_file(path: &Path) -> io::Result<(fs::File, bool)> {
+    match fs::OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(fp) => Ok((fp, true)),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(|fp| (fp, false)),
+        Err(e) => Err(e),
+    }
+}
+
+// Replace the queue file `seq_num` with an empty, read-only file, which a
+// Receiver passes straight by. The file is replaced in one go, so that a
+// Receiver never finds it missing.
+pub fn empty_queue_file(data_dir: &Path, seq_num: usize) -> io::Result<()> {
+    let replacement = data_dir.join(EVICTED_REPLACEMENT_FILE);
+    let fp = fs::File::create(&replacement)?;
+    let mut permissions = fp.metadata()?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&replacement, permissions)?;
+    fs::rename(replacement, data_dir.join(format!("{}", seq_num)))
+}
+
+pub fn write_header<W: Write>(fp: &mut W, header: Header) -> io::Result<()> {
+    let mut buf = [0; HEADER_LEN as usize];
+    buf[..4].copy_from_slice(MAGIC);
+    buf[4] = VERSION;
+    buf[5] = header.compression.tag();
+    buf[6] = header.blocks as u8 | (header.timestamps as u8) << 1;
+    fp.write_all(&buf)
+}
+
+// Read the header of a queue file. A header that's not ours, or is from a
+// version of hopper we don't know, fails with InvalidData.
+pub fn read_header<R: Read>(fp: &mut R) -> io::Result<Header> {
+    let mut buf = [0; HEADER_LEN as usize];
+    fp.read_exact(&mut buf)?;
+    if &buf[..4] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a hopper queue file",
+        ));
+    }
+    if buf[4] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown queue file version {}", buf[4]),
+        ));
+    }
+    if buf[6] > 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown record framing {}", buf[6]),
+        ));
+    }
+    Ok(Header {
+        compression: Compression::from_tag(buf[5])?,
+        blocks: buf[6] & 1 != 0,
+        timestamps: buf[6] & 2 != 0,
+    })
+}
+
+// Count the items in the complete records of `fp` from `offset` onward. A
+// record cut short -- say, by a crash in the middle of a write -- is the end
+// of the line: the file is truncated back to the last complete record.
+// Checksums are left to the Receiver. A file with a header we can't make
+// sense of is left be and has nothing in it as far as we're concerned.
+fn count_records(fp: &mut fs::File, offset: u64) -> io::Result<usize> {
+    let len = fp.metadata()?.len();
+    if len < HEADER_LEN {
+        return Ok(0);
+    }
+    fp.seek(SeekFrom::Start(0))?;
+    let header = match read_header(fp) {
+        Ok(header) => header,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut pos = cmp::min(cmp::max(offset, HEADER_LEN), len);
+    let mut records = 0;
+    fp.seek(SeekFrom::Start(pos))?;
+    let mut rdr = io::BufReader::new(&mut *fp);
+    let mut prefix = vec![0; header.prefix_len()];
+    loop {
+        match rdr.read_exact(&mut prefix) {
+            Ok(()) => {
+                let payload_len = BigEndian::read_u32(&prefix[..sender::PAYLOAD_LEN_BYTES]);
+                let items = header.items(&prefix);
+                let end = pos + prefix.len() as u64 + u64::from(payload_len);
+                if end > len {
+                    break;
+                }
+                rdr.seek(SeekFrom::Current(i64::from(payload_len)))?;
+                pos = end;
+                records += items;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    drop(rdr);
+    if pos < len {
+        fp.set_len(pos)?;
+    }
+    Ok(records)
+}
+
+// The time the newest item in the queue file at `path` was sent, going by
+// the stamps in its record prefixes, or None should the file carry no
+// timestamps or have no records. The prefixes are read up to the first
+// record cut short, past which a Receiver couldn't read either. A stamp in
+// the future counts only should its record's checksum hold, it otherwise
+// being more likely mangled than the clock having gone back.
+pub fn newest_stamp(path: &Path) -> io::Result<Option<u64>> {
+    let mut fp = fs::File::open(path)?;
+    let len = fp.metadata()?.len();
+    let header = match read_header(&mut fp) {
+        Ok(header) if header.timestamps => header,
+        Ok(_) => return Ok(None),
+        Err(ref e)
+            if e.kind() == io::ErrorKind::InvalidData
+                || e.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    let len_bytes = sender::PAYLOAD_LEN_BYTES;
+    let now = now_stamp();
+    let mut pos = HEADER_LEN;
+    let mut newest = None;
+    let mut rdr = io::BufReader::new(fp);
+    let mut prefix = vec![0; header.prefix_len()];
+    loop {
+        match rdr.read_exact(&mut prefix) {
+            Ok(()) => {
+                let payload_len = BigEndian::read_u32(&prefix[..len_bytes]);
+                let end = pos + prefix.len() as u64 + u64::from(payload_len);
+                if end > len {
+                    break;
+                }
+                let stamp = header.stamp(&prefix);
+                if stamp.is_some_and(|stamp| stamp > now) {
+                    let mut payload = vec![0; payload_len as usize];
+                    rdr.read_exact(&mut payload)?;
+                    let crc = BigEndian::read_u32(&prefix[len_bytes..2 * len_bytes]);
+                    if checksum(&prefix[2 * len_bytes..], &payload) == crc {
+                        newest = cmp::max(newest, stamp);
+                    }
+                } else {
+                    newest = cmp::max(newest, stamp);
+                    rdr.seek_relative(i64::from(payload_len))?;
+                }
+                pos = end;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(newest)
+}
+
+/// Recover the queue files left behind in `data_dir` by a previous
+/// incarnation of the channel.
+///
+/// Queue files the previous Receiver had fully read are removed and the rest
+/// are marked read-only, their complete records counted. The Receiver is to
+/// start from the returned position and the Senders from a fresh queue file
+/// after the leftovers. The second member of the return is the sequence
+/// number of each leftover queue file -- which count against
+/// `max_disk_files` -- alongside the number of items in it still to be read.
+pub fn recover_directory(data_dir: &Path) -> io::Result<(Position, Vec<(usize, usize)>)> {
+    // A replacement file left by a crash never made it into place. One left
+    // read-only, as an evicted file's is, would be in the way of the next.
     for directory_entry in fs::read_dir(data_dir)? {
-        let num = directory_entry?
-            .file_name()
-            .to_str()
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        worked = true;
-        min = cmp::min(num, min);
+        let de = directory_entry?;
+        let replacement = de.file_name().to_str().is_some_and(|n| n.ends_with(".new"));
+        if replacement && !de.file_type()?.is_dir() {
+            fs::remove_file(de.path())?;
+        }
+    }
+    let cursor = read_cursor(data_dir)?;
+    let mut position = Position::default();
+    let mut leftover = Vec::new();
+    for seq_num in queue_files(data_dir)? {
+        match cursor {
+            Some((cursor_seq, _, _)) if seq_num < cursor_seq => {
+                fs::remove_file(data_dir.join(format!("{}", seq_num)))?;
+            }
+            _ => leftover.push(seq_num),
+        }
+    }
+    if leftover.is_empty() {
+        return Ok((position, Vec::new()));
+    }
+    position.seq_num = leftover[0];
+    if let Some((cursor_seq, offset, skip)) = cursor {
+        if cursor_seq == position.seq_num {
+            position.offset = offset;
+            position.skip = skip;
+        }
     }
-    assert!(worked);
-    Ok(min)
+    let mut file_items = Vec::with_capacity(leftover.len());
+    for seq_num in &leftover {
+        let path = data_dir.join(format!("{}", seq_num));
+        let mut fp = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+        let items = if *seq_num == position.seq_num {
+            let records = count_records(&mut fp, position.offset)?;
+            let len = fp.metadata()?.len();
+            if position.offset >= len {
+                // The block we were part way through, if any, is gone.
+                position.offset = len;
+                position.skip = 0;
+            }
+            records.saturating_sub(position.skip)
+        } else {
+            count_records(&mut fp, 0)?
+        };
+        position.disk_writes_to_read += items;
+        file_items.push((*seq_num, items));
+        let mut permissions = fp.metadata()?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions)?;
+    }
+    // The Senders pick up from the highest numbered queue file. We don't want
+    // them appending to a read-only file, so make them a new one.
+    let next = leftover[leftover.len() - 1].wrapping_add(1);
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(data_dir.join(format!("{}", next)))?;
+    Ok((position, file_items))
 }
 
+// Remove the files of a channel directory. Subdirectories are none of the
+// channel's and are left be.
 pub fn clear_directory(data_dir: &Path) -> io::Result<()> {
     if data_dir.is_dir() {
         for directory_entry in fs::read_dir(data_dir)? {
             let de = directory_entry?;
-            fs::remove_file(de.path())?
+            if !de.file_type()?.is_dir() {
+                fs::remove_file(de.path())?
+            }
         }
     }
     Ok(())
 }
+
+// Whether the channel directory holds a directory of the name a queue file
+// would have -- as a priority channel's lane has -- leaving it no place for
+// a channel's queue files.
+pub fn queue_file_taken(data_dir: &Path) -> io::Result<bool> {
+    for directory_entry in fs::read_dir(data_dir)? {
+        let de = directory_entry?;
+        let numbered = de
+            .file_name()
+            .to_str()
+            .is_some_and(|n| n.parse::<usize>().is_ok());
+        if numbered && de.file_type()?.is_dir() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Wakes a thread parked waiting on several queues at once
+#[derive(Debug)]
+pub struct Unparker(pub Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
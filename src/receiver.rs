@@ -1,135 +1,778 @@
-use bincode::deserialize_from;
-use byteorder::{BigEndian, ReadBytesExt};
-use flate2::read::DeflateDecoder;
+use super::{Checkpoint, CorruptionPolicy};
+use ack::{self, AckReceiver, Ledger};
+use byteorder::{BigEndian, ByteOrder};
+use codec::{Bincode, Codec};
+use deque;
+use parking_lot::Mutex;
 use private;
-use serde::de::DeserializeOwned;
-use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use sender::PAYLOAD_LEN_BYTES;
+use shared::SharedReceiver;
+use stats::{self, Counters, Stats};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::iter::IntoIterator;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{fs, sync};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+use std::{cmp, fs, sync};
+
+/// The error returned by `Receiver::recv`
+#[derive(Debug)]
+pub enum RecvError {
+    /// Every Sender has hung up and there are no more values to be had
+    Disconnected,
+    /// Reading an item paged to disk failed
+    ///
+    /// The Receiver remains usable. Whether a later receive gets past the
+    /// failure depends on its cause: an `Error::IoError` may well be
+    /// transient, while the items behind an `Error::Corrupt` or
+    /// `Error::Deserialize` have been skipped.
+    Disk(super::Error),
+}
+
+/// The error returned by `Receiver::try_recv`
+#[derive(Debug)]
+pub enum TryRecvError {
+    /// There is no value available right now
+    Empty,
+    /// Every Sender has hung up and there are no more values to be had
+    Disconnected,
+    /// Reading an item paged to disk failed, as for `RecvError::Disk`
+    Disk(super::Error),
+}
+
+/// The error returned by `Receiver::recv_timeout` and
+/// `Receiver::recv_deadline`
+#[derive(Debug)]
+pub enum RecvTimeoutError {
+    /// No value arrived before the timeout
+    Timeout,
+    /// Every Sender has hung up and there are no more values to be had
+    Disconnected,
+    /// Reading an item paged to disk failed, as for `RecvError::Disk`
+    Disk(super::Error),
+}
 
 #[derive(Debug)]
 /// The 'receive' side of hopper, similar to
 /// [`std::sync::mpsc::Receiver`](https://doc.rust-lang.org/std/sync/mpsc/struct.Receiver.html).
-pub struct Receiver<T> {
-    root: PathBuf,           // directory we store our queues in
-    fp: BufReader<fs::File>, // active fp
+pub struct Receiver<T, C = Bincode> {
+    root: PathBuf,                   // directory we store our queues in
+    fp: BufReader<fs::File>,         // active fp
+    seq_num: usize,                  // sequence number of the active fp
+    offset: u64,                     // bytes of the active fp that have been read
+    header: Option<private::Header>, // of the active fp, once read
+    block: Vec<u8>,                  // the decompressed block being read, if any
+    block_pos: usize,                // where in `block` the next item begins
+    block_items: usize,              // items of `block` yet to be read
+    block_end: u64,                  // the offset just past the block being read
+    skip: usize,                     // items of the block at `offset` already read
+    consumed: usize,                 // items of the active fp read, or lost
+    lost: usize,                     // items lost that we're yet to learn of
+    corruption: CorruptionPolicy,
+    deferred: Option<super::Error>, // a disk read failure yet to be reported
+    checkpoint: Option<Checkpoint>, // durable channels record their position
+    ttl: Option<Duration>,          // items older than this are passed over
+    expiry_checked: bool,           // the active fp, sealed, was checked for expiry
+    unrecorded: usize,              // items read since the position was last recorded
+    recorded_at: Instant,           // when the position was last recorded
     resource_type: PhantomData<T>,
     mem_buffer: private::Queue<T>,
     disk_writes_to_read: usize,
     max_disk_files: sync::Arc<AtomicUsize>,
+    codec: C,
+    counters: sync::Arc<Counters>,
+    item_start: ack::Position, // where the item last read from disk began
+    ledger: Option<sync::Arc<Mutex<Ledger<T>>>>, // should items be acked
 }
 
-impl<T> Receiver<T>
+impl<T, C> Receiver<T, C>
 where
-    T: DeserializeOwned,
+    C: Codec<T>,
 {
     #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         data_dir: &Path,
         mem_buffer: private::Queue<T>,
         max_disk_files: sync::Arc<AtomicUsize>,
-    ) -> Result<Receiver<T>, super::Error> {
+        recovered: Option<private::Position>,
+        codec: C,
+        corruption: CorruptionPolicy,
+        checkpoint: Checkpoint,
+        ttl: Option<Duration>,
+        counters: sync::Arc<Counters>,
+    ) -> Result<Receiver<T, C>, super::Error> {
         let setup_mem_buffer = mem_buffer.clone(); // clone is cheeeeeap
         let guard = setup_mem_buffer.lock_front();
         if !data_dir.is_dir() {
             return Err(super::Error::NoSuchDirectory);
         }
-        match private::read_seq_num(data_dir) {
-            Ok(seq_num) => {
-                let log = data_dir.join(format!("{}", seq_num));
-                match fs::OpenOptions::new().read(true).open(log) {
-                    Ok(mut fp) => {
-                        fp.seek(SeekFrom::End(0))
-                            .expect("could not get to end of file");
-                        drop(guard);
-                        Ok(Receiver {
-                            root: data_dir.to_path_buf(),
-                            fp: BufReader::new(fp),
-                            resource_type: PhantomData,
-                            mem_buffer,
-                            disk_writes_to_read: 0,
-                            max_disk_files,
-                        })
+        // A durable Receiver picks up wherever the recovered position tells
+        // it to. Everyone else starts at the end of the most recent queue
+        // file, just where the Senders will begin writing.
+        let position = match recovered {
+            Some(position) => position,
+            None => match private::read_seq_num(data_dir) {
+                Ok(seq_num) => private::Position {
+                    seq_num,
+                    offset: 0,
+                    skip: 0,
+                    disk_writes_to_read: 0,
+                },
+                Err(e) => return Err(super::Error::IoError(e)),
+            },
+        };
+        let log = data_dir.join(format!("{}", position.seq_num));
+        match fs::OpenOptions::new().read(true).open(log) {
+            Ok(mut fp) => {
+                // The header of a queue file is read as soon as it's there to
+                // be read. The Senders write it before anything else, so a
+                // queue file without one is empty.
+                let header = match fp.metadata() {
+                    Ok(ref metadata) if metadata.len() >= private::HEADER_LEN => {
+                        match private::read_header(&mut fp) {
+                            Ok(header) => Some(header),
+                            // Left for read_disk_value to deal with.
+                            Err(ref e) if e.kind() == ErrorKind::InvalidData => None,
+                            Err(e) => return Err(super::Error::IoError(e)),
+                        }
                     }
-                    Err(e) => Err(super::Error::IoError(e)),
+                    Ok(_) => None,
+                    Err(e) => return Err(super::Error::IoError(e)),
+                };
+                let seek = if header.is_none() {
+                    fp.seek(SeekFrom::Start(0))
+                } else if recovered.is_some() {
+                    fp.seek(SeekFrom::Start(cmp::max(
+                        position.offset,
+                        private::HEADER_LEN,
+                    )))
+                } else {
+                    fp.seek(SeekFrom::End(0))
+                };
+                let offset = match seek {
+                    Ok(offset) => offset,
+                    Err(e) => return Err(super::Error::IoError(e)),
+                };
+                let checkpoint = if recovered.is_some() {
+                    let recorded =
+                        private::write_cursor(data_dir, position.seq_num, offset, position.skip);
+                    if let Err(e) = recorded {
+                        return Err(super::Error::IoError(e));
+                    }
+                    Some(checkpoint)
+                } else {
+                    None
+                };
+                drop(guard);
+                mem_buffer.lock_back().inner.receiver_seq_num = position.seq_num;
+                counters
+                    .receiver_seq_num
+                    .store(position.seq_num, Ordering::Relaxed);
+                counters
+                    .disk_items
+                    .fetch_add(position.disk_writes_to_read, Ordering::Relaxed);
+                Ok(Receiver {
+                    root: data_dir.to_path_buf(),
+                    fp: BufReader::new(fp),
+                    seq_num: position.seq_num,
+                    offset,
+                    header,
+                    block: Vec::new(),
+                    block_pos: 0,
+                    block_items: 0,
+                    block_end: 0,
+                    skip: position.skip,
+                    consumed: 0,
+                    lost: 0,
+                    corruption,
+                    deferred: None,
+                    checkpoint,
+                    ttl,
+                    expiry_checked: false,
+                    unrecorded: 0,
+                    recorded_at: Instant::now(),
+                    resource_type: PhantomData,
+                    mem_buffer,
+                    disk_writes_to_read: position.disk_writes_to_read,
+                    max_disk_files,
+                    codec,
+                    counters,
+                    item_start: (position.seq_num, offset, position.skip),
+                    ledger: None,
+                })
+            }
+            Err(e) => Err(super::Error::IoError(e)),
+        }
+    }
+
+    // Record our position in the queue files, should this be a durable
+    // channel. A Receiver recovered from a crash will start from here.
+    fn persist_cursor(&mut self) -> Result<(), super::Error> {
+        let position = (self.seq_num, self.offset, self.skip);
+        self.unrecorded = 0;
+        self.recorded_at = Instant::now();
+        if let Some(ref ledger) = self.ledger {
+            return ledger
+                .lock()
+                .advance(position, self.checkpoint.is_some())
+                .map_err(super::Error::IoError);
+        }
+        if self.checkpoint.is_some() {
+            if let Err(e) = private::write_cursor(&self.root, position.0, position.1, position.2) {
+                return Err(super::Error::IoError(e));
+            }
+        }
+        Ok(())
+    }
+
+    // Record our position, should it be due as per the checkpoint cadence,
+    // having read past `items` more items.
+    fn checkpoint(&mut self, items: usize) -> Result<(), super::Error> {
+        if let Some(checkpoint) = self.checkpoint {
+            self.unrecorded += items;
+            if checkpoint.due(self.unrecorded, self.recorded_at) {
+                return self.persist_cursor();
+            }
+        }
+        Ok(())
+    }
+
+    // The bounds of the next item of the block being read, alongside the
+    // time it was sent should the block carry timestamps
+    fn next_block_item(&mut self) -> io::Result<(Option<u64>, usize, usize)> {
+        let timestamps = self.header.is_some_and(|h| h.timestamps);
+        let len_end = self.block_pos + PAYLOAD_LEN_BYTES;
+        let start = if timestamps {
+            len_end + private::STAMP_LEN
+        } else {
+            len_end
+        };
+        if start > self.block.len() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "block cut short"));
+        }
+        let end = start + BigEndian::read_u32(&self.block[self.block_pos..len_end]) as usize;
+        if end > self.block.len() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "block cut short"));
+        }
+        let stamp = if timestamps {
+            Some(BigEndian::read_u64(&self.block[len_end..start]))
+        } else {
+            None
+        };
+        self.block_pos = end;
+        self.block_items -= 1;
+        Ok((stamp, start, end))
+    }
+
+    // Whether an item sent at `stamp` has outlived the channel's TTL
+    fn outlived(&self, stamp: Option<u64>) -> bool {
+        match (self.ttl, stamp) {
+            (Some(ttl), Some(stamp)) => private::expired(stamp, ttl),
+            _ => false,
+        }
+    }
+
+    // Read the next item of the block being read. None is returned should the
+    // item -- or the rest of the block -- turn out to be corrupt.
+    fn read_block_value(&mut self) -> Result<Option<T>, super::Error> {
+        let (decoded, items) = match self.next_block_item() {
+            Ok((stamp, _, _)) if self.outlived(stamp) => (Ok(None), 1),
+            Ok((_, start, end)) => (
+                self.codec
+                    .decode(&mut &self.block[start..end])
+                    .map(Some)
+                    .map_err(super::Error::Deserialize),
+                1,
+            ),
+            Err(_) => {
+                let items = self.block_items;
+                self.block_items = 0;
+                (Err(super::Error::Corrupt), items)
+            }
+        };
+        self.skip += items;
+        if self.block_items == 0 {
+            self.offset = self.block_end;
+            self.skip = 0;
+        }
+        match decoded {
+            Ok(Some(event)) => {
+                self.disk_value_read();
+                self.checkpoint(1)?;
+                Ok(Some(event))
+            }
+            Ok(None) => {
+                self.expire(1)?;
+                Ok(None)
+            }
+            Err(e) => {
+                self.corrupt(Some(items), e)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Account for `items` read past for having outlived the channel's TTL.
+    fn expire(&mut self, items: usize) -> Result<(), super::Error> {
+        self.lose(items);
+        self.counters.expired.fetch_add(items, Ordering::Relaxed);
+        self.checkpoint(items)
+    }
+
+    // Whether the active queue file is done with by the Senders and its
+    // newest item has outlived the channel's TTL. The file's age is taken
+    // from the time its records say their items were sent, not from the
+    // file's mtime, which a copy or a touch would change. The newest stamp
+    // is settled once the Senders are done with the file -- before we got to
+    // it, before a restart or while we were reading it -- so it's looked at
+    // the once.
+    fn file_expired(&mut self) -> io::Result<bool> {
+        let ttl = match self.ttl {
+            Some(ttl) if !self.expiry_checked => ttl,
+            _ => return Ok(false),
+        };
+        let metadata = self.fp.get_ref().metadata()?;
+        if !metadata.permissions().readonly() {
+            return Ok(false);
+        }
+        self.expiry_checked = true;
+        let path = self.root.join(format!("{}", self.seq_num));
+        Ok(private::newest_stamp(&path)?.is_some_and(|stamp| private::expired(stamp, ttl)))
+    }
+
+    // Account for an item read from disk.
+    fn disk_value_read(&mut self) {
+        self.disk_writes_to_read -= 1;
+        self.consumed += 1;
+        stats::decrement(&self.counters.disk_items, 1);
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Account for `items` that won't be read after all. They come off the
+    // disk writes we know of and, should there be more of them than that,
+    // off those we've yet to learn of.
+    fn lose(&mut self, items: usize) {
+        self.consumed += items;
+        stats::decrement(&self.counters.disk_items, items);
+        let known = cmp::min(items, self.disk_writes_to_read);
+        self.disk_writes_to_read -= known;
+        self.lost += items - known;
+    }
+
+    // Deal with corruption found in the active queue file as per the
+    // corruption policy. `items` is the number of items in the corrupt
+    // record, which has been read past, or None if the rest of the file is
+    // unreadable. `error` is what the corruption is reported as, should the
+    // policy be to report it.
+    fn corrupt(&mut self, items: Option<usize>, error: super::Error) -> Result<(), super::Error> {
+        match (items, self.corruption) {
+            (Some(items), CorruptionPolicy::SkipRecord)
+            | (Some(items), CorruptionPolicy::Error) => {
+                // The item count of a corrupt block can't be trusted any
+                // further than the items the Senders say are left in the file.
+                let back_guard = self.mem_buffer.lock_back();
+                let written = back_guard
+                    .inner
+                    .file_items
+                    .get(&self.seq_num)
+                    .cloned()
+                    .unwrap_or(0);
+                drop(back_guard);
+                let items = cmp::min(items, written.saturating_sub(self.consumed));
+                self.lose(items);
+                self.checkpoint(items)?;
+            }
+            _ => self.skip_file()?,
+        }
+        match self.corruption {
+            CorruptionPolicy::Error => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    // Skip whatever is left of the active queue file.
+    fn skip_file(&mut self) -> Result<(), super::Error> {
+        self.block_items = 0;
+        self.skip = 0;
+        let mut back_guard = self.mem_buffer.lock_back();
+        if back_guard.inner.sender_seq_num != self.seq_num || back_guard.inner.sender_fp.is_none() {
+            drop(back_guard);
+            return self.next_file();
+        }
+        // The Senders are still writing to the file, so we can't be done with
+        // it. We skip to the end of what they've written so far instead.
+        if let Some(ref mut fp) = back_guard.inner.sender_fp {
+            let _ = fp.flush();
+        }
+        let written = back_guard
+            .inner
+            .file_items
+            .get(&self.seq_num)
+            .cloned()
+            .unwrap_or(0);
+        self.offset = match self.fp.seek(SeekFrom::End(0)) {
+            Ok(offset) => offset,
+            Err(e) => return Err(super::Error::IoError(e)),
+        };
+        drop(back_guard);
+        let lost = written.saturating_sub(self.consumed);
+        self.lose(lost);
+        self.persist_cursor()
+    }
+
+    // Remove the active queue file, which we're done with, and move on to
+    // the next. Items written to the file that we never read are lost.
+    fn next_file(&mut self) -> Result<(), super::Error> {
+        let mut back_guard = self.mem_buffer.lock_back();
+        let written = back_guard
+            .inner
+            .file_items
+            .remove(&self.seq_num)
+            .unwrap_or(0);
+        // A file evicted by the Senders has already been counted as removed.
+        let evicted = back_guard.inner.evicted.remove(&self.seq_num);
+        // The Senders mustn't evict the file we're moving on to.
+        back_guard.inner.receiver_seq_num = self.seq_num.wrapping_add(1);
+        drop(back_guard);
+        let lost = written.saturating_sub(self.consumed);
+        self.lose(lost);
+        let old_log = self.root.join(format!("{}", self.seq_num));
+        let old_len = match self.fp.get_ref().metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(e) => return Err(super::Error::IoError(e)),
+        };
+        // Should items be acked the file is kept until they all are. An
+        // evicted file has none to keep it.
+        match self.ledger {
+            Some(ref ledger) if !evicted => ledger.lock().retire(self.seq_num, old_len),
+            _ => {
+                if let Err(e) = fs::remove_file(old_log) {
+                    return Err(super::Error::IoError(e));
+                }
+                self.counters.remove_disk_file();
+                if !evicted {
+                    self.counters.remove_disk_bytes(old_len);
+                    self.max_disk_files.fetch_add(1, Ordering::Relaxed);
                 }
+                self.mem_buffer.notify_not_full();
+            }
+        }
+        // The Senders may not have got round to creating the next queue file,
+        // should they have had to give up on this one.
+        let seq_num = self.seq_num.wrapping_add(1);
+        let lg = self.root.join(format!("{}", seq_num));
+        let counters = &self.counters;
+        let opened = private::open_queue_file(&lg).and_then(|(_, created)| {
+            if created {
+                counters.add_disk_files(1);
+            }
+            fs::OpenOptions::new().read(true).open(&lg)
+        });
+        match opened {
+            Ok(fp) => {
+                self.fp = BufReader::new(fp);
+                self.seq_num = seq_num;
+                self.counters
+                    .receiver_seq_num
+                    .store(seq_num, Ordering::Relaxed);
+                self.offset = 0;
+                self.header = None;
+                self.expiry_checked = false;
+                self.consumed = 0;
+                self.block_items = 0;
+                self.skip = 0;
+                self.persist_cursor()
             }
             Err(e) => Err(super::Error::IoError(e)),
         }
     }
 
-    // This function is _only_ called when there's disk writes to be read. If a
-    // disk read happens and no `T` is returned this is an unrecoverable error.
-    fn read_disk_value(&mut self) -> Result<T, super::Error> {
+    // This function is _only_ called when there's disk writes to be read.
+    // Should items turn out to be lost to corruption, and with them all the
+    // disk writes there were to read, None is returned.
+    fn read_disk_value(&mut self) -> Result<Option<T>, super::Error> {
         loop {
-            match self.fp.read_u32::<BigEndian>() {
-                Ok(payload_size_in_bytes) => {
-                    let mut payload_buf = vec![0; payload_size_in_bytes as usize];
-                    match self.fp.read_exact(&mut payload_buf[..]) {
-                        Ok(()) => {
-                            let mut dec = DeflateDecoder::new(&payload_buf[..]);
-                            match deserialize_from(&mut dec) {
-                                Ok(event) => {
-                                    self.disk_writes_to_read -= 1;
-                                    return Ok(event);
-                                }
-                                Err(e) => panic!("Failed decoding. Skipping {:?}", e),
-                            }
-                        }
-                        Err(e) => {
-                            panic!(
-                                "Error, on-disk payload of advertised size not available! \
-                                 Recv failed with error {:?}",
-                                e
-                            );
-                        }
+            if self.disk_writes_to_read == 0 {
+                return Ok(None);
+            }
+            // Items left in the block being read come first.
+            if self.block_items > 0 {
+                self.item_start = (self.seq_num, self.offset, self.skip);
+                match self.read_block_value()? {
+                    Some(event) => return Ok(Some(event)),
+                    None => continue,
+                }
+            }
+            // Should the whole of the active queue file have expired, what's
+            // left of it is passed over unread.
+            match self.file_expired() {
+                Ok(true) => {
+                    let written = self
+                        .mem_buffer
+                        .lock_back()
+                        .inner
+                        .file_items
+                        .get(&self.seq_num)
+                        .cloned()
+                        .unwrap_or(0);
+                    let expired = written.saturating_sub(self.consumed);
+                    self.counters.expired.fetch_add(expired, Ordering::Relaxed);
+                    self.next_file()?;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => return Err(super::Error::IoError(e)),
+            }
+            let header = match self.header {
+                Some(header) => header,
+                None => match private::read_header(&mut self.fp) {
+                    Ok(header) => {
+                        self.header = Some(header);
+                        self.offset = private::HEADER_LEN;
+                        header
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        self.end_of_file()?;
+                        continue;
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                        self.corrupt(None, super::Error::Corrupt)?;
+                        continue;
                     }
+                    Err(e) => return Err(super::Error::IoError(e)),
+                },
+            };
+            self.item_start = (self.seq_num, self.offset, 0);
+            let mut prefix = vec![0; header.prefix_len()];
+            match private::read_full(&mut self.fp, &mut prefix) {
+                Ok(0) => {
+                    self.end_of_file()?;
+                    continue;
+                }
+                Ok(read) if read < prefix.len() => {
+                    // A record cut short.
+                    self.corrupt(None, super::Error::Corrupt)?;
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => return Err(super::Error::IoError(e)),
+            }
+            let payload_size_in_bytes = BigEndian::read_u32(&prefix[..PAYLOAD_LEN_BYTES]);
+            let crc = BigEndian::read_u32(&prefix[PAYLOAD_LEN_BYTES..2 * PAYLOAD_LEN_BYTES]);
+            let items = header.items(&prefix);
+            let record_end = self.offset + (prefix.len() as u64) + u64::from(payload_size_in_bytes);
+            // Check the advertised payload is there before making room for it,
+            // as a corrupt length could be anything.
+            let file_len = match self.fp.get_ref().metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(e) => return Err(super::Error::IoError(e)),
+            };
+            if record_end > file_len {
+                self.corrupt(None, super::Error::Corrupt)?;
+                continue;
+            }
+            let mut payload_buf = vec![0; payload_size_in_bytes as usize];
+            match private::read_full(&mut self.fp, &mut payload_buf) {
+                Ok(read) if read < payload_buf.len() => {
+                    self.corrupt(None, super::Error::Corrupt)?;
+                    continue;
                 }
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::UnexpectedEof => {
-                            // Okay, we're pretty sure that no one snuck data in
-                            // on us. We check the metadata condition of the
-                            // file and, if we find it read-only, switch on over
-                            // to a new log file.
-                            let metadata = self
-                                .fp
-                                .get_ref()
-                                .metadata()
-                                .expect("could not get metadata at UnexpectedEof");
-                            if metadata.permissions().readonly() {
-                                match private::read_seq_num_min(&self.root) {
-                                    Ok(seq_num) => {
-                                        let old_log = self.root.join(format!("{}", seq_num));
-                                        fs::remove_file(old_log).expect("could not remove log");
-                                        self.max_disk_files.fetch_add(1, Ordering::Relaxed);
-                                        let lg =
-                                            self.root.join(format!("{}", seq_num.wrapping_add(1)));
-                                        match fs::OpenOptions::new().read(true).open(&lg) {
-                                            Ok(fp) => {
-                                                self.fp = BufReader::new(fp);
-                                                continue;
-                                            }
-                                            Err(e) => return Err(super::Error::IoError(e)),
-                                        }
-                                    }
-                                    Err(e) => {
-                                        return Err(super::Error::IoError(e));
-                                    }
-                                }
-                            }
+                Ok(_) => {}
+                Err(e) => return Err(super::Error::IoError(e)),
+            }
+            if private::checksum(&prefix[2 * PAYLOAD_LEN_BYTES..], &payload_buf) != crc {
+                self.offset = record_end;
+                self.corrupt(Some(items), super::Error::Corrupt)?;
+                continue;
+            }
+            // A record whose newest item has expired is passed over whole.
+            if self.outlived(header.stamp(&prefix)) {
+                self.offset = record_end;
+                let unread = items.saturating_sub(self.skip);
+                self.skip = 0;
+                self.expire(unread)?;
+                continue;
+            }
+            if header.blocks {
+                // A block is decompressed whole and its items handed out one
+                // at a time. Those read before a restart are passed over.
+                self.block.clear();
+                let skipped = header
+                    .compression
+                    .decoder(&payload_buf[..])
+                    .and_then(|mut dec| dec.read_to_end(&mut self.block))
+                    .and_then(|_| {
+                        self.block_pos = 0;
+                        self.block_items = items;
+                        self.block_end = record_end;
+                        for _ in 0..cmp::min(self.skip, items) {
+                            self.next_block_item()?;
                         }
-                        _ => return Err(super::Error::IoError(e)),
-                    }
+                        Ok(())
+                    });
+                if skipped.is_err() {
+                    self.block_items = 0;
+                    self.offset = record_end;
+                    let unread = items.saturating_sub(self.skip);
+                    self.skip = 0;
+                    self.corrupt(Some(unread), super::Error::Corrupt)?;
+                    continue;
+                }
+                if self.block_items == 0 {
+                    self.offset = self.block_end;
+                    self.skip = 0;
                 }
+                continue;
+            }
+            self.offset = record_end;
+            let decoded = match header.compression.decoder(&payload_buf[..]) {
+                Ok(mut dec) => self
+                    .codec
+                    .decode(&mut dec)
+                    .map_err(super::Error::Deserialize),
+                Err(_) => Err(super::Error::Corrupt),
+            };
+            match decoded {
+                Ok(event) => {
+                    self.disk_value_read();
+                    self.checkpoint(1)?;
+                    return Ok(Some(event));
+                }
+                Err(e) => self.corrupt(Some(1), e)?,
+            }
+        }
+    }
+
+    // We've hit the end of the active queue file with disk writes still to
+    // read. Should the file be read-only the Senders are done with it and we
+    // move on to the next. Otherwise the Senders are yet to write what we're
+    // after.
+    fn end_of_file(&mut self) -> Result<(), super::Error> {
+        let metadata = match self.fp.get_ref().metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => return Err(super::Error::IoError(e)),
+        };
+        if metadata.permissions().readonly() {
+            self.next_file()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Take items lost that we were yet to learn of off the `disk_writes`
+    // we've just learned of.
+    fn discount_lost(&mut self, disk_writes: usize) -> usize {
+        let lost = cmp::min(self.lost, disk_writes);
+        self.lost -= lost;
+        disk_writes - lost
+    }
+
+    // Pop the next placement off the in-memory deque, waiting no later than
+    // `deadline` if there is one. A memory placement is returned straight
+    // away. A disk placement -- or disk writes left stranded by Senders that
+    // have hung up -- primes the disk state machine and None is returned, as
+    // it is for an expired item.
+    pub(crate) fn pop_memory(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<T>, RecvTimeoutError> {
+        let popped = match deadline {
+            None => self
+                .mem_buffer
+                .pop_front()
+                .ok_or(deque::PopError::Disconnected),
+            Some(deadline) => self.mem_buffer.pop_front_until(deadline),
+        };
+        match popped {
+            Ok(placement) => {
+                let ev = self.placed(placement);
+                self.mem_buffer.notify_not_full();
+                Ok(ev)
             }
+            Err(deque::PopError::Empty) => Err(RecvTimeoutError::Timeout),
+            Err(deque::PopError::Disconnected) => self.stranded().map(|()| None),
         }
     }
 
-    fn next_value(&mut self) -> Option<T> {
+    // Take delivery of a placement popped off the in-memory deque. A memory
+    // placement's item is returned, unless it has expired. A disk placement
+    // primes the disk state machine. The caller is to notify the Senders of
+    // the room made.
+    fn placed(&mut self, placement: private::Placement<T>) -> Option<T> {
+        match placement {
+            private::Placement::Memory(ev, weight, sent_at) => {
+                self.counters.release_memory(weight);
+                let outlived = match (self.ttl, sent_at) {
+                    (Some(ttl), Some(sent_at)) => sent_at.elapsed() > ttl,
+                    _ => false,
+                };
+                if outlived {
+                    self.counters.expired.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                self.counters.received.fetch_add(1, Ordering::Relaxed);
+                Some(ev)
+            }
+            private::Placement::Disk(sz) => {
+                self.disk_writes_to_read = self.discount_lost(sz);
+                None
+            }
+        }
+    }
+
+    // Every Sender has hung up. Prime the disk state machine with whatever
+    // disk writes they never announced or, should there be none, report the
+    // disconnection.
+    fn stranded(&mut self) -> Result<(), RecvTimeoutError> {
+        let mut back_guard = self.mem_buffer.lock_back();
+        let stranded = back_guard.inner.total_disk_writes;
+        back_guard.inner.total_disk_writes = 0;
+        drop(back_guard);
+        let stranded = self.discount_lost(stranded);
+        if stranded == 0 {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+        self.disk_writes_to_read = stranded;
+        Ok(())
+    }
+
+    // The number of items the Receiver knows to be waiting for it on disk.
+    #[cfg(feature = "futures")]
+    pub(crate) fn disk_writes_to_read(&self) -> usize {
+        self.disk_writes_to_read
+    }
+
+    // Read up to `max` of the items waiting on disk. Should a read fail the
+    // items read up till then are returned alongside the error.
+    pub(crate) fn read_disk_values(&mut self, max: usize) -> (Vec<T>, Option<super::Error>) {
+        let mut values = Vec::with_capacity(cmp::min(max, self.disk_writes_to_read));
+        while values.len() < max && self.disk_writes_to_read > 0 {
+            match self.read_disk_value() {
+                Ok(Some(ev)) => values.push(ev),
+                Ok(None) => {}
+                Err(e) => return (values, Some(e)),
+            }
+        }
+        (values, None)
+    }
+
+    pub(crate) fn register_waker(&self, waker: &Waker) {
+        self.mem_buffer.register_waker(waker)
+    }
+
+    pub(crate) fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        self.recv_from(deadline).map(|(ev, _)| ev)
+    }
+
+    // Receive a value alongside where in the queue files it was read from,
+    // should it have been read from disk.
+    pub(crate) fn recv_from(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<(T, Option<ack::Position>), RecvTimeoutError> {
         // The receive loop
         //
         // The receiver is two interlocked state machines. The in-memory state
@@ -141,80 +784,264 @@ where
         // will suffer transient failures -- think file-descriptor exhaustion --
         // and so we only move out of disk back to memory state machine when the
         // counter is fully exhausted.
+        //
+        // Once every Sender has hung up `pop_front` stops blocking. Whatever
+        // the Senders wrote to disk but never managed to announce with a disk
+        // placement is still to be read, after which we're done for good.
+        //
+        // If there's a deadline we only wait on the in-memory deque until
+        // then. Disk reads are never waited on and so are never cut short.
+        //
+        // A failed disk read is handed back to the caller. The disk state
+        // machine is left as it was, so the next receive takes up where the
+        // failed one left off.
+        if let Some(e) = self.deferred.take() {
+            return Err(RecvTimeoutError::Disk(e));
+        }
         loop {
             if self.disk_writes_to_read == 0 {
-                match self.mem_buffer.pop_front() {
-                    private::Placement::Memory(ev) => {
-                        return Some(ev);
-                    }
-                    private::Placement::Disk(sz) => {
-                        self.disk_writes_to_read = sz;
-                        continue;
-                    }
+                if let Some(ev) = self.pop_memory(deadline)? {
+                    return Ok((ev, None));
                 }
             } else {
                 match self.read_disk_value() {
-                    Ok(ev) => return Some(ev),
-                    Err(_) => return None,
+                    Ok(Some(ev)) => return Ok((ev, Some(self.item_start))),
+                    Ok(None) => {}
+                    Err(e) => return Err(RecvTimeoutError::Disk(e)),
+                }
+            }
+        }
+    }
+
+    /// Receive a value, blocking until one is available
+    ///
+    /// This function will block waiting for a value in memory or on disk. If
+    /// every Sender has hung up and the channel is drained
+    /// `RecvError::Disconnected` is returned. Should reading an item from disk
+    /// fail the underlying error is returned as `RecvError::Disk`.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        match self.recv_until(None) {
+            Ok(ev) => Ok(ev),
+            Err(RecvTimeoutError::Disk(e)) => Err(RecvError::Disk(e)),
+            // Without a deadline there's no timing out.
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                Err(RecvError::Disconnected)
+            }
+        }
+    }
+
+    /// Receive up to `max` values, blocking for no longer than `timeout` for
+    /// the first
+    ///
+    /// Values held in memory are taken many at a time, under one acquisition
+    /// of the in-memory deque's lock. Once a value has been received no more
+    /// waiting is done and what's to hand is returned. Errors are as for
+    /// `recv_timeout`. Should a disk read fail once values have been received
+    /// they are returned and the failure reported by the next receive.
+    pub fn recv_batch(
+        &mut self,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<T>, RecvTimeoutError> {
+        if let Some(e) = self.deferred.take() {
+            return Err(RecvTimeoutError::Disk(e));
+        }
+        let deadline = private::deadline(timeout);
+        let mut batch = Vec::new();
+        let mut placements = Vec::new();
+        while batch.len() < max {
+            if self.disk_writes_to_read > 0 {
+                let (values, err) = self.read_disk_values(max - batch.len());
+                batch.extend(values);
+                if let Some(e) = err {
+                    if batch.is_empty() {
+                        return Err(RecvTimeoutError::Disk(e));
+                    }
+                    self.deferred = Some(e);
+                    break;
+                }
+                continue;
+            }
+            // Only the first value is waited for. Popping stops at a disk
+            // placement, the items behind it coming after the disk writes it
+            // announces.
+            let wait_until = if batch.is_empty() {
+                deadline
+            } else {
+                Some(Instant::now())
+            };
+            let popped = self.mem_buffer.pop_front_batch(
+                max - batch.len(),
+                wait_until,
+                &mut placements,
+                |placement| match *placement {
+                    private::Placement::Disk(_) => true,
+                    private::Placement::Memory(..) => false,
+                },
+            );
+            let outcome = match popped {
+                Ok(()) => Ok(()),
+                Err(deque::PopError::Empty) => Err(RecvTimeoutError::Timeout),
+                Err(deque::PopError::Disconnected) => self.stranded(),
+            };
+            if !placements.is_empty() {
+                for placement in placements.drain(..) {
+                    batch.extend(self.placed(placement));
+                }
+                self.mem_buffer.notify_not_full();
+            }
+            if let Err(e) = outcome {
+                if batch.is_empty() {
+                    return Err(e);
                 }
+                break;
             }
         }
+        Ok(batch)
+    }
+
+    /// Attempt to receive a value without blocking
+    ///
+    /// This function will return a value if one is available in memory or on
+    /// disk. Otherwise `TryRecvError::Empty` is returned or, if every Sender
+    /// has hung up and the channel is drained, `TryRecvError::Disconnected`.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.recv_until(Some(Instant::now())) {
+            Ok(ev) => Ok(ev),
+            Err(RecvTimeoutError::Timeout) => Err(TryRecvError::Empty),
+            Err(RecvTimeoutError::Disconnected) => Err(TryRecvError::Disconnected),
+            Err(RecvTimeoutError::Disk(e)) => Err(TryRecvError::Disk(e)),
+        }
+    }
+
+    /// Receive a value, blocking for no longer than `timeout`
+    ///
+    /// This function will block waiting for a value until `timeout` has
+    /// elapsed, failing then with `RecvTimeoutError::Timeout`. If every Sender
+    /// has hung up and the channel is drained `RecvTimeoutError::Disconnected`
+    /// is returned.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(private::deadline(timeout))
+    }
+
+    /// Receive a value, blocking no later than `deadline`
+    ///
+    /// This function behaves as `recv_timeout` does but with an absolute
+    /// deadline, handy when waiting in a loop.
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Some(deadline))
+    }
+
+    /// Return a snapshot of the channel's counters
+    ///
+    /// The snapshot is the same whether taken from a Sender or the Receiver.
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot(&self.mem_buffer)
+    }
+
+    /// Turn this Receiver into one handing out items to be acknowledged
+    ///
+    /// An item not acked within `ack_timeout` of being handed out is
+    /// delivered again. See `AckReceiver`.
+    pub fn into_acked(mut self, ack_timeout: Duration) -> AckReceiver<T, C>
+    where
+        T: Clone,
+    {
+        let ledger = sync::Arc::new(Mutex::new(Ledger::new(
+            self.root.clone(),
+            self.mem_buffer.clone(),
+            sync::Arc::clone(&self.max_disk_files),
+            sync::Arc::clone(&self.counters),
+            self.checkpoint.is_some(),
+            (self.seq_num, self.offset, self.skip),
+        )));
+        self.ledger = Some(sync::Arc::clone(&ledger));
+        AckReceiver::new(self, ledger, ack_timeout)
+    }
+
+    /// Turn this Receiver into one that several consumers can share
+    pub fn into_shared(self) -> SharedReceiver<T, C> {
+        SharedReceiver::from(self)
+    }
+
+    // What a SharedReceiver needs to take a snapshot of the channel's
+    // counters without holding the Receiver.
+    pub(crate) fn shared_parts(&self) -> (private::Queue<T>, sync::Arc<Counters>) {
+        (self.mem_buffer.clone(), sync::Arc::clone(&self.counters))
     }
 
     /// An iterator over messages on a receiver, this iterator will block
     /// whenever `next` is called, waiting for a new message, and `None` will be
     /// returned when the corresponding channel has hung up.
-    pub fn iter(&mut self) -> Iter<T> {
+    ///
+    /// The iterator calls `recv` and ends, too, should reading an item from
+    /// disk fail. Use `recv` directly to learn of such failures.
+    pub fn iter(&mut self) -> Iter<'_, T, C> {
         Iter { rx: self }
     }
 }
 
+impl<T, C> Drop for Receiver<T, C> {
+    fn drop(&mut self) {
+        if self.checkpoint.is_some_and(|c| c.on_drop) && self.unrecorded > 0 {
+            let position = (self.seq_num, self.offset, self.skip);
+            let _ = match self.ledger {
+                Some(ref ledger) => ledger.lock().advance(position, true),
+                None => private::write_cursor(&self.root, position.0, position.1, position.2),
+            };
+        }
+        // Senders waiting for room would otherwise wait forever.
+        self.mem_buffer.deregister_receiver();
+    }
+}
+
 #[derive(Debug)]
-pub struct Iter<'a, T>
+pub struct Iter<'a, T, C = Bincode>
 where
-    T: 'a + DeserializeOwned,
+    T: 'a,
+    C: 'a + Codec<T>,
 {
-    rx: &'a mut Receiver<T>,
+    rx: &'a mut Receiver<T, C>,
 }
 
 #[derive(Debug)]
-pub struct IntoIter<T>
+pub struct IntoIter<T, C = Bincode>
 where
-    T: DeserializeOwned,
+    C: Codec<T>,
 {
-    rx: Receiver<T>,
+    rx: Receiver<T, C>,
 }
 
-impl<T> IntoIterator for Receiver<T>
+impl<T, C> IntoIterator for Receiver<T, C>
 where
-    T: DeserializeOwned,
+    C: Codec<T>,
 {
     type Item = T;
-    type IntoIter = IntoIter<T>;
+    type IntoIter = IntoIter<T, C>;
 
-    fn into_iter(self) -> IntoIter<T> {
+    fn into_iter(self) -> IntoIter<T, C> {
         IntoIter { rx: self }
     }
 }
 
-impl<'a, T> Iterator for Iter<'a, T>
+impl<'a, T, C> Iterator for Iter<'a, T, C>
 where
-    T: DeserializeOwned,
+    C: Codec<T>,
 {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        self.rx.next_value()
+        self.rx.recv().ok()
     }
 }
 
-impl<T> Iterator for IntoIter<T>
+impl<T, C> Iterator for IntoIter<T, C>
 where
-    T: DeserializeOwned,
+    C: Codec<T>,
 {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        self.rx.next_value()
+        self.rx.recv().ok()
     }
 }
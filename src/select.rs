@@ -0,0 +1,182 @@
+//! Waiting on several Receivers at once
+//!
+//! A Receiver blocks waiting on its own channel alone. A `Select` holds any
+//! number of Receivers and hands out whichever item turns up first, so that a
+//! single thread can consume many channels. Each Receiver's deque is told to
+//! wake the selecting thread should an item land in it, rather than the
+//! thread waiting on each deque in turn.
+use codec::{Bincode, Codec};
+use private::{self, Unparker};
+use receiver::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A set of Receivers, received from as one
+///
+/// Receivers are added with `Select::add`, which returns the index items
+/// received from that Receiver are tagged with. Receivers are taken in turn,
+/// so that a busy channel does not starve the others.
+#[derive(Debug)]
+pub struct Select<T, C = Bincode> {
+    receivers: Vec<Option<Receiver<T, C>>>,
+    next: usize,           // the index to look at first
+    failed: Option<usize>, // the index of the last disk failure reported
+}
+
+impl<T, C> Default for Select<T, C>
+where
+    C: Codec<T>,
+{
+    fn default() -> Select<T, C> {
+        Select::new()
+    }
+}
+
+impl<T, C> Select<T, C>
+where
+    C: Codec<T>,
+{
+    /// Create an empty Select
+    pub fn new() -> Select<T, C> {
+        Select {
+            receivers: Vec::new(),
+            next: 0,
+            failed: None,
+        }
+    }
+
+    /// Add a Receiver, returning its index
+    pub fn add(&mut self, receiver: Receiver<T, C>) -> usize {
+        self.receivers.push(Some(receiver));
+        self.receivers.len() - 1
+    }
+
+    /// Remove the Receiver at `index`, should there be one
+    ///
+    /// The indices of the other Receivers are left as they are.
+    pub fn remove(&mut self, index: usize) -> Option<Receiver<T, C>> {
+        self.receivers.get_mut(index).and_then(Option::take)
+    }
+
+    /// The Receiver at `index`, should there be one
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Receiver<T, C>> {
+        self.receivers.get_mut(index).and_then(Option::as_mut)
+    }
+
+    /// The number of Receivers held
+    pub fn len(&self) -> usize {
+        self.receivers.iter().filter(|rx| rx.is_some()).count()
+    }
+
+    /// Whether no Receivers are held
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The index of the Receiver whose disk failure was last returned
+    ///
+    /// A Receiver that has failed goes on reporting its failure. It is up to
+    /// the caller to `remove` it, or to carry on regardless.
+    pub fn failed(&self) -> Option<usize> {
+        self.failed
+    }
+
+    // Take an item from the first Receiver with one, starting from the one
+    // after that last received from.
+    fn take(&mut self) -> Result<(usize, T), TryRecvError> {
+        let total = self.receivers.len();
+        let mut disconnected = true;
+        for i in 0..total {
+            let index = (self.next + i) % total;
+            let receiver = match self.receivers[index] {
+                Some(ref mut receiver) => receiver,
+                None => continue,
+            };
+            match receiver.try_recv() {
+                Ok(event) => {
+                    self.next = index + 1;
+                    return Ok((index, event));
+                }
+                Err(TryRecvError::Empty) => disconnected = false,
+                Err(TryRecvError::Disconnected) => {}
+                Err(TryRecvError::Disk(e)) => {
+                    self.next = index + 1;
+                    self.failed = Some(index);
+                    return Err(TryRecvError::Disk(e));
+                }
+            }
+        }
+        if disconnected {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<(usize, T), RecvTimeoutError> {
+        // The Waker is registered before looking a last time, so that an item
+        // landing between the look and the park is sure to wake us.
+        let waker = Waker::from(Arc::new(Unparker(thread::current())));
+        loop {
+            match self.take() {
+                Ok(selected) => return Ok(selected),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Disk(e)) => return Err(RecvTimeoutError::Disk(e)),
+            }
+            for receiver in self.receivers.iter().flatten() {
+                receiver.register_waker(&waker);
+            }
+            match self.take() {
+                Ok(selected) => return Ok(selected),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Disk(e)) => return Err(RecvTimeoutError::Disk(e)),
+            }
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+
+    /// Receive a value from whichever Receiver has one first, blocking until
+    /// one is available
+    ///
+    /// The value is returned alongside the index of its Receiver.
+    /// `RecvError::Disconnected` is returned once every Receiver is hung up
+    /// on and drained, or there are none. A disk failure is returned as
+    /// `RecvError::Disk`, see `Select::failed`.
+    pub fn recv(&mut self) -> Result<(usize, T), RecvError> {
+        match self.recv_until(None) {
+            Ok(selected) => Ok(selected),
+            Err(RecvTimeoutError::Disk(e)) => Err(RecvError::Disk(e)),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                Err(RecvError::Disconnected)
+            }
+        }
+    }
+
+    /// Attempt to receive a value from any of the Receivers without blocking
+    ///
+    /// Errors are as for `recv` and `Receiver::try_recv`.
+    pub fn try_recv(&mut self) -> Result<(usize, T), TryRecvError> {
+        self.take()
+    }
+
+    /// Receive a value from whichever Receiver has one first, blocking for no
+    /// longer than `timeout`
+    ///
+    /// Errors are as for `recv` and `Receiver::recv_timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<(usize, T), RecvTimeoutError> {
+        self.recv_until(private::deadline(timeout))
+    }
+}
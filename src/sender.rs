@@ -1,30 +1,43 @@
-use bincode::serialize_into;
-use byteorder::{BigEndian, WriteBytesExt};
+use super::{FsyncPolicy, OverflowPolicy};
+use byteorder::{BigEndian, ByteOrder};
+use codec::{Bincode, Codec};
+use compression::{Block, Compression};
 use deque;
 use deque::BackGuardInner;
-use flate2::write::DeflateEncoder;
-use flate2::Compression;
 use parking_lot::MutexGuard;
 use private;
-use serde::{Deserialize, Serialize};
+use stats::{Counters, Stats};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Write};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-const PAYLOAD_LEN_BYTES: usize = ::std::mem::size_of::<u32>();
+pub const PAYLOAD_LEN_BYTES: usize = ::std::mem::size_of::<u32>();
 
 #[derive(Debug)]
 /// The 'send' side of hopper, similar to `std::sync::mpsc::Sender`.
-pub struct Sender<T> {
+pub struct Sender<T, C = Bincode> {
     name: String,
     root: PathBuf, // directory we store our queues in
     max_disk_bytes: usize,
+    max_total_disk_bytes: usize,
     mem_buffer: private::Queue<T>,
     resource_type: PhantomData<T>,
     disk_files_capacity: Arc<AtomicUsize>,
+    codec: C,
+    compression: Compression,
+    block: Option<Block>,
+    overflow: OverflowPolicy,
+    fsync: FsyncPolicy,
+    ttl: Option<Duration>, // items are timestamped should the channel have one
+    memory_budget: Option<usize>, // bytes, should memory be accounted in bytes
+    counters: Arc<Counters>,
+    flusher: bool, // not one of the channel's Senders, see `detached`
 }
 
 #[derive(Default, Debug)]
@@ -33,37 +46,103 @@ pub struct SenderSync {
     pub bytes_written: usize,
     pub sender_seq_num: usize,
     pub total_disk_writes: usize,
-    pub path: PathBuf, // active fp filename
+    pub path: PathBuf,  // active fp filename
+    pub block: Vec<u8>, // items not yet written out, should blocks be in use
+    pub block_items: usize,
+    pub block_stamp: u64, // the time the newest item of the block was sent
+    // The queue file the Receiver is reading, or about to. The files after it
+    // and before the one being written may be evicted to make room.
+    pub receiver_seq_num: usize,
+    // Queue files evicted that the Receiver has yet to pass
+    pub evicted: HashSet<usize>,
+    // The number of items written to each queue file, kept until the
+    // Receiver is done with the file. Should the Receiver find a file
+    // corrupt it learns from this how many items it has lost.
+    pub file_items: HashMap<usize, usize>,
 }
 
-impl<'de, T> Clone for Sender<T>
+impl<T, C> Clone for Sender<T, C>
 where
-    T: Serialize + Deserialize<'de>,
+    C: Codec<T>,
 {
-    fn clone(&self) -> Sender<T> {
+    fn clone(&self) -> Sender<T, C> {
+        self.mem_buffer.register_sender();
         Sender {
             name: self.name.clone(),
             root: self.root.clone(),
             max_disk_bytes: self.max_disk_bytes,
+            max_total_disk_bytes: self.max_total_disk_bytes,
             mem_buffer: self.mem_buffer.clone(),
             resource_type: self.resource_type,
             disk_files_capacity: Arc::clone(&self.disk_files_capacity),
+            codec: self.codec.clone(),
+            compression: self.compression,
+            block: self.block,
+            overflow: self.overflow,
+            fsync: self.fsync,
+            ttl: self.ttl,
+            memory_budget: self.memory_budget,
+            counters: Arc::clone(&self.counters),
+            flusher: false,
         }
     }
 }
 
-impl<T> Sender<T>
+impl<T, C> Drop for Sender<T, C> {
+    fn drop(&mut self) {
+        if self.flusher {
+            return;
+        }
+        // The last Sender out makes sure everything it and its siblings wrote
+        // to disk is actually there. Any disk writes it could not announce to
+        // the Receiver are left in total_disk_writes, where the Receiver will
+        // find them once it has drained the in-memory deque. The back lock is
+        // held while the Sender deregisters so the Receiver cannot go looking
+        // before the flush is done.
+        let mut back_guard = self.mem_buffer.lock_back();
+        let last_sender = self.mem_buffer.deregister_sender();
+        if last_sender {
+            // A block that can't be written out now never will be. Its items
+            // are lost and the Receiver must not go looking for them.
+            if self.seal_block(&mut back_guard).is_err() {
+                back_guard.inner.total_disk_writes -= back_guard.inner.block_items;
+                back_guard.inner.block.clear();
+                back_guard.inner.block_items = 0;
+                back_guard.inner.block_stamp = 0;
+            }
+            let _ = self.flush_file(&mut back_guard);
+        }
+        drop(back_guard);
+        if last_sender {
+            let front_guard = self.mem_buffer.lock_front();
+            self.mem_buffer.notify_not_empty(&front_guard);
+            drop(front_guard);
+        }
+    }
+}
+
+impl<T, C> Sender<T, C>
 where
-    T: Serialize,
+    C: Codec<T>,
 {
     #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
     pub fn new<S>(
         name: S,
         data_dir: &Path,
         max_disk_bytes: usize,
+        max_total_disk_bytes: usize,
         mem_buffer: private::Queue<T>,
         max_disk_files: Arc<AtomicUsize>,
-    ) -> Result<Sender<T>, super::Error>
+        codec: C,
+        compression: Compression,
+        block: Option<Block>,
+        overflow: OverflowPolicy,
+        fsync: FsyncPolicy,
+        ttl: Option<Duration>,
+        memory_budget: Option<usize>,
+        counters: Arc<Counters>,
+    ) -> Result<Sender<T, C>, super::Error>
     where
         S: Into<String>,
     {
@@ -75,18 +154,48 @@ where
         match private::read_seq_num(data_dir) {
             Ok(seq_num) => {
                 let log = data_dir.join(format!("{}", seq_num));
-                match fs::OpenOptions::new().append(true).create(true).open(&log) {
+                // A queue file new to us gets its header straight away, as
+                // the Receiver may well open it before we next flush.
+                let opened = private::open_queue_file(&log).and_then(|(mut fp, created)| {
+                    if created {
+                        counters.add_disk_files(1);
+                    }
+                    if fp.metadata()?.len() == 0 {
+                        let header = private::Header {
+                            compression,
+                            blocks: block.is_some(),
+                            timestamps: ttl.is_some(),
+                        };
+                        private::write_header(&mut fp, header)?;
+                        guard.inner.bytes_written = private::HEADER_LEN as usize;
+                        counters.add_disk_bytes(private::HEADER_LEN as usize);
+                    }
+                    Ok(fp)
+                });
+                match opened {
                     Ok(fp) => {
-                        (*guard).inner.sender_fp = Some(BufWriter::new(fp));
-                        (*guard).inner.sender_seq_num = seq_num;
-                        (*guard).inner.path = log;
+                        guard.inner.sender_fp = Some(BufWriter::new(fp));
+                        guard.inner.sender_seq_num = seq_num;
+                        guard.inner.path = log;
+                        counters.sender_seq_num.store(seq_num, Ordering::Relaxed);
+                        mem_buffer.register_sender();
                         Ok(Sender {
                             name: name.into(),
                             root: data_dir.to_path_buf(),
                             max_disk_bytes,
+                            max_total_disk_bytes,
                             mem_buffer,
                             resource_type: PhantomData,
                             disk_files_capacity: max_disk_files,
+                            codec,
+                            compression,
+                            block,
+                            overflow,
+                            fsync,
+                            ttl,
+                            memory_budget,
+                            counters,
+                            flusher: false,
                         })
                     }
                     Err(e) => Err(super::Error::IoError(e)),
@@ -98,72 +207,68 @@ where
 
     fn write_to_disk(
         &self,
-        event: T,
+        event: &T,
+        seal: bool,
         guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
-    ) -> Result<(), (T, super::Error)> {
-        let mut buf: Vec<u8> = Vec::with_capacity(64);
-        let mut e = DeflateEncoder::new(buf, Compression::fast());
-        serialize_into(&mut e, &event).expect("could not serialize");
-        buf = e.finish().unwrap();
-        let payload_len = buf.len();
-        // If the individual sender writes enough to go over the max we mark the
-        // file read-only--which will help the receiver to decide it has hit the
-        // end of its log file--and create a new log file.
-        let bytes_written = (*guard).inner.bytes_written + payload_len + PAYLOAD_LEN_BYTES;
-        if (bytes_written > self.max_disk_bytes) || (*guard).inner.sender_fp.is_none() {
-            // Once we've gone over the write limit for our current file or find
-            // that we've gotten behind the current queue file we need to seek
-            // forward to find our place in the space of queue files. We mark
-            // our current file read-only and then bump sender_seq_num to get up
-            // to date.
-            let _ = fs::metadata(&(*guard).inner.path).map(|p| {
-                let mut permissions = p.permissions();
-                permissions.set_readonly(true);
-                let _ = fs::set_permissions(&(*guard).inner.path, permissions);
-            });
-            (*guard).inner.sender_seq_num = (*guard).inner.sender_seq_num.wrapping_add(1);
-            (*guard).inner.path = self.root.join(format!("{}", (*guard).inner.sender_seq_num));
-            let disk_files_capacity = self.disk_files_capacity.load(Ordering::Acquire);
-            if disk_files_capacity == 0 {
-                return Err((event, super::Error::Full));
-            } else {
-                match fs::OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(&(*guard).inner.path)
-                {
-                    Ok(fp) => {
-                        self.disk_files_capacity.fetch_sub(1, Ordering::Release);
-                        (*guard).inner.sender_fp = Some(BufWriter::new(fp));
-                        (*guard).inner.bytes_written = 0;
-                    }
-                    Err(e) => {
-                        return Err((event, super::Error::IoError(e)));
-                    }
+    ) -> Result<(), super::Error> {
+        let stamp = match self.ttl {
+            Some(_) => private::now_stamp(),
+            None => 0,
+        };
+        let limits = match self.block {
+            Some(limits) => limits,
+            None => {
+                // Every item is a record of its own. The record's length
+                // prefix is written ahead of the compressed item and filled in
+                // once compression is done.
+                let prefix_len = self.header().prefix_len();
+                let mut record = match self.compression.encoder(vec![0; prefix_len]) {
+                    Ok(e) => e,
+                    Err(err) => return Err(super::Error::IoError(err)),
+                };
+                if let Err(err) = self.codec.encode(event, &mut record) {
+                    return Err(super::Error::IoError(err));
                 }
+                let mut record = match record.finish() {
+                    Ok(record) => record,
+                    Err(err) => return Err(super::Error::IoError(err)),
+                };
+                private::fill_prefix(self.header(), &mut record, 1, stamp);
+                return self.write_record(&record, 1, guard);
             }
+        };
+        // The item is serialized onto the end of the pending block, behind
+        // its length and -- should the channel have a TTL -- the time it was
+        // sent. The block is written out when full or when asked.
+        let block_len = guard.inner.block.len();
+        guard.inner.block.extend_from_slice(&[0; PAYLOAD_LEN_BYTES]);
+        if self.ttl.is_some() {
+            let mut buf = [0; private::STAMP_LEN];
+            BigEndian::write_u64(&mut buf, stamp);
+            guard.inner.block.extend_from_slice(&buf);
         }
-
-        assert!((*guard).inner.sender_fp.is_some());
-        let mut bytes_written = 0;
-        if let Some(ref mut fp) = (*guard).inner.sender_fp {
-            match fp.write_u32::<BigEndian>(payload_len as u32) {
-                Ok(()) => bytes_written += PAYLOAD_LEN_BYTES,
-                Err(e) => {
-                    return Err((event, super::Error::IoError(e)));
-                }
-            };
-            match fp.write(&buf[..]) {
-                Ok(written) => {
-                    assert_eq!(payload_len, written);
-                    bytes_written += written;
-                }
-                Err(e) => {
-                    return Err((event, super::Error::IoError(e)));
-                }
+        let item_start = guard.inner.block.len();
+        if let Err(err) = self.codec.encode(event, &mut guard.inner.block) {
+            guard.inner.block.truncate(block_len);
+            return Err(super::Error::IoError(err));
+        }
+        let item_len = guard.inner.block.len() - item_start;
+        BigEndian::write_u32(
+            &mut guard.inner.block[block_len..block_len + PAYLOAD_LEN_BYTES],
+            item_len as u32,
+        );
+        guard.inner.block_items += 1;
+        guard.inner.block_stamp = ::std::cmp::max(guard.inner.block_stamp, stamp);
+        if seal
+            || guard.inner.block_items >= limits.max_items
+            || guard.inner.block.len() >= limits.max_bytes
+        {
+            if let Err(err) = self.seal_block(guard) {
+                guard.inner.block.truncate(block_len);
+                guard.inner.block_items -= 1;
+                return Err(err);
             }
         }
-        (*guard).inner.bytes_written += bytes_written;
         Ok(())
     }
 
@@ -175,20 +280,25 @@ where
     /// in limbo for a good spell.
     pub fn flush(&mut self) -> Result<(), super::Error> {
         let mut back_guard = self.mem_buffer.lock_back();
-        if (*back_guard).inner.total_disk_writes != 0 {
+        self.announce_disk_writes(&mut back_guard)
+    }
+
+    // Write out the disk writes made so far and push a disk placement telling
+    // the Receiver of them, should there be any.
+    fn announce_disk_writes(
+        &self,
+        back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<(), super::Error> {
+        if back_guard.inner.total_disk_writes != 0 {
             // disk mode
-            assert!((*back_guard).inner.sender_fp.is_some());
-            if let Some(ref mut fp) = (*back_guard).inner.sender_fp {
-                fp.flush().expect("unable to flush");
-            } else {
-                unreachable!()
-            }
+            self.seal_block(back_guard)?;
+            self.flush_file(back_guard)?;
             match self.mem_buffer.push_back(
-                private::Placement::Disk((*back_guard).inner.total_disk_writes),
-                &mut back_guard,
+                private::Placement::Disk(back_guard.inner.total_disk_writes),
+                back_guard,
             ) {
                 Ok(must_wake_receiver) => {
-                    (*back_guard).inner.total_disk_writes = 0;
+                    back_guard.inner.total_disk_writes = 0;
                     if must_wake_receiver {
                         let front_guard = self.mem_buffer.lock_front();
                         self.mem_buffer.notify_not_empty(&front_guard);
@@ -208,10 +318,328 @@ where
     /// This function will fail with IO errors if the underlying queue files are
     /// temporarily exhausted -- say, due to lack of file descriptors -- of with
     /// Full if there is no more space in the in-memory buffer _or_ on disk, as
-    /// per the `max_disk_files` setting from
-    /// `channel_with_explicit_capacity`. Ownership of the event will be
-    /// returned back to the caller on failure.
+    /// per the `max_disk_files` and `max_total_disk_bytes` settings of
+    /// `Config`. Ownership of the event will be returned back to the caller on
+    /// failure. Should there be no space the channel's `OverflowPolicy` may
+    /// have the event dropped, room made for it or the send wait instead.
     pub fn send(&mut self, event: T) -> Result<(), (T, super::Error)> {
+        let mut back_guard = self.mem_buffer.lock_back();
+        let placed = self.place_overflowing(event, &mut back_guard);
+        drop(back_guard);
+        match placed {
+            Ok(true) => self.count(Ok(())),
+            Ok(false) => {
+                self.counters.shed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => self.count(Err(err)),
+        }
+    }
+
+    /// Flush outstanding disk writes every `interval`, on a thread of the
+    /// channel's own
+    ///
+    /// Events paged to disk are only announced to the Receiver once a later
+    /// send -- or `flush` -- finds room in the in-memory buffer to do so. When
+    /// traffic dies down they may wait indefinitely. The flusher thread bounds
+    /// that wait to roughly `interval`, so long as the Receiver keeps making
+    /// room. The thread does not count as a Sender: it exits once every Sender
+    /// has hung up, or the Receiver has.
+    pub fn spawn_flusher(&self, interval: Duration) -> Result<(), super::Error>
+    where
+        T: Send + 'static,
+        C: Send + 'static,
+    {
+        let flusher = self.detached();
+        thread::Builder::new()
+            .name(format!("hopper-flusher-{}", self.name))
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let mut back_guard = flusher.mem_buffer.lock_back();
+                if !flusher.mem_buffer.has_senders() || flusher.mem_buffer.receiver_gone() {
+                    return;
+                }
+                let _ = flusher.announce_disk_writes(&mut back_guard);
+            })
+            .map(|_| ())
+            .map_err(super::Error::IoError)
+    }
+
+    // A copy of this Sender that is not one of the channel's Senders. It does
+    // not keep the channel open and does nothing when dropped.
+    pub(crate) fn detached(&self) -> Sender<T, C> {
+        Sender {
+            name: self.name.clone(),
+            root: self.root.clone(),
+            max_disk_bytes: self.max_disk_bytes,
+            max_total_disk_bytes: self.max_total_disk_bytes,
+            mem_buffer: self.mem_buffer.clone(),
+            resource_type: self.resource_type,
+            disk_files_capacity: Arc::clone(&self.disk_files_capacity),
+            codec: self.codec.clone(),
+            compression: self.compression,
+            block: self.block,
+            overflow: self.overflow,
+            fsync: self.fsync,
+            ttl: self.ttl,
+            memory_budget: self.memory_budget,
+            counters: Arc::clone(&self.counters),
+            flusher: true,
+        }
+    }
+
+    // Flush outstanding disk writes, waiting for the Receiver to make room in
+    // the in-memory buffer should there be none to announce them.
+    #[cfg(feature = "futures")]
+    pub(crate) fn flush_blocking(&mut self) -> Result<(), super::Error> {
+        let mut back_guard = self.mem_buffer.lock_back();
+        self.mem_buffer.start_waiting_not_full();
+        let flushed = loop {
+            match self.announce_disk_writes(&mut back_guard) {
+                Err(super::Error::NoFlush) => {
+                    if !self.mem_buffer.wait_not_full(&mut back_guard, None) {
+                        break Err(super::Error::NoFlush);
+                    }
+                }
+                flushed => break flushed,
+            }
+        };
+        self.mem_buffer.stop_waiting_not_full();
+        flushed
+    }
+
+    /// Send a run of events into the queue
+    ///
+    /// This function behaves as calling `send` for each event would, but
+    /// takes the Sender lock once for the lot. Events paged to disk are
+    /// flushed and announced to the Receiver together, rather than one by one.
+    /// Should an event fail to send it is returned alongside the error, the
+    /// events before it having been sent and those after it left in `events`.
+    /// Pass `iter.by_ref()` to hang on to them.
+    pub fn send_all<I>(&mut self, events: I) -> Result<(), (T, super::Error)>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut back_guard = self.mem_buffer.lock_back();
+        let mut must_wake_receiver = false;
+        let mut sent = 0;
+        let mut failed = None;
+        for event in events {
+            let event = if back_guard.inner.total_disk_writes == 0 {
+                match self.push_memory(event, &mut back_guard) {
+                    Ok(must_wake) => {
+                        must_wake_receiver |= must_wake;
+                        sent += 1;
+                        continue;
+                    }
+                    Err(deque::Error::Full(placed_event)) => placed_event.extract().unwrap(),
+                }
+            } else {
+                event
+            };
+            match self.write_to_disk(&event, false, &mut back_guard) {
+                Ok(()) => {
+                    back_guard.inner.total_disk_writes += 1;
+                    sent += 1;
+                }
+                // No room: the overflow policy has its say, the disk writes
+                // so far being announced first to keep order.
+                Err(super::Error::Full) => {
+                    let _ = self.announce_disk_writes(&mut back_guard);
+                    match self.place_overflowing(event, &mut back_guard) {
+                        Ok(true) => sent += 1,
+                        Ok(false) => {
+                            self.counters.shed.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(err) => {
+                            failed = Some(err);
+                            break;
+                        }
+                    }
+                }
+                Err(err) => {
+                    failed = Some((event, err));
+                    break;
+                }
+            }
+        }
+        // Should the announcement fail the disk writes are left for the next
+        // send, or flush, to announce.
+        let _ = self.announce_disk_writes(&mut back_guard);
+        drop(back_guard);
+        if must_wake_receiver {
+            let front_guard = self.mem_buffer.lock_front();
+            self.mem_buffer.notify_not_empty(&front_guard);
+            drop(front_guard);
+        }
+        self.counters.sent.fetch_add(sent, Ordering::Relaxed);
+        match failed {
+            None => Ok(()),
+            Some(failed) => self.count(Err(failed)),
+        }
+    }
+
+    /// Send a event into the queue, waiting for room should there be none
+    ///
+    /// This function behaves as `send` does except that, rather than fail
+    /// with Full, it blocks until the Receiver makes room in the in-memory
+    /// buffer or on disk. Full is still returned should the Receiver go away.
+    pub fn send_blocking(&mut self, event: T) -> Result<(), (T, super::Error)> {
+        self.send_until(event, None)
+    }
+
+    /// Send a event into the queue, waiting no longer than `timeout` for room
+    /// should there be none
+    ///
+    /// This function behaves as `send_blocking` does but gives up with Full
+    /// once `timeout` has elapsed.
+    pub fn send_timeout(&mut self, event: T, timeout: Duration) -> Result<(), (T, super::Error)> {
+        self.send_until(event, private::deadline(timeout))
+    }
+
+    fn send_until(&mut self, event: T, deadline: Option<Instant>) -> Result<(), (T, super::Error)> {
+        let mut back_guard = self.mem_buffer.lock_back();
+        let placed = self.place_until(event, &mut back_guard, deadline);
+        drop(back_guard);
+        self.count(placed)
+    }
+
+    // Place `event`, waiting no later than `deadline` for room should there
+    // be none.
+    fn place_until(
+        &self,
+        mut event: T,
+        back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+        deadline: Option<Instant>,
+    ) -> Result<(), (T, super::Error)> {
+        self.mem_buffer.start_waiting_not_full();
+        let placed = loop {
+            match self.place(event, back_guard) {
+                Err((ev, super::Error::Full)) => {
+                    // The Receiver can only make room on disk by reading what
+                    // we've written there, so be sure it knows of it. Should
+                    // the deque be full there's room coming anyhow.
+                    let _ = self.announce_disk_writes(back_guard);
+                    if !self.mem_buffer.wait_not_full(back_guard, deadline) {
+                        break Err((ev, super::Error::Full));
+                    }
+                    event = ev;
+                }
+                placed => break placed,
+            }
+        };
+        self.mem_buffer.stop_waiting_not_full();
+        placed
+    }
+
+    // Place `event` and, should there be no room for it, do as the overflow
+    // policy says. Ok(false) is returned should the event have been dropped.
+    fn place_overflowing(
+        &self,
+        event: T,
+        back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<bool, (T, super::Error)> {
+        let mut event = match self.place(event, back_guard) {
+            Err((ev, super::Error::Full)) => ev,
+            placed => return placed.map(|()| true),
+        };
+        match self.overflow {
+            OverflowPolicy::Error => Err((event, super::Error::Full)),
+            OverflowPolicy::DropNewest => Ok(false),
+            OverflowPolicy::Block => self.place_until(event, back_guard, None).map(|()| true),
+            OverflowPolicy::DropOldest => loop {
+                if !self.evict_oldest(back_guard) && !self.evict_memory(back_guard) {
+                    return Ok(false);
+                }
+                event = match self.place(event, back_guard) {
+                    Err((ev, super::Error::Full)) => ev,
+                    placed => return placed.map(|()| true),
+                };
+            },
+        }
+    }
+
+    // Empty the oldest queue file the Receiver has yet to start reading,
+    // returning whether there was one. The Receiver passes the emptied file
+    // by, learning from `file_items` that its items are lost. The room it
+    // took up is had back straight away.
+    fn evict_oldest(&self, back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>) -> bool {
+        let sender_seq_num = back_guard.inner.sender_seq_num;
+        let receiver_seq_num = back_guard.inner.receiver_seq_num;
+        // The Receiver may be a file ahead of the Senders, should they have
+        // given up on their last.
+        let ahead = sender_seq_num.wrapping_sub(receiver_seq_num);
+        if !(2..=usize::MAX / 2).contains(&ahead) {
+            return false;
+        }
+        let mut seq_num = receiver_seq_num.wrapping_add(1);
+        while seq_num != sender_seq_num && back_guard.inner.evicted.contains(&seq_num) {
+            seq_num = seq_num.wrapping_add(1);
+        }
+        if seq_num == sender_seq_num {
+            return false;
+        }
+        let len = match fs::metadata(self.root.join(format!("{}", seq_num))) {
+            Ok(metadata) => metadata.len() as usize,
+            Err(_) => return false,
+        };
+        if private::empty_queue_file(&self.root, seq_num).is_err() {
+            return false;
+        }
+        let items = back_guard
+            .inner
+            .file_items
+            .get(&seq_num)
+            .cloned()
+            .unwrap_or(0);
+        back_guard.inner.evicted.insert(seq_num);
+        self.counters.dropped.fetch_add(items, Ordering::Relaxed);
+        self.counters.remove_disk_bytes(len);
+        self.disk_files_capacity.fetch_add(1, Ordering::Release);
+        true
+    }
+
+    // Drop the oldest item in the in-memory deque, returning whether there
+    // was one. This is only done with nothing waiting to go to disk, the room
+    // made being what the event then goes into. A disk placement at the front
+    // of the deque is left be.
+    fn evict_memory(&self, back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>) -> bool {
+        if back_guard.inner.total_disk_writes != 0 {
+            return false;
+        }
+        let evicted = self.mem_buffer.pop_front_if(|placement| match *placement {
+            private::Placement::Memory(..) => true,
+            private::Placement::Disk(_) => false,
+        });
+        match evicted {
+            Some(private::Placement::Memory(_, weight, _)) => {
+                self.counters.release_memory(weight);
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Count the outcome of a send.
+    fn count(&self, placed: Result<(), (T, super::Error)>) -> Result<(), (T, super::Error)> {
+        match placed {
+            Ok(()) => {
+                self.counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err((_, super::Error::Full)) => {
+                self.counters.shed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {}
+        }
+        placed
+    }
+
+    fn place(
+        &self,
+        event: T,
+        back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<(), (T, super::Error)> {
         // Welcome. Let me tell you about the time I fell off the toilet, hit my
         // head and when I woke up I saw this! ~passes knapkin drawing of the
         // flux capacitor over to you~
@@ -246,11 +674,9 @@ where
         // `placement::Disk(total_disk_writes)` push_back. If that is a success
         // we're in in-memory mode. If that's a failure we're still in
         // to-disk. Similar story for flipping from in-memory to to-disk.
-        let mut back_guard = self.mem_buffer.lock_back();
-        if (*back_guard).inner.total_disk_writes == 0 {
+        if back_guard.inner.total_disk_writes == 0 {
             // in-memory mode
-            let placed_event = private::Placement::Memory(event);
-            match self.mem_buffer.push_back(placed_event, &mut back_guard) {
+            match self.push_memory(event, back_guard) {
                 Ok(must_wake_receiver) => {
                     if must_wake_receiver {
                         let front_guard = self.mem_buffer.lock_front();
@@ -259,25 +685,56 @@ where
                     }
                 }
                 Err(deque::Error::Full(placed_event)) => {
-                    self.write_to_disk(placed_event.extract().unwrap(), &mut back_guard)?;
-                    (*back_guard).inner.total_disk_writes += 1;
+                    let event = placed_event.extract().unwrap();
+                    if let Err(err) = self.write_to_disk(&event, false, back_guard) {
+                        return Err((event, err));
+                    }
+                    back_guard.inner.total_disk_writes += 1;
                 }
             }
         } else {
             // disk mode
-            self.write_to_disk(event, &mut back_guard)?;
-            (*back_guard).inner.total_disk_writes += 1;
-            assert!((*back_guard).inner.sender_fp.is_some());
-            if let Some(ref mut fp) = (*back_guard).inner.sender_fp {
-                fp.flush().expect("unable to flush");
-            } else {
-                unreachable!()
+            //
+            // Should there be room in the deque for a disk placement any
+            // pending block is written out along with the event, so that the
+            // placement covers it.
+            let room = self.mem_buffer.size() < self.mem_buffer.capacity();
+            let block_len = back_guard.inner.block.len();
+            if let Err(err) = self.write_to_disk(&event, room, back_guard) {
+                return Err((event, err));
+            }
+            back_guard.inner.total_disk_writes += 1;
+            // Should the flush fail the queue file is abandoned and the event
+            // is not sent, as for a failed write. It is taken back out of the
+            // pending block or out of the counts of the file it went down with.
+            // The Receiver finds what else was lost as per its corruption
+            // policy.
+            if let Err(err) = self.flush_file(back_guard) {
+                back_guard.inner.total_disk_writes -= 1;
+                if back_guard.inner.block_items != 0 {
+                    back_guard.inner.block.truncate(block_len);
+                    back_guard.inner.block_items -= 1;
+                } else {
+                    let seq_num = back_guard.inner.sender_seq_num;
+                    if let Some(items) = back_guard.inner.file_items.get_mut(&seq_num) {
+                        *items -= 1;
+                    }
+                    self.counters.disk_items.fetch_sub(1, Ordering::Relaxed);
+                }
+                return Err((event, err));
+            }
+            // The Receiver makes room without the back lock, so there may be
+            // room now where there was none above. A placement must not cover
+            // items still in the pending block: the Receiver would go looking
+            // for them on disk. They are announced once the block is sealed.
+            if back_guard.inner.block_items != 0 {
+                return Ok(());
             }
             if let Ok(must_wake_receiver) = self.mem_buffer.push_back(
-                private::Placement::Disk((*back_guard).inner.total_disk_writes),
-                &mut back_guard,
+                private::Placement::Disk(back_guard.inner.total_disk_writes),
+                back_guard,
             ) {
-                (*back_guard).inner.total_disk_writes = 0;
+                back_guard.inner.total_disk_writes = 0;
                 if must_wake_receiver {
                     let front_guard = self.mem_buffer.lock_front();
                     self.mem_buffer.notify_not_empty(&front_guard);
@@ -288,8 +745,228 @@ where
         Ok(())
     }
 
+    // Push `event` onto the in-memory deque. A channel accounting for memory
+    // in bytes refuses the event should it not fit in what's left of the
+    // memory budget, as if the deque were full. So does a channel of a
+    // HopperEnv should the event not fit in the env's budget, items weighing
+    // their size in slots unless accounted for in bytes.
+    fn push_memory(
+        &self,
+        event: T,
+        back_guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<bool, deque::Error<private::Placement<T>>> {
+        let weight = match self.memory_budget {
+            Some(_) => self.codec.weigh(&event),
+            None if self.counters.budget.is_some() => ::std::mem::size_of::<T>(),
+            None => 0,
+        };
+        let sent_at = self.ttl.map(|_| Instant::now());
+        let placed_event = private::Placement::Memory(event, weight, sent_at);
+        if !self.counters.reserve_memory(weight, self.memory_budget) {
+            return Err(deque::Error::Full(placed_event));
+        }
+        let pushed = self.mem_buffer.push_back(placed_event, back_guard);
+        if pushed.is_err() {
+            self.counters.release_memory(weight);
+        }
+        pushed
+    }
+
     /// Return the sender's name
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Return a snapshot of the channel's counters
+    ///
+    /// The snapshot is the same whether taken from a Sender or the Receiver.
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot(&self.mem_buffer)
+    }
+}
+
+impl<T, C> Sender<T, C> {
+    // Compress the pending block, if there is one, and write it out as a
+    // record.
+    fn seal_block(
+        &self,
+        guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<(), super::Error> {
+        if guard.inner.block_items == 0 {
+            return Ok(());
+        }
+        let block_items = guard.inner.block_items;
+        let mut record = self
+            .compression
+            .encoder(vec![0; self.header().prefix_len()])
+            .and_then(|mut e| e.write_all(&guard.inner.block).and_then(|()| e.finish()))
+            .map_err(super::Error::IoError)?;
+        let stamp = guard.inner.block_stamp;
+        private::fill_prefix(self.header(), &mut record, block_items, stamp);
+        self.write_record(&record, block_items, guard)?;
+        guard.inner.block.clear();
+        guard.inner.block_items = 0;
+        guard.inner.block_stamp = 0;
+        Ok(())
+    }
+
+    // Flush the queue file being written, and have the OS write it through
+    // should the fsync policy call for it. Should that fail the file is
+    // abandoned.
+    fn flush_file(
+        &self,
+        guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<(), super::Error> {
+        if let Some(ref mut fp) = guard.inner.sender_fp {
+            let flushed = fp.flush().and_then(|()| match self.fsync {
+                FsyncPolicy::Never => Ok(()),
+                _ => fp.get_ref().sync_data(),
+            });
+            if let Err(e) = flushed {
+                self.abandon_file(guard);
+                return Err(super::Error::IoError(e));
+            }
+        }
+        Ok(())
+    }
+
+    // Give up on the queue file being written after a failed write. What made
+    // it to disk may end part way through a record -- say, because the disk is
+    // full -- so nothing more is written to the file. It is marked read-only
+    // and the next record goes to a new file.
+    fn abandon_file(&self, guard: &mut MutexGuard<BackGuardInner<SenderSync>>) {
+        if let Some(fp) = guard.inner.sender_fp.take() {
+            // Drop whatever is still buffered, rather than flush it.
+            self.counters.remove_disk_bytes(fp.buffer().len());
+            let _ = fp.into_parts();
+        }
+        let _ = fs::metadata(&guard.inner.path).map(|p| {
+            let mut permissions = p.permissions();
+            permissions.set_readonly(true);
+            let _ = fs::set_permissions(&guard.inner.path, permissions);
+        });
+    }
+
+    fn write_record(
+        &self,
+        record: &[u8],
+        items: usize,
+        guard: &mut MutexGuard<BackGuardInner<SenderSync>>,
+    ) -> Result<(), super::Error> {
+        // If the individual sender writes enough to go over the max we mark the
+        // file read-only--which will help the receiver to decide it has hit the
+        // end of its log file--and create a new log file.
+        let bytes_written = guard.inner.bytes_written + record.len();
+        let new_file = (bytes_written > self.max_disk_bytes) || guard.inner.sender_fp.is_none();
+        // The record, and the header of the new file it may need, must fit in
+        // what's left of the channel's disk quota -- and of its HopperEnv's
+        // budget. The room is reserved up front and handed back should the
+        // write not happen.
+        let needed = if new_file {
+            record.len() + private::HEADER_LEN as usize
+        } else {
+            record.len()
+        };
+        if !self
+            .counters
+            .reserve_disk_bytes(needed, self.max_total_disk_bytes)
+        {
+            return Err(super::Error::Full);
+        }
+        if new_file {
+            // Once we've gone over the write limit for our current file or find
+            // that we've gotten behind the current queue file we need to seek
+            // forward to find our place in the space of queue files. Should
+            // there be no room for another file we stay put, the Receiver
+            // having to be able to find the next file along.
+            let disk_files_capacity = self.disk_files_capacity.load(Ordering::Acquire);
+            if disk_files_capacity == 0 {
+                self.counters.remove_disk_bytes(needed);
+                return Err(super::Error::Full);
+            }
+            // The next file is set up, header and all, before anything is
+            // changed. Should that fail we stay on the current file and try
+            // again with the next record. The file is left in place, empty,
+            // rather than removed: a Receiver done with an abandoned file may
+            // already have it open.
+            let seq_num = guard.inner.sender_seq_num.wrapping_add(1);
+            let path = self.root.join(format!("{}", seq_num));
+            let (fp, created) = match self.start_file(&path) {
+                Ok(started) => started,
+                Err((created, e)) => {
+                    self.counters.remove_disk_bytes(needed);
+                    if created {
+                        self.counters.add_disk_files(1);
+                    }
+                    return Err(super::Error::IoError(e));
+                }
+            };
+            // The file moved on from is flushed once dropped. Should the
+            // policy call for it, it's written through first. It's marked
+            // read-only, which tells the Receiver it's hit the end of it.
+            if self.fsync != FsyncPolicy::Never {
+                if let Some(ref mut fp) = guard.inner.sender_fp {
+                    let _ = fp.flush().and_then(|()| fp.get_ref().sync_data());
+                }
+            }
+            let _ = fs::metadata(&guard.inner.path).map(|p| {
+                let mut permissions = p.permissions();
+                permissions.set_readonly(true);
+                let _ = fs::set_permissions(&guard.inner.path, permissions);
+            });
+            if created {
+                self.counters.add_disk_files(1);
+            }
+            self.disk_files_capacity.fetch_sub(1, Ordering::Release);
+            guard.inner.sender_seq_num = seq_num;
+            guard.inner.path = path;
+            guard.inner.sender_fp = Some(BufWriter::new(fp));
+            guard.inner.bytes_written = private::HEADER_LEN as usize;
+            self.counters
+                .sender_seq_num
+                .store(seq_num, Ordering::Relaxed);
+        }
+
+        assert!(guard.inner.sender_fp.is_some());
+        let written = match guard.inner.sender_fp {
+            Some(ref mut fp) => fp.write_all(record).and_then(|()| match self.fsync {
+                FsyncPolicy::Always => fp.flush().and_then(|()| fp.get_ref().sync_data()),
+                _ => Ok(()),
+            }),
+            None => unreachable!(),
+        };
+        if let Err(e) = written {
+            self.counters.remove_disk_bytes(record.len());
+            self.abandon_file(guard);
+            return Err(super::Error::IoError(e));
+        }
+        guard.inner.bytes_written += record.len();
+        let seq_num = guard.inner.sender_seq_num;
+        *guard.inner.file_items.entry(seq_num).or_insert(0) += items;
+        self.counters.disk_items.fetch_add(items, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Open the queue file at `path` and write its header, straight through
+    // rather than buffered so that a failure shows up here. Whether this call
+    // created the file is returned alongside, on failure too. Should the
+    // header fail the file is emptied of whatever part of it made it in.
+    fn start_file(&self, path: &Path) -> Result<(fs::File, bool), (bool, io::Error)> {
+        let (mut fp, created) = private::open_queue_file(path).map_err(|e| (false, e))?;
+        match private::write_header(&mut fp, self.header()) {
+            Ok(()) => Ok((fp, created)),
+            Err(e) => {
+                let _ = fp.set_len(0);
+                Err((created, e))
+            }
+        }
+    }
+
+    fn header(&self) -> private::Header {
+        private::Header {
+            compression: self.compression,
+            blocks: self.block.is_some(),
+            timestamps: self.ttl.is_some(),
+        }
+    }
 }
@@ -0,0 +1,174 @@
+//! A Receiver shared between several consumers
+//!
+//! The Receiver of a channel owns the one cursor into the queue files and
+//! decides alone when a queue file is done with. A `SharedReceiver` hands
+//! that Receiver out to one consumer at a time, so that every item goes to
+//! exactly one of them. A consumer holds the Receiver only while it looks for
+//! an item. Finding none it lets go and waits on the in-memory deque, so that
+//! a consumer waiting for an item holds none of the others up.
+use codec::{Bincode, Codec};
+use parking_lot::Mutex;
+use private;
+use receiver::{Receiver, RecvError, RecvTimeoutError, TryRecvError};
+use stats::{Counters, Stats};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The 'receive' side of hopper, shareable between threads
+///
+/// A SharedReceiver is made from a `Receiver` and cloned for each consumer.
+/// The channel is only hung up on -- as far as the Senders are concerned --
+/// once every clone has been dropped.
+#[derive(Debug)]
+pub struct SharedReceiver<T, C = Bincode> {
+    inner: Arc<Mutex<Receiver<T, C>>>,
+    mem_buffer: private::Queue<T>,
+    counters: Arc<Counters>,
+}
+
+impl<T, C> Clone for SharedReceiver<T, C> {
+    fn clone(&self) -> SharedReceiver<T, C> {
+        SharedReceiver {
+            inner: Arc::clone(&self.inner),
+            mem_buffer: self.mem_buffer.clone(),
+            counters: Arc::clone(&self.counters),
+        }
+    }
+}
+
+impl<T, C> From<Receiver<T, C>> for SharedReceiver<T, C>
+where
+    C: Codec<T>,
+{
+    fn from(receiver: Receiver<T, C>) -> SharedReceiver<T, C> {
+        let (mem_buffer, counters) = receiver.shared_parts();
+        SharedReceiver {
+            inner: Arc::new(Mutex::new(receiver)),
+            mem_buffer,
+            counters,
+        }
+    }
+}
+
+impl<T, C> SharedReceiver<T, C>
+where
+    C: Codec<T>,
+{
+    /// Receive a value, blocking until one is available
+    ///
+    /// See `Receiver::recv`.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        match self.recv_until(None) {
+            Ok(ev) => Ok(ev),
+            Err(RecvTimeoutError::Disk(e)) => Err(RecvError::Disk(e)),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                Err(RecvError::Disconnected)
+            }
+        }
+    }
+
+    /// Attempt to receive a value without blocking
+    ///
+    /// Another consumer may be taking an item, in which case this waits for
+    /// it to be done before looking. See `Receiver::try_recv`.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.lock().try_recv()
+    }
+
+    /// Receive a value, blocking for no longer than `timeout`
+    ///
+    /// See `Receiver::recv_timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(private::deadline(timeout))
+    }
+
+    /// Receive a value, blocking no later than `deadline`
+    ///
+    /// Time spent waiting on other consumers counts against the deadline.
+    /// See `Receiver::recv_deadline`.
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Some(deadline))
+    }
+
+    /// Receive up to `max` values, blocking for no longer than `timeout` for
+    /// the first
+    ///
+    /// Time spent waiting on other consumers counts against the timeout. See
+    /// `Receiver::recv_batch`.
+    pub fn recv_batch(&self, max: usize, timeout: Duration) -> Result<Vec<T>, RecvTimeoutError> {
+        self.wait_for(private::deadline(timeout), |receiver| {
+            receiver.recv_batch(max, Duration::from_secs(0))
+        })
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        self.wait_for(deadline, |receiver| {
+            receiver.recv_until(Some(Instant::now()))
+        })
+    }
+
+    // Make `attempt`s on the Receiver, which must not block, until one comes
+    // to something or `deadline` passes. Between attempts we wait on the
+    // in-memory deque without the Receiver. The Receiver never waits on
+    // disk, so the deque is all that's worth waiting on: once it has been
+    // hung up on the next attempt goes on to whatever was left on disk.
+    fn wait_for<R, F>(
+        &self,
+        deadline: Option<Instant>,
+        mut attempt: F,
+    ) -> Result<R, RecvTimeoutError>
+    where
+        F: FnMut(&mut Receiver<T, C>) -> Result<R, RecvTimeoutError>,
+    {
+        loop {
+            let mut receiver = match deadline {
+                None => self.inner.lock(),
+                Some(deadline) => match self.inner.try_lock_until(deadline) {
+                    Some(receiver) => receiver,
+                    None => return Err(RecvTimeoutError::Timeout),
+                },
+            };
+            match attempt(&mut receiver) {
+                Err(RecvTimeoutError::Timeout) => {}
+                res => return res,
+            }
+            drop(receiver);
+            if !self.mem_buffer.wait_not_empty(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    /// Return a snapshot of the channel's counters
+    ///
+    /// This does not wait on other consumers. See `Receiver::stats`.
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot(&self.mem_buffer)
+    }
+
+    /// An iterator over messages on the receiver, ending as that of
+    /// `Receiver::iter` does
+    pub fn iter(&self) -> SharedIter<'_, T, C> {
+        SharedIter { rx: self }
+    }
+}
+
+#[derive(Debug)]
+pub struct SharedIter<'a, T, C = Bincode>
+where
+    T: 'a,
+    C: 'a,
+{
+    rx: &'a SharedReceiver<T, C>,
+}
+
+impl<'a, T, C> Iterator for SharedIter<'a, T, C>
+where
+    C: Codec<T>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
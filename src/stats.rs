@@ -0,0 +1,202 @@
+//! Counters describing the state of a channel
+//!
+//! The Senders and the Receiver of a channel share a set of atomic counters,
+//! bumped as items pass through. `Sender::stats` and `Receiver::stats` read
+//! them into a `Stats` snapshot, cheaply enough to be scraped often. The
+//! counters are read one by one, without coordination, and so a snapshot
+//! taken while items are in flight may be off by a little.
+use env::Budget;
+use private;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A snapshot of the state of a channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The placements held by the in-memory deque: items, and the markers
+    /// announcing items paged to disk
+    pub memory_items: usize,
+    /// The number of placements the in-memory deque can hold
+    pub memory_capacity: usize,
+    /// The weight of the items held in memory, should the channel account
+    /// for memory in bytes or belong to a `HopperEnv`
+    pub memory_bytes: usize,
+    /// The items written to queue files and not yet received
+    pub disk_items: usize,
+    /// The number of queue files in the channel directory
+    pub disk_files: usize,
+    /// The bytes written to queue files, including those the Senders have
+    /// yet to flush
+    pub disk_bytes: usize,
+    /// The items sent
+    pub sent: usize,
+    /// The items received
+    pub received: usize,
+    /// The items refused by a Sender with `Error::Full`, or dropped as per
+    /// `OverflowPolicy::DropNewest`
+    pub shed: usize,
+    /// The items lost to making room, as per `OverflowPolicy::DropOldest`
+    pub dropped: usize,
+    /// The items passed over by the Receiver for having outlived
+    /// `Config::ttl`
+    pub expired: usize,
+    /// The sequence number of the queue file the Senders are writing
+    pub sender_seq_num: usize,
+    /// The sequence number of the queue file the Receiver is reading
+    pub receiver_seq_num: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub memory_bytes: AtomicUsize,
+    pub disk_items: AtomicUsize,
+    pub disk_bytes: AtomicUsize,
+    pub disk_files: AtomicUsize,
+    pub sent: AtomicUsize,
+    pub received: AtomicUsize,
+    pub shed: AtomicUsize,
+    pub dropped: AtomicUsize,
+    pub expired: AtomicUsize,
+    pub sender_seq_num: AtomicUsize,
+    pub receiver_seq_num: AtomicUsize,
+    // The budget shared with the other channels of a HopperEnv, should the
+    // channel belong to one
+    pub budget: Option<Arc<Budget>>,
+}
+
+// Take `n` off `counter`. Counters that go down never go below zero, should
+// a count that went up have been missed.
+pub fn decrement(counter: &AtomicUsize, n: usize) {
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+        Some(c.saturating_sub(n))
+    });
+}
+
+// Add `n` to `counter` should that keep it within `limit`, returning whether
+// it did.
+fn reserve(counter: &AtomicUsize, n: usize, limit: usize) -> bool {
+    counter
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+            c.checked_add(n).filter(|c| *c <= limit)
+        })
+        .is_ok()
+}
+
+impl Counters {
+    pub fn with_budget(budget: Arc<Budget>) -> Counters {
+        let mut counters = Counters::default();
+        counters.budget = Some(budget);
+        counters
+    }
+
+    // Count `n` bytes more on disk, come what may.
+    pub fn add_disk_bytes(&self, n: usize) {
+        self.disk_bytes.fetch_add(n, Ordering::Relaxed);
+        if let Some(ref budget) = self.budget {
+            budget.disk_bytes.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    // Count `n` bytes more on disk should that keep the channel within
+    // `quota` and its HopperEnv, if any, within budget. Only the Senders add
+    // to a channel's own count, one at a time, so the channel's check can't
+    // be raced. The budget's can, by other channels, and is made as the bytes
+    // are added.
+    pub fn reserve_disk_bytes(&self, n: usize, quota: usize) -> bool {
+        if self.disk_bytes.load(Ordering::Relaxed).saturating_add(n) > quota {
+            return false;
+        }
+        if let Some(ref budget) = self.budget {
+            if !reserve(&budget.disk_bytes, n, budget.max_disk_bytes) {
+                return false;
+            }
+        }
+        self.disk_bytes.fetch_add(n, Ordering::Relaxed);
+        true
+    }
+
+    // Count `n` bytes fewer on disk.
+    pub fn remove_disk_bytes(&self, n: usize) {
+        decrement(&self.disk_bytes, n);
+        if let Some(ref budget) = self.budget {
+            decrement(&budget.disk_bytes, n);
+        }
+    }
+
+    // Count `n` queue files more.
+    pub fn add_disk_files(&self, n: usize) {
+        self.disk_files.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // Count a queue file fewer.
+    pub fn remove_disk_file(&self) {
+        decrement(&self.disk_files, 1);
+    }
+
+    // Count an item of `weight` more in memory should that keep the channel
+    // within `quota`, if it has one, and its HopperEnv, if any, within
+    // budget.
+    pub fn reserve_memory(&self, weight: usize, quota: Option<usize>) -> bool {
+        if let Some(quota) = quota {
+            if !reserve(&self.memory_bytes, weight, quota) {
+                return false;
+            }
+        } else {
+            self.memory_bytes.fetch_add(weight, Ordering::Relaxed);
+        }
+        if let Some(ref budget) = self.budget {
+            if !reserve(&budget.memory_bytes, weight, budget.max_memory_bytes) {
+                decrement(&self.memory_bytes, weight);
+                return false;
+            }
+        }
+        true
+    }
+
+    // Count an item of `weight` fewer in memory.
+    pub fn release_memory(&self, weight: usize) {
+        decrement(&self.memory_bytes, weight);
+        if let Some(ref budget) = self.budget {
+            decrement(&budget.memory_bytes, weight);
+        }
+    }
+
+    pub fn snapshot<T>(&self, mem_buffer: &private::Queue<T>) -> Stats {
+        self.snapshot_of(mem_buffer.size(), mem_buffer.capacity())
+    }
+
+    // A snapshot of a channel whose deque holds `memory_items` placements of
+    // `memory_capacity`.
+    pub fn snapshot_of(&self, memory_items: usize, memory_capacity: usize) -> Stats {
+        Stats {
+            memory_items,
+            memory_capacity,
+            memory_bytes: self.memory_bytes.load(Ordering::Relaxed),
+            disk_items: self.disk_items.load(Ordering::Relaxed),
+            disk_files: self.disk_files.load(Ordering::Relaxed),
+            disk_bytes: self.disk_bytes.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            sender_seq_num: self.sender_seq_num.load(Ordering::Relaxed),
+            receiver_seq_num: self.receiver_seq_num.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Counters {
+    // Once the channel is gone whatever it held is handed back to its
+    // HopperEnv. Queue files it leaves behind are counted again should a
+    // durable channel of the same name be re-opened.
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            decrement(&budget.disk_bytes, self.disk_bytes.load(Ordering::Relaxed));
+            decrement(
+                &budget.memory_bytes,
+                self.memory_bytes.load(Ordering::Relaxed),
+            );
+        }
+    }
+}